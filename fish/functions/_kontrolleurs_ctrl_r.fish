@@ -1,12 +1,4 @@
 function _kontrolleurs_ctrl_r
-  if history -z | kontrolleurs | read -zl execute cursor match
-    commandline -rb $match
-    commandline -f repaint
-    commandline -C $cursor
-    if test $execute = true
-      commandline -f execute
-    end
-  else
-    commandline -f repaint
-  end
+  history -z | kontrolleurs --format fish | source
+  commandline -f repaint
 end
@@ -0,0 +1,91 @@
+use std::{
+    io::{ErrorKind, Write},
+    process::{Command, Stdio},
+    sync::mpsc,
+    time::Duration,
+};
+
+/// Clipboard helpers with their arguments, in order of preference.
+const HELPERS: &[(&str, &[&str])] = &[
+    ("wl-copy", &[]),
+    ("xclip", &["-selection", "clipboard"]),
+    ("xsel", &["--clipboard", "--input"]),
+    ("pbcopy", &[]),
+];
+
+/// How long a clipboard helper may take to read the text and exit.
+const HELPER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Copies `text` to the system clipboard with the first available helper. A helper that doesn't
+/// finish within `HELPER_TIMEOUT` is left to itself, so it can't block for long.
+pub fn copy(text: &str) -> std::io::Result<()> {
+    copy_with(HELPERS, text, HELPER_TIMEOUT)
+}
+
+fn copy_with(helpers: &[(&str, &[&str])], text: &str, timeout: Duration) -> std::io::Result<()> {
+    for (program, args) in helpers {
+        let child = Command::new(program)
+            .args(*args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        let mut stdin = child.stdin.take().expect("Stdin should be piped");
+        let text = text.to_string();
+        let (sender, receiver) = mpsc::channel();
+        // Also reaps the helper if it takes too long
+        std::thread::spawn(move || {
+            let written = stdin.write_all(text.as_bytes());
+            // Closing stdin lets the helper finish
+            drop(stdin);
+            let status = child.wait();
+            let _ = sender.send(written.and(status));
+        });
+        return match receiver.recv_timeout(timeout) {
+            Ok(Ok(status)) if status.success() => Ok(()),
+            Ok(Ok(status)) => Err(std::io::Error::other(format!("{program} failed: {status}"))),
+            Ok(Err(err)) => Err(err),
+            Err(_) => Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                format!("{program} didn't finish in time"),
+            )),
+        };
+    }
+    Err(std::io::Error::new(
+        ErrorKind::NotFound,
+        "No clipboard helper found",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::ErrorKind, time::Duration};
+
+    use super::copy_with;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn test_copy_with_first_available_helper() {
+        let helpers: &[(&str, &[&str])] = &[("kontrolleurs-no-such-helper", &[]), ("cat", &[])];
+        assert!(copy_with(helpers, "ls", TIMEOUT).is_ok());
+        let err = copy_with(&helpers[..1], "ls", TIMEOUT).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_copy_with_misbehaving_helper() {
+        // More than fits into the pipe at once
+        let text = "ls\n".repeat(100_000);
+        assert!(copy_with(&[("cat", &[])], &text, TIMEOUT).is_ok());
+        assert!(copy_with(&[("false", &[])], "ls", TIMEOUT).is_err());
+        let stalled: &[(&str, &[&str])] = &[("sleep", &["10"])];
+        let err = copy_with(stalled, &text, Duration::from_millis(100)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,1092 @@
+use std::path::PathBuf;
+
+use crate::keys::Bindings;
+
+pub const USAGE: &str = "\
+Usage: kontrolleurs [OPTIONS] < HISTORY
+       kontrolleurs [OPTIONS] --history HISTORY
+
+Interactively searches the null-separated history entries read from stdin or HISTORY. Entries
+match if they contain all words of the query, in any order. Search for a space with a backslash in
+front. Words starting with ! exclude entries containing them instead, \\! searches for a literal !.
+
+Options:
+  --history PATH              Read the history from PATH instead of stdin
+  --input-format fish|bash|zsh
+                              Read null-separated entries (default, e.g. from fish's history -z),
+                              one entry per line like bash's history, or zsh's (extended)
+                              history, where lines ending with a backslash continue on the next
+                              line. Entries are expected most recent first
+  --query QUERY               Start searching for QUERY
+  --first                     Print the first match of --query and exit (with status 3 if
+                              nothing matches), without searching interactively
+  --resume                    Start with the query of the last search with --resume, unless
+                              --query is given. It's kept in
+                              $XDG_STATE_HOME/kontrolleurs/last_query
+  --prompt TEXT               Show TEXT in front of the query (default: $KONTROLLEURS_PROMPT
+                              or 'bck-i-search: ')
+  --prompt-wrap on|off        Wrap a long query onto more rows (default) or keep the prompt on
+                              one row, scrolling the query to show the part at the cursor
+  --case smart|sensitive|insensitive
+                              Ignore case unless the query contains uppercase letters (default),
+                              or always or never match case-sensitively. Ctrl-T cycles through
+                              them
+  --case-sensitive            Same as --case sensitive
+  --mode substring|fuzzy|glob Match the query as substring (default), the characters of the
+                              query in order, but not necessarily next to each other, or as
+                              glob, where * matches any text and ? any character. Ctrl-F
+                              switches between fuzzy and the other mode
+  --prefix                    Only match at the start of an entry or of one of its lines. The
+                              query isn't split into words then. Alt-a toggles it
+  --suffix                    Only match at the end of an entry or of one of its lines. The
+                              query isn't split into words then
+  --loose-whitespace          Let whitespace in the query (e.g. escaped with a backslash) match
+                              any run of whitespace, including line breaks
+  --command NAME              Only search the arguments of entries running the command NAME
+  --strip-command             Don't show the command NAME of --command in matches
+  --command-only              Only search the command names of entries, i.e. their first
+                              word after any VAR=value assignments
+  --accept-keys all|enter     Accept with Enter and the cursor keys (default) or only Enter.
+                              Short for binding accept_cursor to left, right, home, end or to
+                              nothing, which a --keys file can change again. The cursor keys
+                              move within the query when they don't accept
+  --keys PATH                 Read key bindings from PATH (default:
+                              $XDG_CONFIG_HOME/kontrolleurs/keys), with lines like
+                              next_match = ctrl-n, down to bind an action (see below) to
+                              other keys. An empty list unbinds it
+  --cycle-order history|recent
+                              Cycle through matches in history order (default) or most recent
+                              first, which needs timestamps
+  --sort recency|frequency    Order the history most recent first (default) or by how often
+                              commands were run, which reads the whole history upfront and
+                              shows each command once
+  --wrap                      Start over at the first match after the last one
+  --skip-newest COUNT         Don't match the COUNT most recent entries
+  --max-entries COUNT         Only read the COUNT most recent records of the history
+  --reverse                   Search the history oldest first. This reads the whole history
+                              (up to --max-entries) into memory before showing anything, and
+                              --skip-newest entries can't be shown at all then
+  --count-matches LIMIT       Count the matches while waiting for input, up to LIMIT, to show
+                              how many there are before cycling through all of them
+  --no-count-drop             Don't dim the number of matches when it's less than half of the
+                              previous query's
+  --max-scan ENTRIES          Only look at the first ENTRIES entries when all matches are needed
+                              upfront (default: 100000)
+  --metadata                  Entries start with #key=value lines (keys: when, cwd, output)
+  --outputs                   Search the files entries produced (#output=PATH metadata lines,
+                              one per file) instead of the entries themselves, implies
+                              --metadata
+  --keep-duplicates           Show every occurrence of a command, not just the most recent one
+  --fold-repeats              Show commands run several times in a row only once
+  --skip-comments             Skip entries that are blank or only comments
+  --list ROWS                 Show ROWS matches at once, the current one inverted. Up and Down
+                              move between them, clicking one accepts it without running it
+  --status                    Show how the query is matched and the current match's ordinal in
+                              a status bar on the last row instead of in front of the query
+  --trim-whitespace           Don't show whitespace at the start of entries, they are still
+                              selected as they are
+  --continuation-marker TEXT  Show TEXT in front of the second and later lines of multiline
+                              entries (default: '│ ')
+  --collapse-multiline        Only show the matching line of multiline entries
+  --separate-highlights       Underline highlights directly following another one
+  --no-color                  Don't color highlights, also if NO_COLOR is set
+  --min-highlight-length CHARS
+                              Only highlight matches once the query has CHARS characters
+                              (default: 1)
+  --max-highlights COUNT      Highlight at most COUNT occurrences per line
+  --ambiguous-width narrow|wide
+                              Whether characters of ambiguous width take one (default) or two
+                              columns, which depends on the terminal
+  --wrap-indent COLUMNS       Indent continuation rows of long lines by COLUMNS
+  --truncate                  Cut long lines off at the terminal width with … instead of
+                              wrapping them, scrolled to show the current match
+  --expand-env                Match against entries with environment variables expanded
+  --keep-undefined-env        Leave undefined variables as they are when expanding
+  --commandline TEXT          Emit TEXT as command line when quitting
+  --format lines|fish|json    Print the selection as lines with execute flag, cursor and
+                              null-terminated entry (default), as fish commandline calls
+                              that can be sourced or as JSON object with entry, execute,
+                              background and cursor keys, plus match_start and match_end with
+                              the byte range of the match in the entry (or null). Cursor
+                              positions are in characters.
+                              Ctrl-Z accepts to run in the background, reported as background
+                              instead of true/false by the lines format
+  --background-suffix TEXT    Append TEXT to run in the background (fish format, default: ' &')
+  --socket PATH               Write the selection as JSON to the Unix domain socket at PATH
+  --echo-selection            Briefly show the selected entry before exiting
+  --metrics-file PATH         Append the time until selection and the search mode to PATH
+  --help                      Show this help
+  --version                   Show the version
+
+Keys (with their action for --keys):
+  Ctrl-R, Down, Tab           Next match (next_match)
+  Ctrl-S, Up, Shift-Tab       Previous match (prev_match)
+  Ctrl-O                      Next occurrence of the query in the current match
+                              (next_occurrence)
+  Enter                       Accept and run (accept)
+  Ctrl-Z                      Accept and run in the background (accept_background)
+  Alt-Enter                   Accept without running, with the cursor at the end to edit it
+                              first (accept_edit)
+  Left, Right, Home, End      Accept and move the cursor (accept_cursor), or move within
+                              the query instead while nothing matches or the cursor isn't at
+                              its end
+  Alt-b, Alt-f                Move within the query by word (backward_word, forward_word)
+  Backspace                   Delete the character in front of the cursor (delete_char)
+  Alt-Backspace, Ctrl-W       Delete the word (or everything but whitespace) in front of the
+                              cursor (delete_word, delete_whitespace_word)
+  Ctrl-U                      Delete the whole query (clear)
+  Alt-p, Alt-n                Show the entry before or after the current match
+                              (context_before, context_after)
+  Ctrl-Y                      Copy the current match (copy)
+  Ctrl-D                      Delete the current match's command, i.e. all its entries, from
+                              the --history file too on exit (delete_entry)
+  Alt-c                       Copy the current match's directory (needs --metadata)
+                              (copy_directory)
+  Ctrl-F                      Toggle fuzzy matching (toggle_fuzzy)
+  Ctrl-T                      Cycle through case sensitivities (cycle_case)
+  Alt-a                       Toggle only matching at the start (see --prefix) (toggle_prefix)
+  Ctrl-P                      Toggle showing the current match (toggle_preview)
+  Esc, Ctrl-C, Ctrl-G         Quit (quit)
+
+Environment:
+  KONTROLLEURS_PROMPT         Text in front of the query, see --prompt
+  KONTROLLEURS_HIGHLIGHT      How highlights look, a comma-separated list of fg=COLOR (a name
+                              like red or light-red, or a number up to 255), bold, invert and
+                              underline (default: fg=red,invert,bold)
+  NO_COLOR                    Don't color highlights if set and not empty, like --no-color
+
+Exit status:
+  0  An entry was selected, or it or its directory copied
+  1  Something went wrong
+  2  Quit without selecting anything, e.g. with Esc
+  3  Accepted while nothing matched
+";
+
+/// How many entries are scanned at most when all matches are needed upfront.
+pub const DEFAULT_MAX_SCAN: usize = 100_000;
+
+/// Text in front of the query if neither --prompt nor `PROMPT_VAR` is set.
+pub const DEFAULT_PROMPT: &str = "bck-i-search: ";
+
+/// Environment variable with the text in front of the query.
+pub const PROMPT_VAR: &str = "KONTROLLEURS_PROMPT";
+
+/// Environment variable with how highlights look, see `Highlight::parse`.
+pub const HIGHLIGHT_VAR: &str = "KONTROLLEURS_HIGHLIGHT";
+
+/// How many characters the query needs at least for matches to be highlighted.
+pub const DEFAULT_MIN_HIGHLIGHT_LENGTH: usize = 1;
+
+/// What's shown in front of the second and later lines of multiline entries.
+pub const DEFAULT_CONTINUATION_MARKER: &str = "│ ";
+
+/// What the fish format appends to entries that should run in the background.
+pub const DEFAULT_BACKGROUND_SUFFIX: &str = " &";
+
+/// Runtime configuration, assembled from the command line.
+#[derive(Debug, Default, PartialEq)]
+pub struct Config {
+    /// When to match the query case-sensitively.
+    pub case: CaseMode,
+    /// How the query is matched initially.
+    pub match_mode: MatchMode,
+    /// Only match the query at the start of an entry (or of one of its lines), with substring
+    /// matching.
+    pub prefix: bool,
+    /// Only match the query at the end of an entry (or of one of its lines), with substring
+    /// matching.
+    pub suffix: bool,
+    /// Let whitespace in the query match any run of whitespace in entries.
+    pub loose_whitespace: bool,
+    /// Only search entries whose first word is this command, and only their arguments.
+    pub command: Option<String>,
+    /// Hide the command of `command` in the preview.
+    pub strip_command: bool,
+    /// Only search the command name of entries (of the arguments with `command`), see
+    /// `command_name`.
+    pub command_only: bool,
+    /// File to read key bindings from (`keys::default_path` if unset).
+    pub keys: Option<PathBuf>,
+    /// Which key does what, with `--accept-keys` applied and then read from `keys` by `main`.
+    pub bindings: Bindings,
+    /// Expand environment variables in entries before matching them.
+    pub expand_env: bool,
+    /// Leave undefined variables as they are instead of expanding them to nothing.
+    pub keep_undefined_env: bool,
+    /// Initial query to search for.
+    pub query: Option<String>,
+    /// Start with the query of the last search, and save the query on exit.
+    pub resume: bool,
+    /// Print the first match of the query instead of searching interactively.
+    pub first: bool,
+    /// Command line before searching, emitted unchanged when quitting.
+    pub commandline: Option<String>,
+    pub format: OutputFormat,
+    /// Unix domain socket to write the selection to, as JSON.
+    pub socket: Option<PathBuf>,
+    /// Suffix to run an entry in the background (`DEFAULT_BACKGROUND_SUFFIX` if unset).
+    pub background_suffix: Option<String>,
+    /// History records start with `#key=value` metadata lines.
+    pub metadata: bool,
+    /// Match the query against the files an entry produced instead of the entry. Entries
+    /// without `output` metadata never match.
+    pub outputs: bool,
+    pub cycle_order: CycleOrder,
+    /// Start over at the first match after the last one.
+    pub wrap: bool,
+    /// The order of the history itself.
+    pub sort: HistorySort,
+    /// Number of most recent entries that never match.
+    pub skip_newest: usize,
+    /// Read at most this many history records (all if unset).
+    pub max_entries: Option<usize>,
+    /// Search the history oldest first.
+    pub reverse: bool,
+    /// Count up to this many matches in the background, while waiting for input.
+    pub count_matches: Option<usize>,
+    /// Don't dim the number of matches when it's less than half of the previous query's.
+    pub no_count_drop: bool,
+    /// Scan at most this many entries when collecting all matches (`DEFAULT_MAX_SCAN` if unset).
+    pub max_scan: Option<usize>,
+    /// Fold consecutive entries with the same command into one.
+    pub fold_repeats: bool,
+    /// Don't skip commands that occur again later in the history.
+    pub keep_duplicates: bool,
+    /// Skip entries that are blank or consist of comments only.
+    pub skip_comments: bool,
+    /// Show this many matches at once instead of only the current one.
+    pub list: Option<usize>,
+    /// Show the match mode, case sensitivity and ordinal in a status bar on the last row.
+    pub status: bool,
+    /// Don't show whitespace at the start of entries (or of the line shown in their place).
+    pub trim_whitespace: bool,
+    /// Text in front of continuation lines of multiline entries
+    /// (`DEFAULT_CONTINUATION_MARKER` if unset).
+    pub continuation_marker: Option<String>,
+    /// Only show the matching line of multiline entries.
+    pub collapse_multiline: bool,
+    /// Mark highlights that directly follow another highlight.
+    pub separate_highlights: bool,
+    /// Don't color highlights.
+    pub no_color: bool,
+    /// How highlights look.
+    pub highlight: Highlight,
+    /// Don't highlight matches of shorter queries (`DEFAULT_MIN_HIGHLIGHT_LENGTH` if unset).
+    pub min_highlight_length: Option<usize>,
+    /// Highlight at most this many occurrences per line (unlimited if unset).
+    pub max_highlights: Option<usize>,
+    /// Characters of ambiguous width take two columns instead of one.
+    pub ambiguous_wide: bool,
+    /// Indent rows of wrapped lines by this many columns (0 leaves wrapping to the terminal).
+    pub wrap_indent: usize,
+    /// Cut long lines off at the terminal width instead of wrapping them.
+    pub truncate: bool,
+    /// Briefly show the selected entry before handing it to the shell.
+    pub echo_selection: bool,
+    /// File to read the history from instead of stdin.
+    pub history: Option<PathBuf>,
+    /// How history entries are separated.
+    pub input_format: InputFormat,
+    /// File to append selection latency records to.
+    pub metrics_file: Option<PathBuf>,
+    /// Text in front of the query (`DEFAULT_PROMPT` if unset).
+    pub prompt: Option<String>,
+    /// What happens to a query too long for the prompt's row.
+    pub prompt_wrap: PromptWrap,
+    /// Show the usage instead of searching.
+    pub help: bool,
+    /// Show the version instead of searching.
+    pub version: bool,
+}
+
+/// How a prompt wider than the terminal is shown.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PromptWrap {
+    /// Wrapped onto as many rows as needed.
+    #[default]
+    On,
+    /// Kept on a single row, with only the part of the query around the cursor shown.
+    Off,
+}
+
+/// When to match case-sensitively.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CaseMode {
+    /// Only if the query contains an uppercase letter.
+    #[default]
+    Smart,
+    Sensitive,
+    Insensitive,
+}
+
+impl CaseMode {
+    pub fn is_sensitive(self, query: &str) -> bool {
+        match self {
+            Self::Smart => query.chars().any(char::is_uppercase),
+            Self::Sensitive => true,
+            Self::Insensitive => false,
+        }
+    }
+
+    /// The mode after `self` when cycling through all modes.
+    pub fn next(self) -> Self {
+        match self {
+            Self::Smart => Self::Sensitive,
+            Self::Sensitive => Self::Insensitive,
+            Self::Insensitive => Self::Smart,
+        }
+    }
+}
+
+/// How the query is matched against entries.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MatchMode {
+    /// The entry contains the query.
+    #[default]
+    Substring,
+    /// The entry contains all characters of the query in the same order, possibly with other
+    /// characters between them.
+    Fuzzy,
+    /// The entry contains text matching the query as glob pattern, where `*` matches any
+    /// (possibly empty) text and `?` any single character.
+    Glob,
+}
+
+/// The order in which matches are cycled through.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CycleOrder {
+    /// The order of the history source, evaluated lazily.
+    #[default]
+    History,
+    /// Most recent first, by the entries' timestamps. This needs to find all matches upfront and
+    /// hence keeps them all in memory.
+    Recent,
+}
+
+/// The order of the history, before any matching.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum HistorySort {
+    /// As read, i.e. most recent first.
+    #[default]
+    Recency,
+    /// Most frequently run commands first, then most recent first.
+    Frequency,
+}
+
+/// How history entries are separated.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum InputFormat {
+    /// Null-separated, which allows for multiline entries.
+    #[default]
+    Fish,
+    /// Newline-separated.
+    Bash,
+    /// Newline-separated with backslash continuations, optionally with a `: <timestamp>:<elapsed>;`
+    /// prefix.
+    Zsh,
+}
+
+impl InputFormat {
+    /// The byte entries end with.
+    pub fn separator(self) -> u8 {
+        match self {
+            Self::Fish => b'\0',
+            Self::Bash | Self::Zsh => b'\n',
+        }
+    }
+}
+
+/// How the selection is printed.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputFormat {
+    /// `true`/`false` whether to execute, the cursor position (in characters) and the
+    /// null-terminated entry, each on its own line.
+    #[default]
+    Lines,
+    /// `commandline` calls for fish to `source`, i.e. `commandline -rb -- ENTRY`,
+    /// `commandline -C CURSOR` and, if the entry should be executed, `commandline -f execute`.
+    Fish,
+    /// A single line with a JSON object with the keys `entry`, `execute` and `background`
+    /// (booleans), `cursor` (in characters) and `match_start` and `match_end` (byte offsets in
+    /// the entry, or `null`).
+    Json,
+}
+
+/// How highlighted matches look.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Highlight {
+    /// The ANSI color of the text
+    pub fg: Option<u8>,
+    pub bold: bool,
+    pub invert: bool,
+    pub underline: bool,
+}
+
+/// Red, inverted and bold.
+pub const DEFAULT_HIGHLIGHT: Highlight = Highlight {
+    fg: Some(1),
+    bold: true,
+    invert: true,
+    underline: false,
+};
+
+impl Default for Highlight {
+    fn default() -> Self {
+        DEFAULT_HIGHLIGHT
+    }
+}
+
+impl Highlight {
+    /// Parses a comma-separated list of `fg=COLOR` (a name like `red` or `light-red`, or an ANSI
+    /// color number), `bold`, `invert` and `underline`. Unknown items are ignored, without any
+    /// known item it's the default.
+    pub fn parse(text: &str) -> Self {
+        let mut highlight = Self {
+            fg: None,
+            bold: false,
+            invert: false,
+            underline: false,
+        };
+        let mut known = false;
+        for item in text.split(',').map(str::trim) {
+            match item {
+                "bold" => highlight.bold = true,
+                "invert" => highlight.invert = true,
+                "underline" => highlight.underline = true,
+                _ => match item.strip_prefix("fg=").and_then(color_value) {
+                    Some(fg) => highlight.fg = Some(fg),
+                    None => continue,
+                },
+            }
+            known = true;
+        }
+        if known {
+            highlight
+        } else {
+            Self::default()
+        }
+    }
+}
+
+/// Returns the ANSI color number of `name`.
+fn color_value(name: &str) -> Option<u8> {
+    const NAMES: [&str; 8] = [
+        "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+    ];
+    if let Ok(value) = name.parse() {
+        return Some(value);
+    }
+    let (offset, name) = match name.strip_prefix("light-") {
+        Some(name) => (8, name),
+        None => (0, name),
+    };
+    let index = NAMES.iter().position(|&known| known == name)?;
+    Some(offset + index as u8)
+}
+
+impl Config {
+    /// Parses the given command line arguments (without the program name).
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut config = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--case" => {
+                    config.case = match value(&arg, &mut args)?.as_str() {
+                        "smart" => CaseMode::Smart,
+                        "sensitive" => CaseMode::Sensitive,
+                        "insensitive" => CaseMode::Insensitive,
+                        other => return Err(format!("Invalid value for {arg}: {other}")),
+                    }
+                }
+                "--case-sensitive" => config.case = CaseMode::Sensitive,
+                "--mode" => {
+                    config.match_mode = match value(&arg, &mut args)?.as_str() {
+                        "substring" => MatchMode::Substring,
+                        "fuzzy" => MatchMode::Fuzzy,
+                        "glob" => MatchMode::Glob,
+                        other => return Err(format!("Invalid value for {arg}: {other}")),
+                    }
+                }
+                "--prefix" => config.prefix = true,
+                "--suffix" => config.suffix = true,
+                "--loose-whitespace" => config.loose_whitespace = true,
+                "--command" => config.command = Some(value(&arg, &mut args)?),
+                "--strip-command" => config.strip_command = true,
+                "--command-only" => config.command_only = true,
+                "--accept-keys" => {
+                    let keys = match value(&arg, &mut args)?.as_str() {
+                        "all" => "left, right, home, end",
+                        "enter" => "",
+                        other => return Err(format!("Invalid value for {arg}: {other}")),
+                    };
+                    config.bindings.apply(&format!("accept_cursor = {keys}"))?;
+                }
+                "--help" => config.help = true,
+                "--version" => config.version = true,
+                "--echo-selection" => config.echo_selection = true,
+                "--history" => config.history = Some(PathBuf::from(value(&arg, &mut args)?)),
+                "--keys" => config.keys = Some(PathBuf::from(value(&arg, &mut args)?)),
+                "--input-format" => {
+                    config.input_format = match value(&arg, &mut args)?.as_str() {
+                        "fish" => InputFormat::Fish,
+                        "bash" => InputFormat::Bash,
+                        "zsh" => InputFormat::Zsh,
+                        other => return Err(format!("Invalid value for {arg}: {other}")),
+                    }
+                }
+                "--metrics-file" => {
+                    config.metrics_file = Some(PathBuf::from(value(&arg, &mut args)?))
+                }
+                "--prompt" => config.prompt = Some(value(&arg, &mut args)?),
+                "--prompt-wrap" => {
+                    config.prompt_wrap = match value(&arg, &mut args)?.as_str() {
+                        "on" => PromptWrap::On,
+                        "off" => PromptWrap::Off,
+                        other => return Err(format!("Invalid value for {arg}: {other}")),
+                    }
+                }
+                "--query" => config.query = Some(value(&arg, &mut args)?),
+                "--resume" => config.resume = true,
+                "--first" => config.first = true,
+                "--commandline" => config.commandline = Some(value(&arg, &mut args)?),
+                "--socket" => config.socket = Some(PathBuf::from(value(&arg, &mut args)?)),
+                "--background-suffix" => config.background_suffix = Some(value(&arg, &mut args)?),
+                "--format" => {
+                    config.format = match value(&arg, &mut args)?.as_str() {
+                        "lines" => OutputFormat::Lines,
+                        "fish" => OutputFormat::Fish,
+                        "json" => OutputFormat::Json,
+                        other => return Err(format!("Invalid value for {arg}: {other}")),
+                    }
+                }
+                "--metadata" => config.metadata = true,
+                "--outputs" => config.outputs = true,
+                "--cycle-order" => {
+                    config.cycle_order = match value(&arg, &mut args)?.as_str() {
+                        "history" => CycleOrder::History,
+                        "recent" => CycleOrder::Recent,
+                        other => return Err(format!("Invalid value for {arg}: {other}")),
+                    }
+                }
+                "--sort" => {
+                    config.sort = match value(&arg, &mut args)?.as_str() {
+                        "recency" => HistorySort::Recency,
+                        "frequency" => HistorySort::Frequency,
+                        other => return Err(format!("Invalid value for {arg}: {other}")),
+                    }
+                }
+                "--wrap" => config.wrap = true,
+                "--skip-newest" => {
+                    let count = value(&arg, &mut args)?;
+                    config.skip_newest = count
+                        .parse()
+                        .map_err(|_| format!("Invalid value for {arg}: {count}"))?;
+                }
+                "--max-entries" => {
+                    let max_entries = value(&arg, &mut args)?;
+                    config.max_entries = Some(
+                        max_entries
+                            .parse()
+                            .map_err(|_| format!("Invalid value for {arg}: {max_entries}"))?,
+                    );
+                }
+                "--reverse" => config.reverse = true,
+                "--max-scan" => {
+                    let max_scan = value(&arg, &mut args)?;
+                    config.max_scan = Some(
+                        max_scan
+                            .parse()
+                            .map_err(|_| format!("Invalid value for {arg}: {max_scan}"))?,
+                    );
+                }
+                "--fold-repeats" => config.fold_repeats = true,
+                "--keep-duplicates" => config.keep_duplicates = true,
+                "--skip-comments" => config.skip_comments = true,
+                "--count-matches" => {
+                    let limit = value(&arg, &mut args)?;
+                    config.count_matches = Some(
+                        limit
+                            .parse()
+                            .ok()
+                            .filter(|&limit| limit > 0)
+                            .ok_or_else(|| format!("Invalid value for {arg}: {limit}"))?,
+                    );
+                }
+                "--no-count-drop" => config.no_count_drop = true,
+                "--list" => {
+                    let rows = value(&arg, &mut args)?;
+                    config.list = Some(
+                        rows.parse()
+                            .ok()
+                            .filter(|&rows| rows > 0)
+                            .ok_or_else(|| format!("Invalid value for {arg}: {rows}"))?,
+                    );
+                }
+                "--status" => config.status = true,
+                "--trim-whitespace" => config.trim_whitespace = true,
+                "--continuation-marker" => {
+                    config.continuation_marker = Some(value(&arg, &mut args)?)
+                }
+                "--collapse-multiline" => config.collapse_multiline = true,
+                "--separate-highlights" => config.separate_highlights = true,
+                "--no-color" => config.no_color = true,
+                "--min-highlight-length" => {
+                    let length = value(&arg, &mut args)?;
+                    config.min_highlight_length = Some(
+                        length
+                            .parse()
+                            .map_err(|_| format!("Invalid value for {arg}: {length}"))?,
+                    );
+                }
+                "--max-highlights" => {
+                    let max = value(&arg, &mut args)?;
+                    config.max_highlights = Some(
+                        max.parse()
+                            .map_err(|_| format!("Invalid value for {arg}: {max}"))?,
+                    );
+                }
+                "--ambiguous-width" => {
+                    config.ambiguous_wide = match value(&arg, &mut args)?.as_str() {
+                        "narrow" => false,
+                        "wide" => true,
+                        other => return Err(format!("Invalid value for {arg}: {other}")),
+                    }
+                }
+                "--wrap-indent" => {
+                    let indent = value(&arg, &mut args)?;
+                    config.wrap_indent = indent
+                        .parse()
+                        .map_err(|_| format!("Invalid value for {arg}: {indent}"))?;
+                }
+                "--truncate" => config.truncate = true,
+                "--expand-env" => config.expand_env = true,
+                "--keep-undefined-env" => config.keep_undefined_env = true,
+                _ => return Err(format!("Unknown argument: {arg}")),
+            }
+        }
+        if config.first && config.query.is_none() {
+            return Err("--first needs a --query".to_string());
+        }
+        Ok(config)
+    }
+}
+
+fn value(flag: &str, args: &mut impl Iterator<Item = String>) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("Missing value for {flag}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use termion::event::Key;
+
+    use std::path::PathBuf;
+
+    use crate::keys::Action;
+
+    use super::{
+        Bindings, CaseMode, Config, CycleOrder, Highlight, HistorySort, InputFormat, MatchMode,
+        OutputFormat, PromptWrap,
+    };
+
+    fn parse(args: &[&str]) -> Result<Config, String> {
+        Config::from_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_defaults() {
+        assert_eq!(parse(&[]), Ok(Config::default()));
+        assert_eq!(Config::default().case, CaseMode::Smart);
+    }
+
+    #[test]
+    fn test_case() {
+        assert_eq!(
+            parse(&["--case", "insensitive"]).unwrap().case,
+            CaseMode::Insensitive
+        );
+        assert_eq!(
+            parse(&["--case-sensitive"]).unwrap().case,
+            CaseMode::Sensitive
+        );
+        assert!(parse(&["--case", "upper"]).is_err());
+    }
+
+    #[test]
+    fn test_smart_case() {
+        assert!(!CaseMode::Smart.is_sensitive("git push"));
+        assert!(CaseMode::Smart.is_sensitive("README"));
+        assert!(!CaseMode::Insensitive.is_sensitive("README"));
+        assert!(CaseMode::Sensitive.is_sensitive("git push"));
+    }
+
+    #[test]
+    fn test_accept_keys() {
+        let mut bindings = parse(&["--accept-keys", "enter"]).unwrap().bindings;
+        assert_eq!(bindings.action(Key::Left), None);
+        assert_eq!(bindings.action(Key::Char('\n')), Some(Action::Accept));
+        // A key bindings file goes on from there
+        bindings.apply("accept_cursor = end").unwrap();
+        assert_eq!(bindings.action(Key::End), Some(Action::AcceptCursor));
+        assert_eq!(bindings.action(Key::Home), None);
+        let bindings = parse(&["--accept-keys", "enter", "--accept-keys", "all"])
+            .unwrap()
+            .bindings;
+        for key in [Key::Left, Key::Right, Key::Home, Key::End] {
+            assert_eq!(bindings.action(key), Some(Action::AcceptCursor));
+        }
+        assert!(parse(&["--accept-keys"]).is_err());
+        assert!(parse(&["--accept-keys", "spam"]).is_err());
+    }
+
+    #[test]
+    fn test_expand_env() {
+        let config = parse(&["--expand-env", "--keep-undefined-env"]).unwrap();
+        assert!(config.expand_env);
+        assert!(config.keep_undefined_env);
+    }
+
+    #[test]
+    fn test_query() {
+        let config = parse(&["--query", "git push"]).unwrap();
+        assert_eq!(config.query.as_deref(), Some("git push"));
+    }
+
+    #[test]
+    fn test_first() {
+        assert!(parse(&["--first", "--query", "git"]).unwrap().first);
+        assert_eq!(
+            parse(&["--first"]),
+            Err("--first needs a --query".to_string())
+        );
+    }
+
+    #[test]
+    fn test_resume() {
+        assert!(parse(&["--resume"]).unwrap().resume);
+        assert!(!parse(&[]).unwrap().resume);
+    }
+
+    #[test]
+    fn test_commandline() {
+        let config = parse(&["--commandline", "git st"]).unwrap();
+        assert_eq!(config.commandline.as_deref(), Some("git st"));
+        assert!(parse(&["--commandline"]).is_err());
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(
+            parse(&["--format", "fish"]).unwrap().format,
+            OutputFormat::Fish
+        );
+        assert!(parse(&["--format", "zsh"]).is_err());
+    }
+
+    #[test]
+    fn test_socket() {
+        let config = parse(&["--socket", "/run/kontrolleurs.sock"]).unwrap();
+        assert_eq!(config.socket, Some(PathBuf::from("/run/kontrolleurs.sock")));
+    }
+
+    #[test]
+    fn test_background_suffix() {
+        let config = parse(&["--background-suffix", " &; disown"]).unwrap();
+        assert_eq!(config.background_suffix.as_deref(), Some(" &; disown"));
+    }
+
+    #[test]
+    fn test_cycle_order() {
+        let config = parse(&["--metadata", "--cycle-order", "recent"]).unwrap();
+        assert!(config.metadata);
+        assert_eq!(config.cycle_order, CycleOrder::Recent);
+        assert!(parse(&["--cycle-order", "random"]).is_err());
+    }
+
+    #[test]
+    fn test_sort() {
+        let config = parse(&["--sort", "frequency"]).unwrap();
+        assert_eq!(config.sort, HistorySort::Frequency);
+        assert!(parse(&["--sort", "alphabetical"]).is_err());
+    }
+
+    #[test]
+    fn test_wrap() {
+        assert!(parse(&["--wrap"]).unwrap().wrap);
+        assert!(!parse(&[]).unwrap().wrap);
+    }
+
+    #[test]
+    fn test_skip_newest() {
+        assert_eq!(parse(&["--skip-newest", "1"]).unwrap().skip_newest, 1);
+        assert!(parse(&["--skip-newest", "one"]).is_err());
+    }
+
+    #[test]
+    fn test_reverse() {
+        assert!(parse(&["--reverse"]).unwrap().reverse);
+        assert!(!parse(&[]).unwrap().reverse);
+    }
+
+    #[test]
+    fn test_max_entries() {
+        assert_eq!(parse(&["--max-entries", "5"]).unwrap().max_entries, Some(5));
+        assert_eq!(parse(&[]).unwrap().max_entries, None);
+        assert!(parse(&["--max-entries", "many"]).is_err());
+    }
+
+    #[test]
+    fn test_max_scan() {
+        assert_eq!(parse(&["--max-scan", "10"]).unwrap().max_scan, Some(10));
+        assert!(parse(&["--max-scan", "-1"]).is_err());
+    }
+
+    #[test]
+    fn test_echo_selection() {
+        assert!(parse(&["--echo-selection"]).unwrap().echo_selection);
+    }
+
+    #[test]
+    fn test_keys() {
+        let config = parse(&["--keys", "/tmp/keys"]).unwrap();
+        assert_eq!(config.keys, Some(PathBuf::from("/tmp/keys")));
+        assert_eq!(config.bindings, Bindings::default());
+        assert!(parse(&["--keys"]).is_err());
+    }
+
+    #[test]
+    fn test_history() {
+        let config = parse(&["--history", "/tmp/fish_history"]).unwrap();
+        assert_eq!(config.history, Some(PathBuf::from("/tmp/fish_history")));
+    }
+
+    #[test]
+    fn test_input_format() {
+        let config = parse(&["--input-format", "bash"]).unwrap();
+        assert_eq!(config.input_format, InputFormat::Bash);
+        assert_eq!(config.input_format.separator(), b'\n');
+        assert!(parse(&["--input-format", "ksh"]).is_err());
+    }
+
+    #[test]
+    fn test_metrics_file() {
+        let config = parse(&["--metrics-file", "/tmp/metrics"]).unwrap();
+        assert_eq!(config.metrics_file, Some(PathBuf::from("/tmp/metrics")));
+    }
+
+    #[test]
+    fn test_command() {
+        let config = parse(&["--command", "git", "--strip-command"]).unwrap();
+        assert_eq!(config.command.as_deref(), Some("git"));
+        assert!(config.strip_command);
+        assert!(parse(&["--command"]).is_err());
+        assert!(parse(&["--command-only"]).unwrap().command_only);
+    }
+
+    #[test]
+    fn test_outputs() {
+        assert!(parse(&["--outputs"]).unwrap().outputs);
+    }
+
+    #[test]
+    fn test_mode() {
+        assert_eq!(
+            parse(&["--mode", "fuzzy"]).unwrap().match_mode,
+            MatchMode::Fuzzy
+        );
+        assert_eq!(
+            parse(&["--mode", "glob"]).unwrap().match_mode,
+            MatchMode::Glob
+        );
+        assert!(parse(&["--mode", "regex"]).is_err());
+    }
+
+    #[test]
+    fn test_prefix() {
+        assert!(parse(&["--prefix"]).unwrap().prefix);
+    }
+
+    #[test]
+    fn test_suffix() {
+        assert!(parse(&["--suffix"]).unwrap().suffix);
+    }
+
+    #[test]
+    fn test_loose_whitespace() {
+        assert!(!parse(&[]).unwrap().loose_whitespace);
+        assert!(parse(&["--loose-whitespace"]).unwrap().loose_whitespace);
+    }
+
+    #[test]
+    fn test_fold_repeats() {
+        assert!(parse(&["--fold-repeats"]).unwrap().fold_repeats);
+    }
+
+    #[test]
+    fn test_keep_duplicates() {
+        assert!(parse(&["--keep-duplicates"]).unwrap().keep_duplicates);
+    }
+
+    #[test]
+    fn test_no_color() {
+        assert!(parse(&["--no-color"]).unwrap().no_color);
+    }
+
+    #[test]
+    fn test_skip_comments() {
+        assert!(parse(&["--skip-comments"]).unwrap().skip_comments);
+    }
+
+    #[test]
+    fn test_trim_whitespace() {
+        assert!(parse(&["--trim-whitespace"]).unwrap().trim_whitespace);
+    }
+
+    #[test]
+    fn test_continuation_marker() {
+        let config = parse(&["--continuation-marker", ""]).unwrap();
+        assert_eq!(config.continuation_marker.as_deref(), Some(""));
+        assert!(parse(&["--continuation-marker"]).is_err());
+    }
+
+    #[test]
+    fn test_collapse_multiline() {
+        assert!(parse(&["--collapse-multiline"]).unwrap().collapse_multiline);
+    }
+
+    #[test]
+    fn test_prompt_and_help() {
+        let config = parse(&["--prompt", "search: ", "--help"]).unwrap();
+        assert_eq!(config.prompt.as_deref(), Some("search: "));
+        assert!(config.help);
+    }
+
+    #[test]
+    fn test_version() {
+        let config = parse(&["--version"]).unwrap();
+        assert!(config.version);
+        assert!(!config.help);
+    }
+
+    #[test]
+    fn test_separate_highlights() {
+        assert!(
+            parse(&["--separate-highlights"])
+                .unwrap()
+                .separate_highlights
+        );
+    }
+
+    #[test]
+    fn test_min_highlight_length() {
+        assert_eq!(parse(&[]).unwrap().min_highlight_length, None);
+        let config = parse(&["--min-highlight-length", "3"]).unwrap();
+        assert_eq!(config.min_highlight_length, Some(3));
+        assert!(parse(&["--min-highlight-length", "x"]).is_err());
+    }
+
+    #[test]
+    fn test_highlight() {
+        assert_eq!(
+            Highlight::parse("fg=green,bold,underline"),
+            Highlight {
+                fg: Some(2),
+                bold: true,
+                invert: false,
+                underline: true,
+            }
+        );
+        assert_eq!(
+            Highlight::parse(" invert , fg=light-blue "),
+            Highlight {
+                fg: Some(12),
+                bold: false,
+                invert: true,
+                underline: false,
+            }
+        );
+        // Unknown items are skipped
+        assert_eq!(
+            Highlight::parse("fg=208,blink,fg=purple"),
+            Highlight {
+                fg: Some(208),
+                bold: false,
+                invert: false,
+                underline: false,
+            }
+        );
+        assert_eq!(Highlight::parse("blink,fg=256"), Highlight::default());
+        assert_eq!(Highlight::parse(""), Highlight::default());
+    }
+
+    #[test]
+    fn test_list() {
+        assert_eq!(parse(&["--list", "5"]).unwrap().list, Some(5));
+        assert!(parse(&["--list", "0"]).is_err());
+        assert!(parse(&["--list", "many"]).is_err());
+    }
+
+    #[test]
+    fn test_count_matches() {
+        let config = parse(&["--count-matches", "100"]).unwrap();
+        assert_eq!(config.count_matches, Some(100));
+        assert!(parse(&["--count-matches", "0"]).is_err());
+        assert!(parse(&["--no-count-drop"]).unwrap().no_count_drop);
+    }
+
+    #[test]
+    fn test_status() {
+        assert!(parse(&["--status"]).unwrap().status);
+        assert!(!parse(&[]).unwrap().status);
+    }
+
+    #[test]
+    fn test_max_highlights() {
+        assert_eq!(
+            parse(&["--max-highlights", "3"]).unwrap().max_highlights,
+            Some(3)
+        );
+        assert!(parse(&["--max-highlights", "many"]).is_err());
+    }
+
+    #[test]
+    fn test_ambiguous_width() {
+        assert!(
+            parse(&["--ambiguous-width", "wide"])
+                .unwrap()
+                .ambiguous_wide
+        );
+        assert!(
+            !parse(&["--ambiguous-width", "narrow"])
+                .unwrap()
+                .ambiguous_wide
+        );
+        assert!(parse(&["--ambiguous-width", "medium"]).is_err());
+    }
+
+    #[test]
+    fn test_wrap_indent() {
+        assert_eq!(parse(&["--wrap-indent", "2"]).unwrap().wrap_indent, 2);
+        assert!(parse(&["--wrap-indent", "two"]).is_err());
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert!(parse(&["--truncate"]).unwrap().truncate);
+        assert!(!parse(&[]).unwrap().truncate);
+    }
+
+    #[test]
+    fn test_prompt_wrap() {
+        assert_eq!(parse(&[]).unwrap().prompt_wrap, PromptWrap::On);
+        let config = parse(&["--prompt-wrap", "off"]).unwrap();
+        assert_eq!(config.prompt_wrap, PromptWrap::Off);
+        assert!(parse(&["--prompt-wrap", "auto"]).is_err());
+    }
+
+    #[test]
+    fn test_unknown_argument() {
+        assert!(parse(&["--spam"]).is_err());
+    }
+}
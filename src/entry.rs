@@ -0,0 +1,136 @@
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+/// A history entry, i.e. a command plus whatever metadata the history source provided.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Entry {
+    pub command: String,
+    /// When the command was run, as Unix timestamp
+    pub timestamp: Option<u64>,
+    /// The directory the command was run in
+    pub cwd: Option<String>,
+    /// Files the command produced
+    pub outputs: Vec<String>,
+    /// How often the command was repeated right after this entry (and folded into it)
+    pub repeats: usize,
+}
+
+impl Entry {
+    /// Splits leading metadata lines of the form `#key=value` off a history record. Known keys
+    /// are `when` (a Unix timestamp, as written by fish's `history --show-time='#when=%s%n'`),
+    /// `cwd` (the working directory) and `output` (a file the command produced, one line per
+    /// file), other keys are ignored.
+    pub fn with_metadata(record: String) -> Self {
+        let mut entry = Self::default();
+        let mut rest = record.as_str();
+        while let Some((key, value, remainder)) = metadata_line(rest) {
+            match key {
+                "when" => entry.timestamp = value.parse().ok(),
+                "cwd" => entry.cwd = Some(value.to_owned()),
+                "output" => entry.outputs.push(value.to_owned()),
+                _ => (),
+            }
+            rest = remainder;
+        }
+        entry.command = rest.to_owned();
+        entry
+    }
+
+    /// Splits the `: <timestamp>:<elapsed>;` prefix of zsh's extended history off a record. Records
+    /// without such a prefix are taken as they are.
+    pub fn from_zsh(record: String) -> Self {
+        static PREFIX: OnceLock<Regex> = OnceLock::new();
+        let prefix = PREFIX
+            .get_or_init(|| Regex::new(r"^: (\d+):\d+;").expect("Should be valid regex pattern"));
+        let Some(captures) = prefix.captures(&record) else {
+            return Self::from(record);
+        };
+        let end = captures.get(0).expect("Group 0 is the whole match").end();
+        Self {
+            timestamp: captures[1].parse().ok(),
+            command: record[end..].to_owned(),
+            ..Self::default()
+        }
+    }
+
+    /// Whether the command is empty or consists of comments only.
+    pub fn is_blank_or_comment(&self) -> bool {
+        self.command.lines().all(|line| {
+            let line = line.trim_start();
+            line.is_empty() || line.starts_with('#')
+        })
+    }
+}
+
+impl From<String> for Entry {
+    fn from(command: String) -> Self {
+        Self {
+            command,
+            ..Self::default()
+        }
+    }
+}
+
+/// Returns key, value and the remaining text if `text` starts with a metadata line.
+fn metadata_line(text: &str) -> Option<(&str, &str, &str)> {
+    let (line, remainder) = text.strip_prefix('#')?.split_once('\n')?;
+    let (key, value) = line.split_once('=')?;
+    let valid_key = !key.is_empty()
+        && key
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || ch == '_');
+    valid_key.then_some((key, value, remainder))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Entry;
+
+    #[test]
+    fn test_with_metadata() {
+        let entry =
+            Entry::with_metadata("#when=1700000000\n#spam=eggs\n#cwd=/tmp\ngit push".to_string());
+        assert_eq!(entry.command, "git push");
+        assert_eq!(entry.timestamp, Some(1700000000));
+        assert_eq!(entry.cwd.as_deref(), Some("/tmp"));
+    }
+
+    #[test]
+    fn test_with_metadata_outputs() {
+        let entry = Entry::with_metadata("#output=a.o\n#output=b.o\ncc -c a.c b.c".to_string());
+        assert_eq!(entry.outputs, vec!["a.o".to_string(), "b.o".to_string()]);
+    }
+
+    #[test]
+    fn test_from_zsh() {
+        let entry = Entry::from_zsh(": 1700000000:12;make -j8".to_string());
+        assert_eq!(entry.command, "make -j8");
+        assert_eq!(entry.timestamp, Some(1700000000));
+        let entry = Entry::from_zsh(": 1700000000:0;for i in 1 2\ndo echo $i\ndone".to_string());
+        assert_eq!(entry.command, "for i in 1 2\ndo echo $i\ndone");
+        // Not extended history
+        let entry = Entry::from_zsh(": not a timestamp;ls".to_string());
+        assert_eq!(entry.command, ": not a timestamp;ls");
+        assert_eq!(entry.timestamp, None);
+    }
+
+    #[test]
+    fn test_is_blank_or_comment() {
+        let is_blank_or_comment =
+            |command: &str| Entry::from(command.to_string()).is_blank_or_comment();
+        assert!(is_blank_or_comment(""));
+        assert!(is_blank_or_comment("  \t"));
+        assert!(is_blank_or_comment("  # TODO"));
+        assert!(is_blank_or_comment("# one\n\n# two"));
+        assert!(!is_blank_or_comment("# list\nls"));
+        assert!(!is_blank_or_comment("echo # not just a comment"));
+    }
+
+    #[test]
+    fn test_with_metadata_keeps_comments() {
+        let entry = Entry::with_metadata("# just a comment\nls".to_string());
+        assert_eq!(entry.command, "# just a comment\nls");
+        assert_eq!(entry.timestamp, None);
+    }
+}
@@ -0,0 +1,156 @@
+use std::ops::Range;
+
+/// A text with environment variables (`$NAME` or `${NAME}`) expanded, which remembers where
+/// each part of the expanded text came from.
+pub struct Expanded {
+    pub text: String,
+    /// (range in the expanded text, range in the original text, whether it's a variable)
+    segments: Vec<(Range<usize>, Range<usize>, bool)>,
+}
+
+impl Expanded {
+    /// Expands variables using the current environment.
+    pub fn from_env(original: &str, keep_undefined: bool) -> Self {
+        Self::new(original, |name| std::env::var(name).ok(), keep_undefined)
+    }
+
+    /// Expands variables by calling `lookup` for each variable name. Undefined variables either
+    /// expand to nothing or, if `keep_undefined` is set, are left as they are.
+    pub fn new(
+        original: &str,
+        lookup: impl Fn(&str) -> Option<String>,
+        keep_undefined: bool,
+    ) -> Self {
+        let mut text = String::with_capacity(original.len());
+        let mut segments = Vec::new();
+        let mut literal_start = 0;
+        let mut pos = 0;
+        while let Some(offset) = original[pos..].find('$') {
+            let start = pos + offset;
+            let Some((name, end)) = variable_at(original, start) else {
+                pos = start + 1;
+                continue;
+            };
+            let Some(value) = lookup(name).or_else(|| (!keep_undefined).then(String::new)) else {
+                pos = end;
+                continue;
+            };
+            if literal_start < start {
+                let expanded_start = text.len();
+                text.push_str(&original[literal_start..start]);
+                segments.push((expanded_start..text.len(), literal_start..start, false));
+            }
+            let expanded_start = text.len();
+            text.push_str(&value);
+            segments.push((expanded_start..text.len(), start..end, true));
+            literal_start = end;
+            pos = end;
+        }
+        if literal_start < original.len() {
+            let expanded_start = text.len();
+            text.push_str(&original[literal_start..]);
+            segments.push((
+                expanded_start..text.len(),
+                literal_start..original.len(),
+                false,
+            ));
+        }
+        Self { text, segments }
+    }
+
+    /// Maps a range in the expanded text back to the original text. A range that touches an
+    /// expanded variable covers the whole variable in the original.
+    pub fn to_original(&self, range: Range<usize>) -> Range<usize> {
+        let start = self
+            .segments
+            .iter()
+            .find(|(expanded, _, _)| expanded.contains(&range.start))
+            .map_or_else(
+                || self.original_len(),
+                |(expanded, original, variable)| {
+                    if *variable {
+                        original.start
+                    } else {
+                        original.start + range.start - expanded.start
+                    }
+                },
+            );
+        let end = self
+            .segments
+            .iter()
+            .find(|(expanded, _, _)| expanded.start < range.end && range.end <= expanded.end)
+            .map_or(start, |(expanded, original, variable)| {
+                if *variable {
+                    original.end
+                } else {
+                    original.start + range.end - expanded.start
+                }
+            });
+        start..end.max(start)
+    }
+
+    fn original_len(&self) -> usize {
+        self.segments
+            .last()
+            .map_or(0, |(_, original, _)| original.end)
+    }
+}
+
+/// Returns the variable name and end of the variable starting at `start`, if any.
+fn variable_at(text: &str, start: usize) -> Option<(&str, usize)> {
+    let rest = &text[start + 1..];
+    let is_name = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let (name, end) = if let Some(braced) = rest.strip_prefix('{') {
+        let len = braced.find('}')?;
+        (&braced[..len], start + len + 3)
+    } else {
+        let len = rest.find(|c| !is_name(c)).unwrap_or(rest.len());
+        (&rest[..len], start + len + 1)
+    };
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(is_name);
+    valid.then_some((name, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Expanded;
+
+    fn lookup(name: &str) -> Option<String> {
+        (name == "HOME").then(|| "/home/spam".to_string())
+    }
+
+    #[test]
+    fn test_expand_home() {
+        let expanded = Expanded::new("cd $HOME/eggs", lookup, false);
+        assert_eq!(expanded.text, "cd /home/spam/eggs");
+        let braced = Expanded::new("cd ${HOME}/eggs", lookup, false);
+        assert_eq!(braced.text, "cd /home/spam/eggs");
+    }
+
+    #[test]
+    fn test_expand_undefined() {
+        assert_eq!(Expanded::new("echo $NOPE!", lookup, false).text, "echo !");
+        assert_eq!(
+            Expanded::new("echo $NOPE!", lookup, true).text,
+            "echo $NOPE!"
+        );
+        assert_eq!(Expanded::new("echo $ $1", lookup, false).text, "echo $ $1");
+    }
+
+    #[test]
+    fn test_to_original() {
+        let original = "cd $HOME/eggs";
+        let expanded = Expanded::new(original, lookup, false);
+        // "spam/eggs" starts inside the variable and ends in the literal part
+        let start = expanded.text.find("spam/eggs").unwrap();
+        let range = expanded.to_original(start..start + "spam/eggs".len());
+        assert_eq!(&original[range], "$HOME/eggs");
+        // Entirely inside the literal part
+        let start = expanded.text.find("eggs").unwrap();
+        assert_eq!(&original[expanded.to_original(start..start + 4)], "eggs");
+        // Entirely inside the variable
+        assert_eq!(&original[expanded.to_original(4..8)], "$HOME");
+        assert_eq!(&original[expanded.to_original(0..2)], "cd");
+    }
+}
@@ -0,0 +1,153 @@
+use std::io::BufRead;
+
+/// Reads history entries, each ending with a separator byte (except possibly the last one).
+pub struct HistoryIter<R: BufRead> {
+    reader: R,
+    /// The byte entries end with
+    separator: u8,
+    /// Whether an entry ending with a backslash continues after the separator
+    continuations: bool,
+}
+
+impl<R: BufRead> HistoryIter<R> {
+    pub fn from_reader(reader: R, separator: u8) -> Self {
+        Self {
+            reader,
+            separator,
+            continuations: false,
+        }
+    }
+
+    /// Joins entries ending with a backslash with the following entry, as zsh escapes newlines
+    /// in multiline commands.
+    pub fn with_continuations(mut self) -> Self {
+        self.continuations = true;
+        self
+    }
+}
+
+impl<R: BufRead> Iterator for HistoryIter<R> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let mut buf = Vec::with_capacity(1024);
+            let mut bytes = self.reader.read_until(self.separator, &mut buf).ok()?;
+            if bytes == 0 {
+                return None;
+            }
+            while self.continuations && buf.ends_with(&[b'\\', self.separator]) {
+                // Keep the separator, but not the backslash
+                buf.remove(buf.len() - 2);
+                if self.reader.read_until(self.separator, &mut buf).ok()? == 0 {
+                    break;
+                }
+            }
+            bytes = buf.len();
+            // Omit trailing separator if present
+            if buf[bytes - 1] == self.separator {
+                bytes -= 1;
+            }
+            let Some(entry) = std::str::from_utf8(&buf[..bytes])
+                .ok()
+                .map(ToOwned::to_owned)
+            else {
+                // Skip undecodable entries, rather than returning a likely wrong entry
+                continue;
+            };
+            return Some(entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HistoryIter;
+
+    fn collect_history(input: &[u8]) -> Vec<String> {
+        let reader = std::io::Cursor::new(input);
+        HistoryIter::from_reader(reader, b'\0').collect()
+    }
+
+    fn collect_lines(input: &[u8]) -> Vec<String> {
+        let reader = std::io::Cursor::new(input);
+        HistoryIter::from_reader(reader, b'\n').collect()
+    }
+
+    #[test]
+    fn test_history_iter() {
+        let lines = collect_history(b"entry1\0entry2\0entry 3\nstill entry 3\0");
+        assert_eq!(
+            lines,
+            vec![
+                "entry1".to_string(),
+                "entry2".to_string(),
+                "entry 3\nstill entry 3".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_history_iter_missing_traling_null() {
+        let lines = collect_history(b"first entry");
+        assert_eq!(lines, vec!["first entry".to_string()]);
+    }
+
+    #[test]
+    fn test_history_iter_lines() {
+        let lines = collect_lines(b"entry1\nentry 2\nentry 3\0\n");
+        assert_eq!(
+            lines,
+            vec![
+                "entry1".to_string(),
+                "entry 2".to_string(),
+                "entry 3\0".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_history_iter_lines_missing_trailing_newline() {
+        let lines = collect_lines(b"first entry\nsecond entry");
+        assert_eq!(
+            lines,
+            vec!["first entry".to_string(), "second entry".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_history_iter_continuations() {
+        let reader = std::io::Cursor::new(b"for i in 1 2\\\ndo echo $i\\\ndone\nls\\\n");
+        let lines: Vec<_> = HistoryIter::from_reader(reader, b'\n')
+            .with_continuations()
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "for i in 1 2\ndo echo $i\ndone".to_string(),
+                "ls".to_string()
+            ]
+        );
+        // Only with continuations
+        assert_eq!(collect_lines(b"ls \\\n-l").len(), 2);
+    }
+
+    #[test]
+    fn test_history_iter_max_entries() {
+        let reader = std::io::Cursor::new(b"ls\\\n-l\nmake\ncd\n");
+        let lines: Vec<_> = HistoryIter::from_reader(reader, b'\n')
+            .with_continuations()
+            .take(2)
+            .collect();
+        assert_eq!(lines, vec!["ls\n-l".to_string(), "make".to_string()]);
+        let reader = std::io::Cursor::new(b"ls\nmake\n");
+        let lines: Vec<_> = HistoryIter::from_reader(reader, b'\n').take(5).collect();
+        assert_eq!(lines, vec!["ls".to_string(), "make".to_string()]);
+    }
+
+    #[test]
+    fn test_history_iter_invalid_utf_8() {
+        let lines = collect_history(b"first en\xc3try\0second entry\0");
+        assert_eq!(lines, vec!["second entry".to_string()]);
+    }
+}
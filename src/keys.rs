@@ -0,0 +1,288 @@
+use std::path::PathBuf;
+
+use termion::event::Key;
+
+/// What a key does.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Quit,
+    Accept,
+    AcceptBackground,
+    AcceptEdit,
+    /// Accepts with the cursor where the key moves it, see `adjust_cursor`
+    AcceptCursor,
+    NextMatch,
+    PrevMatch,
+    NextOccurrence,
+    BackwardWord,
+    ForwardWord,
+    DeleteChar,
+    DeleteWord,
+    DeleteWhitespaceWord,
+    Clear,
+    ContextBefore,
+    ContextAfter,
+    Copy,
+    CopyDirectory,
+    DeleteEntry,
+    ToggleFuzzy,
+    TogglePrefix,
+    CycleCase,
+    TogglePreview,
+}
+
+/// Action names as used in key binding files.
+const ACTIONS: &[(&str, Action)] = &[
+    ("quit", Action::Quit),
+    ("accept", Action::Accept),
+    ("accept_background", Action::AcceptBackground),
+    ("accept_edit", Action::AcceptEdit),
+    ("accept_cursor", Action::AcceptCursor),
+    ("next_match", Action::NextMatch),
+    ("prev_match", Action::PrevMatch),
+    ("next_occurrence", Action::NextOccurrence),
+    ("backward_word", Action::BackwardWord),
+    ("forward_word", Action::ForwardWord),
+    ("delete_char", Action::DeleteChar),
+    ("delete_word", Action::DeleteWord),
+    ("delete_whitespace_word", Action::DeleteWhitespaceWord),
+    ("clear", Action::Clear),
+    ("context_before", Action::ContextBefore),
+    ("context_after", Action::ContextAfter),
+    ("copy", Action::Copy),
+    ("copy_directory", Action::CopyDirectory),
+    ("delete_entry", Action::DeleteEntry),
+    ("toggle_fuzzy", Action::ToggleFuzzy),
+    ("toggle_prefix", Action::TogglePrefix),
+    ("cycle_case", Action::CycleCase),
+    ("toggle_preview", Action::TogglePreview),
+];
+
+const DEFAULT_BINDINGS: &[(Key, Action)] = &[
+    (Key::Esc, Action::Quit),
+    (Key::Ctrl('c'), Action::Quit),
+    (Key::Ctrl('g'), Action::Quit),
+    (Key::Char('\n'), Action::Accept),
+    // Like Ctrl-Z in a shell, to run it in the background
+    (Key::Ctrl('z'), Action::AcceptBackground),
+    // Terminals send Alt-Enter as Esc followed by CR, unlike Enter not translated to LF by termion
+    (Key::Alt('\r'), Action::AcceptEdit),
+    (Key::Left, Action::AcceptCursor),
+    (Key::Right, Action::AcceptCursor),
+    (Key::Home, Action::AcceptCursor),
+    (Key::End, Action::AcceptCursor),
+    (Key::Ctrl('r'), Action::NextMatch),
+    (Key::Down, Action::NextMatch),
+    (Key::Char('\t'), Action::NextMatch),
+    (Key::Ctrl('s'), Action::PrevMatch),
+    (Key::Up, Action::PrevMatch),
+    (Key::BackTab, Action::PrevMatch),
+    (Key::Ctrl('o'), Action::NextOccurrence),
+    (Key::Alt('b'), Action::BackwardWord),
+    (Key::Alt('f'), Action::ForwardWord),
+    (Key::Backspace, Action::DeleteChar),
+    // Terminals send Alt-Backspace as Esc followed by DEL (or BS), which termion reports as Alt
+    // key. Note that some terminals (e.g. macOS's Terminal.app or iTerm2) only do so if the
+    // Option key is configured to act as Meta/Esc+.
+    (Key::Alt('\x7f'), Action::DeleteWord),
+    (Key::Alt('\x08'), Action::DeleteWord),
+    (Key::Ctrl('w'), Action::DeleteWhitespaceWord),
+    (Key::Ctrl('u'), Action::Clear),
+    (Key::Alt('p'), Action::ContextBefore),
+    (Key::Alt('n'), Action::ContextAfter),
+    (Key::Ctrl('y'), Action::Copy),
+    (Key::Alt('c'), Action::CopyDirectory),
+    (Key::Ctrl('d'), Action::DeleteEntry),
+    (Key::Ctrl('f'), Action::ToggleFuzzy),
+    (Key::Alt('a'), Action::TogglePrefix),
+    (Key::Ctrl('t'), Action::CycleCase),
+    (Key::Ctrl('p'), Action::TogglePreview),
+];
+
+/// Which key does what. Keys without a binding insert their character, or move the cursor.
+#[derive(Debug, PartialEq)]
+pub struct Bindings(Vec<(Key, Action)>);
+
+impl Default for Bindings {
+    fn default() -> Self {
+        Self(DEFAULT_BINDINGS.to_vec())
+    }
+}
+
+impl Bindings {
+    /// Parses lines like `next_match = ctrl-n, down` on top of the default bindings, see
+    /// `apply`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut bindings = Self::default();
+        bindings.apply(text)?;
+        Ok(bindings)
+    }
+
+    /// Applies lines like `next_match = ctrl-n, down` on top of these bindings. Each listed
+    /// action gets the listed keys instead of its current ones (none if the list is empty), and
+    /// the keys lose whatever action they had before. Empty lines and lines starting with `#`
+    /// are skipped.
+    pub fn apply(&mut self, text: &str) -> Result<(), String> {
+        for (number, line) in text
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line.trim()))
+        {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((name, keys)) = line.split_once('=') else {
+                return Err(format!("Expected ACTION = KEYS on line {number}: {line}"));
+            };
+            let Some(&(_, action)) = ACTIONS.iter().find(|(known, _)| *known == name.trim()) else {
+                return Err(format!("Unknown action on line {number}: {}", name.trim()));
+            };
+            let keys = keys
+                .split(',')
+                .map(str::trim)
+                .filter(|key| !key.is_empty())
+                .map(|key| parse_key(key).ok_or(format!("Unknown key on line {number}: {key}")))
+                .collect::<Result<Vec<_>, _>>()?;
+            self.0
+                .retain(|(key, bound)| *bound != action && !keys.contains(key));
+            self.0.extend(keys.into_iter().map(|key| (key, action)));
+        }
+        Ok(())
+    }
+
+    /// Returns what `key` does, if it's bound.
+    pub fn action(&self, key: Key) -> Option<Action> {
+        self.0
+            .iter()
+            .find(|(bound, _)| *bound == key)
+            .map(|&(_, action)| action)
+    }
+}
+
+/// Parses key names like `ctrl-r`, `alt-b`, `esc`, `enter`, `up` or a single character.
+fn parse_key(name: &str) -> Option<Key> {
+    let single = |text: &str| {
+        let mut chars = text.chars();
+        chars.next().filter(|_| chars.next().is_none())
+    };
+    let key = match name.to_lowercase().as_str() {
+        "esc" => Key::Esc,
+        "enter" => Key::Char('\n'),
+        "tab" => Key::Char('\t'),
+        "shift-tab" => Key::BackTab,
+        "space" => Key::Char(' '),
+        "backspace" => Key::Backspace,
+        "alt-backspace" => Key::Alt('\x7f'),
+        "alt-enter" => Key::Alt('\r'),
+        "delete" => Key::Delete,
+        "up" => Key::Up,
+        "down" => Key::Down,
+        "left" => Key::Left,
+        "right" => Key::Right,
+        "home" => Key::Home,
+        "end" => Key::End,
+        lowercase => {
+            if let Some(ch) = lowercase.strip_prefix("ctrl-").and_then(single) {
+                Key::Ctrl(ch)
+            } else if let Some(ch) = name.get(4..).filter(|_| lowercase.starts_with("alt-")) {
+                // Alt keys are case-sensitive, e.g. Alt-B is Alt-Shift-b
+                Key::Alt(single(ch)?)
+            } else {
+                Key::Char(single(name)?)
+            }
+        }
+    };
+    Some(key)
+}
+
+/// Where key bindings are read from unless `--keys` is given.
+pub fn default_path() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_home.join("kontrolleurs").join("keys"))
+}
+
+#[cfg(test)]
+mod tests {
+    use termion::event::Key;
+
+    use super::{parse_key, Action, Bindings};
+
+    #[test]
+    fn test_defaults() {
+        let bindings = Bindings::default();
+        assert_eq!(bindings.action(Key::Ctrl('r')), Some(Action::NextMatch));
+        assert_eq!(bindings.action(Key::Esc), Some(Action::Quit));
+        assert_eq!(bindings.action(Key::Char('a')), None);
+        assert_eq!(bindings.action(Key::Left), Some(Action::AcceptCursor));
+        assert_eq!(bindings.action(Key::Delete), None);
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut bindings = Bindings::default();
+        bindings.apply("accept_cursor =").unwrap();
+        bindings
+            .apply("accept_cursor = end\ncopy = ctrl-k")
+            .unwrap();
+        assert_eq!(bindings.action(Key::End), Some(Action::AcceptCursor));
+        assert_eq!(bindings.action(Key::Ctrl('k')), Some(Action::Copy));
+        assert_eq!(bindings.action(Key::Left), None);
+        assert!(bindings.apply("accept_cursor = spam").is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        let bindings = Bindings::parse(
+            "# Like emacs\n\
+             next_match = ctrl-n, ctrl-r\n\
+             \n\
+             prev_match = ctrl-p\n\
+             copy =\n",
+        )
+        .unwrap();
+        assert_eq!(bindings.action(Key::Ctrl('n')), Some(Action::NextMatch));
+        assert_eq!(bindings.action(Key::Ctrl('r')), Some(Action::NextMatch));
+        // Replaced
+        assert_eq!(bindings.action(Key::Down), None);
+        assert_eq!(bindings.action(Key::Up), None);
+        // Taken from toggle_preview, which isn't bound anymore
+        assert_eq!(bindings.action(Key::Ctrl('p')), Some(Action::PrevMatch));
+        assert_eq!(bindings.action(Key::Ctrl('y')), None);
+        // Untouched
+        assert_eq!(bindings.action(Key::Ctrl('g')), Some(Action::Quit));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            Bindings::parse("next_match ctrl-n"),
+            Err("Expected ACTION = KEYS on line 1: next_match ctrl-n".to_string())
+        );
+        assert_eq!(
+            Bindings::parse("\nnext = ctrl-n"),
+            Err("Unknown action on line 2: next".to_string())
+        );
+        assert_eq!(
+            Bindings::parse("quit = ctrl-nn"),
+            Err("Unknown key on line 1: ctrl-nn".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(parse_key("ctrl-r"), Some(Key::Ctrl('r')));
+        assert_eq!(parse_key("Ctrl-R"), Some(Key::Ctrl('r')));
+        assert_eq!(parse_key("alt-B"), Some(Key::Alt('B')));
+        assert_eq!(parse_key("enter"), Some(Key::Char('\n')));
+        assert_eq!(parse_key("Esc"), Some(Key::Esc));
+        assert_eq!(parse_key("shift-tab"), Some(Key::BackTab));
+        assert_eq!(parse_key("alt-backspace"), Some(Key::Alt('\x7f')));
+        assert_eq!(parse_key("Alt-Enter"), Some(Key::Alt('\r')));
+        assert_eq!(parse_key("ü"), Some(Key::Char('ü')));
+        assert_eq!(parse_key("ctrl-"), None);
+        assert_eq!(parse_key("hyper-x"), None);
+    }
+}
@@ -0,0 +1,13 @@
+//! Incremental search through shell history, as done by the kontrolleurs binary. History read
+//! with `HistoryIter` is matched against a query with `search`.
+
+pub mod config;
+pub mod entry;
+pub mod expand;
+pub mod history;
+pub mod keys;
+pub mod score;
+pub mod search;
+
+pub use history::HistoryIter;
+pub use search::{search, Match, MatchOptions, Matcher};
@@ -1,59 +1,185 @@
 use std::{
+    borrow::Cow,
+    cmp::Reverse,
+    collections::{HashMap, HashSet, VecDeque},
     fs::File,
-    io::{BufRead, BufReader, Write},
-    os::fd::AsFd,
+    io::{BufRead, BufReader, ErrorKind, IsTerminal, Write},
+    iter::Peekable,
+    ops::Range,
+    path::{Path, PathBuf},
     process::ExitCode,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
+    sync::mpsc::RecvTimeoutError,
+    time::{Duration, Instant},
 };
 
-use regex::Regex;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
 use termion::{
-    event::Key,
-    input::TermRead,
-    raw::{IntoRawMode, RawTerminal},
+    event::{Event, Key, MouseButton, MouseEvent},
+    raw::IntoRawMode,
 };
-use termwiz::cell::unicode_column_width;
+use termwiz::cell::{unicode_column_width, UnicodeVersion, LATEST_UNICODE_VERSION};
 
-use terminal_size::terminal_size;
+use kontrolleurs::{
+    config::{
+        self, CaseMode, Config, CycleOrder, Highlight, HistorySort, InputFormat, MatchMode,
+        OutputFormat, PromptWrap, DEFAULT_BACKGROUND_SUFFIX, DEFAULT_CONTINUATION_MARKER,
+        DEFAULT_MAX_SCAN, DEFAULT_MIN_HIGHLIGHT_LENGTH, DEFAULT_PROMPT, HIGHLIGHT_VAR, PROMPT_VAR,
+    },
+    entry::Entry,
+    expand::Expanded,
+    keys::{self, Action},
+    score, HistoryIter, MatchOptions, Matcher,
+};
+use output::Execute;
+use terminal_size::TerminalSize;
 
+mod clipboard;
+mod metrics;
+mod output;
+mod state;
 mod terminal_size;
+mod tty;
+
+/// Parses a history record read by `HistoryIter`.
+fn parse_record(record: String, input_format: InputFormat, metadata: bool) -> Entry {
+    if input_format == InputFormat::Zsh {
+        Entry::from_zsh(record)
+    } else if metadata {
+        Entry::with_metadata(record)
+    } else {
+        Entry::from(record)
+    }
+}
+
+/// Returns `history` without the records of `deleted` commands. All other records are kept
+/// byte for byte, including undecodable ones.
+fn remove_records(
+    history: &[u8],
+    input_format: InputFormat,
+    metadata: bool,
+    deleted: &HashSet<String>,
+) -> Vec<u8> {
+    let separator = input_format.separator();
+    let mut kept = Vec::with_capacity(history.len());
+    let mut rest = history;
+    while !rest.is_empty() {
+        // Find the end of the record, including continuation lines
+        let mut end = 0;
+        loop {
+            end += rest[end..]
+                .iter()
+                .position(|&byte| byte == separator)
+                .map_or(rest.len() - end, |i| i + 1);
+            let continued =
+                input_format == InputFormat::Zsh && rest[..end].ends_with(&[b'\\', separator]);
+            if !continued || end == rest.len() {
+                break;
+            }
+        }
+        let (record, remainder) = rest.split_at(end);
+        let parsed = HistoryIter::from_reader(record, separator);
+        let mut parsed = if input_format == InputFormat::Zsh {
+            parsed.with_continuations()
+        } else {
+            parsed
+        };
+        let is_deleted = parsed.next().is_some_and(|record| {
+            deleted.contains(&parse_record(record, input_format, metadata).command)
+        });
+        if !is_deleted {
+            kept.extend_from_slice(record);
+        }
+        rest = remainder;
+    }
+    kept
+}
 
-struct HistoryIter<R: BufRead> {
-    reader: R,
+/// Removes the records of `deleted` commands from the history file at `path`. The file is
+/// replaced at once, so it's never left half written.
+fn delete_from_history(
+    path: &Path,
+    input_format: InputFormat,
+    metadata: bool,
+    deleted: &HashSet<String>,
+) -> std::io::Result<()> {
+    let history = std::fs::read(path)?;
+    let kept = remove_records(&history, input_format, metadata, deleted);
+    if kept.len() == history.len() {
+        return Ok(());
+    }
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".kontrolleurs");
+    let temporary = PathBuf::from(temporary);
+    std::fs::write(&temporary, kept)?;
+    let result = std::fs::metadata(path)
+        .and_then(|metadata| std::fs::set_permissions(&temporary, metadata.permissions()))
+        .and_then(|()| std::fs::rename(&temporary, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temporary);
+    }
+    result
+}
+
+/// Folds consecutive entries with the same command into the first of them, if enabled.
+struct FoldRepeats<I: Iterator<Item = Entry>> {
+    inner: Peekable<I>,
+    enabled: bool,
 }
 
-impl<R: BufRead> HistoryIter<R> {
-    pub fn from_reader(reader: R) -> Self {
-        Self { reader }
+impl<I: Iterator<Item = Entry>> FoldRepeats<I> {
+    pub fn new(inner: I, enabled: bool) -> Self {
+        Self {
+            inner: inner.peekable(),
+            enabled,
+        }
     }
 }
 
-impl<R: BufRead> Iterator for HistoryIter<R> {
-    type Item = String;
+impl<I: Iterator<Item = Entry>> Iterator for FoldRepeats<I> {
+    type Item = Entry;
 
     fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            let mut buf = Vec::with_capacity(1024);
-            let mut bytes = self.reader.read_until(b'\0', &mut buf).ok()?;
-            if bytes == 0 {
-                return None;
-            }
-            // Omit trailing null byte if present
-            if buf[bytes - 1] == b'\0' {
-                bytes -= 1;
-            }
-            let Some(entry) = std::str::from_utf8(&buf[..bytes])
-                .ok()
-                .map(ToOwned::to_owned)
-            else {
-                // Skip undecodable entries, rather than returning a likely wrong entry
-                continue;
-            };
-            return Some(entry);
+        let mut entry = self.inner.next()?;
+        while self.enabled
+            && self
+                .inner
+                .next_if(|next| next.command == entry.command)
+                .is_some()
+        {
+            entry.repeats += 1;
+        }
+        Some(entry)
+    }
+}
+
+/// Skips entries whose command was returned before, if enabled. As the history is read most
+/// recent first, the most recent occurrence of each command is kept.
+struct Dedup<I: Iterator<Item = Entry>> {
+    inner: I,
+    seen: HashSet<String>,
+    enabled: bool,
+}
+
+impl<I: Iterator<Item = Entry>> Dedup<I> {
+    pub fn new(inner: I, enabled: bool) -> Self {
+        Self {
+            inner,
+            seen: HashSet::new(),
+            enabled,
+        }
+    }
+}
+
+impl<I: Iterator<Item = Entry>> Iterator for Dedup<I> {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.enabled {
+            return self.inner.next();
         }
+        self.inner
+            .by_ref()
+            .find(|entry| self.seen.insert(entry.command.clone()))
     }
 }
 
@@ -61,6 +187,8 @@ impl<R: BufRead> Iterator for HistoryIter<R> {
 struct ReusableIter<I: Iterator, T> {
     consumed_iter: <Vec<T> as IntoIterator>::IntoIter,
     inner: I,
+    /// Items added with `push`, returned once `inner` is exhausted
+    pushed: VecDeque<T>,
     elements: Vec<T>,
 }
 
@@ -69,23 +197,80 @@ impl<I: Iterator<Item = T>, T> ReusableIter<I, T> {
         Self {
             consumed_iter: Vec::new().into_iter(),
             inner,
+            pushed: VecDeque::new(),
             elements: Vec::new(),
         }
     }
 
+    /// Appends `items` after the items of the wrapped iterator.
+    pub fn push(&mut self, items: impl IntoIterator<Item = T>) {
+        self.pushed.extend(items);
+    }
+
+    /// Returns the next item that wasn't read before.
+    fn next_new(&mut self) -> Option<T> {
+        self.inner.next().or_else(|| self.pushed.pop_front())
+    }
+
     pub fn reset(&mut self) {
         self.elements
             .extend(std::mem::take(&mut self.consumed_iter));
         let elements = std::mem::take(&mut self.elements);
         self.consumed_iter = elements.into_iter();
     }
+
+    /// Starts from the beginning again, but skips the first `position` items.
+    pub fn reset_to(&mut self, position: usize)
+    where
+        T: Clone,
+    {
+        self.reset();
+        self.by_ref().take(position).for_each(drop);
+    }
+
+    /// Reads items from the wrapped iterator until there are `count` items (fewer at its end),
+    /// without advancing. Returns how many items there are.
+    pub fn read_to(&mut self, count: usize) -> usize {
+        let available = self.elements.len() + self.consumed_iter.len();
+        if available < count {
+            let mut remaining: Vec<_> = std::mem::take(&mut self.consumed_iter).collect();
+            remaining.extend(std::iter::from_fn(|| self.next_new()).take(count - available));
+            self.consumed_iter = remaining.into_iter();
+        }
+        self.elements.len() + self.consumed_iter.len()
+    }
+
+    /// Returns how many items were returned since the last reset.
+    pub fn position(&self) -> usize {
+        self.elements.len()
+    }
+}
+
+impl<I: Iterator<Item = T>, T: Clone> ReusableIter<I, T> {
+    /// Returns the item at `index`, counted from the beginning, without advancing.
+    pub fn get(&mut self, index: usize) -> Option<T> {
+        while self.elements.len() + self.consumed_iter.len() <= index {
+            let next = self.next_new()?;
+            let mut remaining: Vec<_> = std::mem::take(&mut self.consumed_iter).collect();
+            remaining.push(next);
+            self.consumed_iter = remaining.into_iter();
+        }
+        self.elements
+            .get(index)
+            .or_else(|| {
+                self.consumed_iter
+                    .as_slice()
+                    .get(index - self.elements.len())
+            })
+            .cloned()
+    }
 }
 
 impl<I: Iterator<Item = T>, T: Clone> Iterator for ReusableIter<I, T> {
     type Item = I::Item;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(next) = self.consumed_iter.next().or_else(|| self.inner.next()) {
+        if let Some(next) = self.consumed_iter.next().or_else(|| self.next_new()) {
             self.elements.push(next.clone());
             Some(next)
         } else {
@@ -94,16 +279,125 @@ impl<I: Iterator<Item = T>, T: Clone> Iterator for ReusableIter<I, T> {
     }
 }
 
-fn adjust_cursor(pos: usize, key: Key) -> usize {
+/// Deletes the word before `cursor` in `input`, including any non-word characters between the
+/// word and the cursor. Returns the new cursor position.
+fn delete_word(input: &mut String, cursor: usize) -> usize {
+    let start = previous_word_start(input, cursor);
+    input.replace_range(start..cursor, "");
+    start
+}
+
+/// Like `delete_word`, but words are separated by whitespace only, as with Ctrl-W in a shell.
+fn delete_whitespace_word(input: &mut String, cursor: usize) -> usize {
+    let end = input[..cursor].trim_end().len();
+    let start = input[..end]
+        .trim_end_matches(|ch: char| !ch.is_whitespace())
+        .len();
+    input.replace_range(start..cursor, "");
+    start
+}
+
+/// Returns where the word before `pos` starts, skipping any non-word characters in between.
+fn previous_word_start(input: &str, pos: usize) -> usize {
+    let end = input[..pos]
+        .trim_end_matches(|ch: char| !ch.is_alphanumeric())
+        .len();
+    input[..end].trim_end_matches(char::is_alphanumeric).len()
+}
+
+/// Returns where the word after `pos` ends, skipping any non-word characters in between.
+fn next_word_end(input: &str, pos: usize) -> usize {
+    let rest = &input[pos..];
+    let word = rest.trim_start_matches(|ch: char| !ch.is_alphanumeric());
+    let after = word.trim_start_matches(char::is_alphanumeric);
+    input.len() - after.len()
+}
+
+/// Returns the arguments of `command` if its first word is `name`.
+fn command_args<'a>(command: &'a str, name: &str) -> Option<&'a str> {
+    let command = command.trim_start();
+    let end = command.find(char::is_whitespace).unwrap_or(command.len());
+    (&command[..end] == name).then(|| command[end..].trim_start())
+}
+
+/// Returns the command name `command` runs, i.e. its first word after any leading `NAME=value`
+/// assignments (which only set environment variables for the command). Empty if there's none.
+/// Words are simply split on whitespace, quoting isn't taken into account.
+fn command_name(command: &str) -> &str {
+    &command[command_name_range(command)]
+}
+
+/// Returns the byte range of `command_name` in `command`.
+fn command_name_range(command: &str) -> Range<usize> {
+    let is_assignment = |word: &str| {
+        word.split_once('=').is_some_and(|(name, _)| {
+            name.starts_with(|ch: char| ch.is_ascii_alphabetic() || ch == '_')
+                && name
+                    .chars()
+                    .all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+        })
+    };
+    let mut rest = command;
+    loop {
+        rest = rest.trim_start();
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let (word, remainder) = rest.split_at(end);
+        if !is_assignment(word) {
+            // `rest` is what's left at the end of `command`
+            let start = command.len() - rest.len();
+            return start..start + word.len();
+        }
+        rest = remainder;
+    }
+}
+
+/// Sorts (score, position, entry) triples by score, then most recent first. Entries without
+/// timestamp stay in their original order after the others with the same score.
+fn sort_matches(mut matches: Vec<(f64, usize, Entry)>) -> Vec<(usize, Entry)> {
+    matches.sort_by(|(a_score, _, a), (b_score, _, b)| {
+        b_score
+            .total_cmp(a_score)
+            .then_with(|| Reverse(a.timestamp).cmp(&Reverse(b.timestamp)))
+    });
+    matches
+        .into_iter()
+        .map(|(_, position, entry)| (position, entry))
+        .collect()
+}
+
+/// Returns `entries` oldest first, without the `skip_newest` most recent ones. Unlike the
+/// history itself, this needs all entries in memory.
+fn oldest_first(entries: impl Iterator<Item = Entry>, skip_newest: usize) -> Vec<Entry> {
+    let mut entries: Vec<_> = entries.skip(skip_newest).collect();
+    entries.reverse();
+    entries
+}
+
+/// Returns each command of `entries` once, the most frequent first. Commands run equally often
+/// keep their order, and the first entry of each command represents it.
+fn sort_by_frequency(entries: impl Iterator<Item = Entry>) -> Vec<Entry> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    let mut unique = Vec::new();
+    for entry in entries {
+        let count = counts.entry(entry.command.clone()).or_default();
+        if *count == 0 {
+            unique.push(entry.clone());
+        }
+        // Folded repeats were run, too
+        *count += 1 + entry.repeats;
+    }
+    unique.sort_by_key(|entry| Reverse(counts[&entry.command]));
+    unique
+}
+
+/// Moves the cursor at `pos` for the key an entry with `len` characters was accepted with. Home
+/// and End go to the start and end of the whole entry, even if it has several lines.
+fn adjust_cursor(pos: usize, len: usize, key: Key) -> usize {
     match key {
         Key::Left if pos > 0 => pos - 1,
-        Key::Right => pos + 1,
+        Key::Right => (pos + 1).min(len),
         Key::Home => 0,
-        Key::End =>
-        // Really just a large number and fish then places at the end
-        {
-            65536
-        }
+        Key::End => len,
         _ => pos,
     }
 }
@@ -111,250 +405,4677 @@ fn adjust_cursor(pos: usize, key: Key) -> usize {
 #[derive(Debug, PartialEq)]
 enum PromptResult {
     Incomplete,
-    Selected(String, bool, usize),
+    /// The selected entry, whether to run it and the cursor position in characters
+    Selected(String, Execute, usize),
+    /// Something was copied to the clipboard instead of selecting an entry
+    Copied,
     Quit,
+    /// Accepted, but there was no match to select
+    NoMatch,
+}
+
+impl PromptResult {
+    /// The exit status for a search that ended with this result.
+    fn exit_code(&self) -> u8 {
+        match self {
+            Self::Incomplete | Self::Selected(..) | Self::Copied => 0,
+            Self::Quit => 2,
+            Self::NoMatch => 3,
+        }
+    }
 }
 
-struct Prompt<I: Iterator<Item = String>, W: Write + AsFd> {
+/// Matches with their positions in the history, best first
+type SortedMatches = ReusableIter<std::vec::IntoIter<(usize, Entry)>, (usize, Entry)>;
+
+struct Prompt<I: Iterator<Item = Entry>, W: Write + TerminalSize> {
     input: String,
-    history: ReusableIter<I, String>,
-    stdout: RawTerminal<W>,
+    /// Position in `input` (in bytes) where characters are inserted and deleted
+    cursor: usize,
+    history: ReusableIter<I, Entry>,
+    stdout: W,
     /// (columns, rows)
     terminal_size: (u16, u16),
     current_input_height: usize,
-    current_entry: Option<String>,
+    /// How many rows of the prompt are below the terminal's cursor
+    rows_below_cursor: usize,
+    current_entry: Option<Entry>,
+    /// Position of the current entry in the history
+    current_position: usize,
+    /// Which of the query's occurrences in the current entry is the current one
+    occurrence: usize,
+    /// An entry next to the current entry (and its position), shown regardless of the query
+    context: Option<(usize, Entry)>,
+    /// Positions of the matches shown before the current one (and whether they were found in the
+    /// preferred pass), to step back to
+    earlier_matches: Vec<(usize, bool)>,
+    /// Number of all matches, once known
+    match_count: Option<usize>,
+    /// How many matches the previous query had at least
+    previous_count: usize,
+    /// Counts matches ahead of the search with `Config::count_matches`
+    counter: Option<MatchCounter>,
+    match_mode: MatchMode,
+    case_mode: CaseMode,
+    /// Whether only matches at the start of entries (or lines) count, see `Config::prefix`
+    prefix: bool,
+    /// Whether only preferred matches (see `score::PREFERRED`) are searched for
+    preferred_pass: bool,
+    /// All remaining matches, only used with `CycleOrder::Recent`
+    sorted_matches: Option<SortedMatches>,
+    /// Whether `sorted_matches` is incomplete because the scan limit was hit
+    partial: bool,
+    /// Whether all matches are being collected right now
+    indexing: bool,
+    /// Whether the current entry is shown below the prompt
+    show_preview: bool,
+    /// Text pasted so far, while the terminal is in the middle of a bracketed paste
+    pasted: Option<String>,
+    /// Whether searching is deferred until the end of a batch of input
+    batching: bool,
+    /// Whether the query changed during the current batch
+    search_pending: bool,
+    /// The query prepared for matching
+    matcher: Matcher,
+    /// The byte ranges of the shown entry's command to highlight, found along with the entry
+    spans: Vec<Range<usize>>,
+    /// Text in front of the query
+    prompt_prefix: String,
+    /// A message shown in front of the prompt until the next key press
+    status: Option<&'static str>,
+    /// Commands deleted with Ctrl-D, which never match again
+    deleted: HashSet<String>,
+    /// Index of the first match shown with `Config::list`
+    list_start: usize,
+    /// How many rows each of the matches shown with `Config::list` takes
+    list_heights: Vec<usize>,
+    /// The terminal row (counting from 1) of the prompt's first row if known, to find which
+    /// match is clicked
+    prompt_row: Option<usize>,
+    /// The byte range of the occurrence the cursor was put after when accepting, in the
+    /// original entry
+    selected_match: Option<Range<usize>>,
+    config: Config,
 }
 
-impl<I: Iterator<Item = String>, W: Write + AsFd> Prompt<I, W> {
-    pub fn new(stdout: RawTerminal<W>, history: I) -> std::io::Result<Self> {
-        let terminal_size = terminal_size(&stdout.as_fd())?;
-        Ok(Self {
+impl<I: Iterator<Item = Entry>, W: Write + TerminalSize> Prompt<I, W> {
+    pub fn new(stdout: W, history: I, config: Config) -> std::io::Result<Self> {
+        let terminal_size = stdout.terminal_size()?;
+        let mut prompt = Self {
             input: String::new(),
+            cursor: 0,
             history: ReusableIter::new(history),
             stdout,
             terminal_size,
             current_input_height: 0,
+            rows_below_cursor: 0,
             current_entry: None,
-        })
+            current_position: 0,
+            occurrence: 0,
+            context: None,
+            earlier_matches: Vec::new(),
+            match_count: None,
+            previous_count: 0,
+            counter: None,
+            match_mode: config.match_mode,
+            case_mode: config.case,
+            prefix: config.prefix,
+            preferred_pass: true,
+            sorted_matches: None,
+            partial: false,
+            indexing: false,
+            show_preview: true,
+            pasted: None,
+            batching: false,
+            search_pending: false,
+            // Replaced right away, once everything it's derived from is in place
+            matcher: Matcher::new("", MatchOptions::default()),
+            spans: Vec::new(),
+            prompt_prefix: config
+                .prompt
+                .clone()
+                .unwrap_or_else(|| DEFAULT_PROMPT.to_string()),
+            status: None,
+            deleted: HashSet::new(),
+            list_start: 0,
+            list_heights: Vec::new(),
+            prompt_row: None,
+            selected_match: None,
+            config,
+        };
+        prompt.matcher = prompt.compile_query();
+        Ok(prompt)
+    }
+
+    /// Handles input from the terminal. Text pasted with bracketed paste is inserted into the
+    /// query all at once, instead of searching again for each character.
+    pub fn handle_event(&mut self, event: Event) -> PromptResult {
+        match event {
+            Event::Unsupported(bytes) if bytes == tty::PASTE_START => {
+                self.pasted = Some(String::new());
+            }
+            Event::Unsupported(bytes) if bytes == tty::PASTE_END => {
+                if let Some(text) = self.pasted.take() {
+                    self.paste(&text);
+                }
+            }
+            Event::Mouse(MouseEvent::Press(MouseButton::Left, _, row)) if self.pasted.is_none() => {
+                if let Some(index) = self.clicked_match(row) {
+                    return self.accept_listed(index);
+                }
+            }
+            Event::Key(key) => match &mut self.pasted {
+                Some(pasted) => {
+                    if let Key::Char(ch) = key {
+                        pasted.push(ch);
+                    }
+                }
+                None => return self.handle_key_press(key),
+            },
+            _ => (),
+        }
+        PromptResult::Incomplete
+    }
+
+    /// Defers searching after changes to the query until `finish_batch`, so a burst of input
+    /// only searches (and redraws) once.
+    pub fn start_batch(&mut self) {
+        self.batching = true;
+    }
+
+    /// Searches for the query as changed during the batch, if it changed.
+    pub fn finish_batch(&mut self) {
+        self.batching = false;
+        if self.search_pending {
+            self.search_pending = false;
+            self.search_from_start();
+        }
     }
 
     pub fn handle_key_press(&mut self, key: Key) -> PromptResult {
-        match key {
-            Key::Esc | Key::Ctrl('c' | 'g') => {
+        self.status = None;
+        let action = self.config.bindings.action(key);
+        // Everything but editing the query needs the matches for the query as it is now
+        if !edits_query(key, action) && self.batching {
+            self.finish_batch();
+            self.batching = true;
+        }
+        let Some(action) = action else {
+            return self.handle_unbound_key(key);
+        };
+        match action {
+            Action::Quit => {
                 self.clear();
                 PromptResult::Quit
             }
-            Key::Char('\n') | Key::Left | Key::Right | Key::Home | Key::End => {
-                self.clear();
-                let execute = key == Key::Char('\n');
-                if let Some(ref entry) = self.current_entry {
-                    let cursor = self
-                        .input_to_regex()
-                        .find(entry)
-                        .expect("Current entry should match input")
-                        .end();
-                    PromptResult::Selected(entry.clone(), execute, adjust_cursor(cursor, key))
-                } else {
-                    PromptResult::Quit
-                }
+            Action::Accept => self.accept(Execute::Yes, key),
+            Action::AcceptBackground => self.accept(Execute::Background, key),
+            // Like accepting with End
+            Action::AcceptEdit => self.accept(Execute::No, Key::End),
+            Action::AcceptCursor if self.cursor_keys_edit() => self.handle_unbound_key(key),
+            Action::AcceptCursor => self.accept(Execute::No, key),
+            Action::BackwardWord => {
+                self.move_cursor(previous_word_start(&self.input, self.cursor));
+                PromptResult::Incomplete
             }
-            Key::Ctrl('r') => {
-                self.update();
+            Action::ForwardWord => {
+                self.move_cursor(next_word_end(&self.input, self.cursor));
                 PromptResult::Incomplete
             }
-            Key::Backspace => {
-                self.input.pop();
-                self.history.reset();
+            Action::NextMatch => {
                 self.update();
                 PromptResult::Incomplete
             }
+            Action::PrevMatch => {
+                self.previous_match();
+                PromptResult::Incomplete
+            }
+            Action::NextOccurrence => {
+                self.next_occurrence();
+                PromptResult::Incomplete
+            }
+            Action::CopyDirectory => self.copy_directory(),
+            Action::Copy => self.copy_entry(),
+            Action::DeleteEntry => {
+                self.delete_current();
+                PromptResult::Incomplete
+            }
+            Action::ContextBefore => {
+                self.show_context(true);
+                PromptResult::Incomplete
+            }
+            Action::ContextAfter => {
+                self.show_context(false);
+                PromptResult::Incomplete
+            }
+            Action::ToggleFuzzy => {
+                // Back to the configured mode, unless that's fuzzy already
+                self.match_mode = match (self.match_mode, self.config.match_mode) {
+                    (MatchMode::Fuzzy, MatchMode::Fuzzy) => MatchMode::Substring,
+                    (MatchMode::Fuzzy, mode) => mode,
+                    _ => MatchMode::Fuzzy,
+                };
+                self.search_from_start();
+                PromptResult::Incomplete
+            }
+            Action::TogglePrefix => {
+                self.prefix = !self.prefix;
+                self.search_from_start();
+                PromptResult::Incomplete
+            }
+            Action::CycleCase => {
+                self.case_mode = self.case_mode.next();
+                self.search_from_start();
+                PromptResult::Incomplete
+            }
+            Action::TogglePreview => {
+                self.show_preview = !self.show_preview;
+                self.redraw();
+                PromptResult::Incomplete
+            }
+            Action::DeleteChar => {
+                if let Some(ch) = self.input[..self.cursor].chars().next_back() {
+                    self.cursor -= ch.len_utf8();
+                    self.input.remove(self.cursor);
+                }
+                self.search_from_start();
+                PromptResult::Incomplete
+            }
+            Action::DeleteWord => {
+                self.cursor = delete_word(&mut self.input, self.cursor);
+                self.search_from_start();
+                PromptResult::Incomplete
+            }
+            Action::DeleteWhitespaceWord => {
+                self.cursor = delete_whitespace_word(&mut self.input, self.cursor);
+                self.search_from_start();
+                PromptResult::Incomplete
+            }
+            Action::Clear => {
+                self.set_input(String::new());
+                PromptResult::Incomplete
+            }
+        }
+    }
+
+    /// Whether keys bound to `accept_cursor` move within the query instead of accepting: without
+    /// a match to accept, or while the cursor is within the query (e.g. after Alt-b or Home), so
+    /// the query can be edited until the cursor is back at its end.
+    fn cursor_keys_edit(&self) -> bool {
+        self.shown_entry().is_none() || self.cursor < self.input.len()
+    }
+
+    /// Handles keys without a binding, which move the cursor or insert their character.
+    fn handle_unbound_key(&mut self, key: Key) -> PromptResult {
+        match key {
+            Key::Left => {
+                let previous = self.input[..self.cursor].chars().next_back();
+                self.move_cursor(self.cursor - previous.map_or(0, char::len_utf8));
+                PromptResult::Incomplete
+            }
+            Key::Right => {
+                let next = self.input[self.cursor..].chars().next();
+                self.move_cursor(self.cursor + next.map_or(0, char::len_utf8));
+                PromptResult::Incomplete
+            }
+            Key::Home => {
+                self.move_cursor(0);
+                PromptResult::Incomplete
+            }
+            Key::End => {
+                self.move_cursor(self.input.len());
+                PromptResult::Incomplete
+            }
+            // Neither Enter nor Tab are inserted into the single-line query, even if unbound
+            Key::Char('\n' | '\t') => PromptResult::Incomplete,
             Key::Char(ch) => {
-                self.input.push(ch);
-                self.history.reset();
-                self.update();
+                // Appending to the last word only narrows down the matches. Not so with a suffix,
+                // where the end of the query has to be the end of the entry, or for an excluded
+                // term, which excludes less the longer it gets.
+                let narrows = self.cursor == self.input.len()
+                    && !ch.is_whitespace()
+                    && !self.config.suffix
+                    && !self
+                        .input
+                        .rsplit(char::is_whitespace)
+                        .next()
+                        .is_some_and(|word| word.starts_with('!'));
+                self.input.insert(self.cursor, ch);
+                self.cursor += ch.len_utf8();
+                if narrows {
+                    self.search_narrowed();
+                } else {
+                    self.search_from_start();
+                }
                 PromptResult::Incomplete
             }
             _ => PromptResult::Incomplete,
         }
     }
 
-    pub fn handle_terminal_size_change(&mut self) {
-        let new_size = terminal_size(&self.stdout.as_fd()).unwrap();
-        if new_size.0 != self.terminal_size.0 {
-            let prompt = self.prompt();
-            self.current_input_height =
-                unicode_column_width(&prompt, None).div_ceil(new_size.0.into());
+    /// Inserts `text` into the query at the cursor and searches again.
+    fn paste(&mut self, text: &str) {
+        // The query is a single line, and pasted newlines shouldn't accept anything either
+        let text: String = text.chars().filter(|ch| !ch.is_control()).collect();
+        self.input.insert_str(self.cursor, &text);
+        self.cursor += text.len();
+        self.search_from_start();
+    }
+
+    /// Adds history `records`, most recent first, after the ones the prompt was created with.
+    /// They're read as configured, and commands already in the history are left out unless
+    /// duplicates are kept. If nothing matched so far, the search starts over to include them.
+    #[allow(dead_code)] // Not used by the binary itself, which reads all of stdin upfront
+    pub fn push_entries(&mut self, records: impl Iterator<Item = String> + 'static) {
+        let entries = history_entries(records, &self.config, 0);
+        let known: HashSet<_> = if self.config.keep_duplicates {
+            HashSet::new()
+        } else {
+            let count = self.history.read_to(usize::MAX);
+            (0..count)
+                .filter_map(|position| self.history.get(position))
+                .map(|entry| entry.command)
+                .collect()
+        };
+        self.history
+            .push(entries.filter(|entry| !known.contains(&entry.command)));
+        if self.current_entry.is_none() {
+            self.search_from_start();
+        } else {
+            // The new entries may match, too
+            self.match_count = None;
+            if let Some(counter) = &mut self.counter {
+                counter.exhausted = false;
+            }
+            self.redraw();
         }
-        self.terminal_size = new_size;
     }
 
-    fn update(&mut self) {
-        self.current_entry = self
-            .history
-            .find(|x| x.to_lowercase().contains(&self.input.to_lowercase()));
-        self.redraw();
+    /// Replaces the query and searches again, starting from the most recent entry.
+    pub fn set_input(&mut self, input: String) {
+        self.input = input;
+        self.cursor = self.input.len();
+        self.search_from_start();
     }
 
-    pub fn redraw(&mut self) {
+    fn accept(&mut self, execute: Execute, key: Key) -> PromptResult {
         self.clear();
-        let prompt = self.prompt();
-        let _ = write!(self.stdout, "{prompt}");
-        self.current_input_height =
-            unicode_column_width(&prompt, None).div_ceil(self.terminal_size.0.into());
-        if let Some(ref entry) = self.current_entry {
-            let highlight = self.input_to_regex();
-            let mut entry_height = 0;
-            for line in entry.lines() {
-                Self::print_line(line, &highlight, &mut self.stdout);
-                entry_height +=
-                    unicode_column_width(line, None).div_ceil(self.terminal_size.0.into());
+        if let Some(entry) = self.shown_entry().cloned() {
+            // Context entries don't have a current occurrence, so the first one is used
+            let occurrence = if self.context.is_some() {
+                0
+            } else {
+                self.occurrence
+            };
+            let current_match = self.occurrences(&entry.command).get(occurrence).cloned();
+            let (cursor, selected_match) = if self.config.outputs || self.matcher.terms().is_empty()
+            {
+                // The query matched a file, not the command, or there's no query at all
+                (entry.command.len(), None)
+            } else if let Some(span) = current_match {
+                (span.end, Some(span))
+            } else if self.context.is_some()
+                || self.matcher.highlight().is_none()
+                || self.revalidate(&entry.command)
+            {
+                // Over-limit queries have no occurrences to check, but still match
+                (entry.command.len(), None)
+            } else {
+                // Show what actually matches instead, to be accepted with the next key
+                self.redraw();
+                return PromptResult::Incomplete;
+            };
+            self.selected_match = selected_match;
+            // Match positions are byte offsets, but shells position the cursor in characters
+            let cursor = output::char_offset(&entry.command, cursor);
+            let len = entry.command.chars().count();
+            PromptResult::Selected(
+                entry.command.clone(),
+                execute,
+                adjust_cursor(cursor, len, key),
+            )
+        } else {
+            PromptResult::NoMatch
+        }
+    }
+
+    /// Searches again from the start, in case the current entry was found before the query or how
+    /// it's matched changed. Returns whether `command` is still the current match then.
+    fn revalidate(&mut self, command: &str) -> bool {
+        // Not deferred even in a batch, the result is needed right away
+        self.matcher = self.compile_query();
+        self.reset_search();
+        self.next_match();
+        self.current_entry
+            .as_ref()
+            .is_some_and(|entry| entry.command == command)
+    }
+
+    /// Copies the working directory of the current entry to the clipboard, or rings the bell if
+    /// that's not possible.
+    fn copy_directory(&mut self) -> PromptResult {
+        match self.shown_entry().and_then(|entry| entry.cwd.clone()) {
+            Some(cwd) => self.copy(&cwd),
+            None => {
+                let _ = write!(self.stdout, "\x07");
+                let _ = self.stdout.flush();
+                PromptResult::Incomplete
             }
-            let cursor_col: usize =
-                unicode_column_width(&prompt, None) % self.terminal_size.0 as usize;
-            let _ = write!(
-                self.stdout,
-                "{}\r{}",
-                termion::cursor::Up(entry_height.try_into().unwrap()),
-                termion::cursor::Right(cursor_col.try_into().unwrap()),
-            );
         }
-        let _ = self.stdout.flush();
     }
 
-    fn clear(&mut self) {
-        let _ = write!(
-            self.stdout,
-            "\r{}{}",
-            if self.current_input_height > 1 {
-                termion::cursor::Up((self.current_input_height - 1).try_into().unwrap()).to_string()
+    /// Copies the shown entry to the clipboard, or rings the bell if there's none.
+    fn copy_entry(&mut self) -> PromptResult {
+        match self.shown_entry().map(|entry| entry.command.clone()) {
+            Some(command) => self.copy(&command),
+            None => {
+                let _ = write!(self.stdout, "\x07");
+                let _ = self.stdout.flush();
+                PromptResult::Incomplete
+            }
+        }
+    }
+
+    /// Copies `text` to the clipboard. If that's not possible, the prompt says so and the search
+    /// goes on.
+    fn copy(&mut self, text: &str) -> PromptResult {
+        match clipboard::copy(text) {
+            Ok(()) => {
+                self.clear();
+                PromptResult::Copied
+            }
+            Err(err) => {
+                self.status = Some(if err.kind() == std::io::ErrorKind::NotFound {
+                    "(no clipboard helper) "
+                } else {
+                    "(copying failed) "
+                });
+                self.redraw();
+                PromptResult::Incomplete
+            }
+        }
+    }
+
+    /// Shows the entry before (`older`) or after the shown entry in the history, whether it
+    /// matches or not. Rings the bell if there's no such entry.
+    fn show_context(&mut self, older: bool) {
+        let position = match (&self.context, &self.current_entry) {
+            (Some((position, _)), _) => Some(*position),
+            (None, Some(_)) => Some(self.current_position),
+            (None, None) => None,
+        };
+        let neighbor = position.and_then(|position| {
+            let position = if older {
+                position + 1
             } else {
-                String::new()
-            },
-            termion::clear::AfterCursor
-        );
+                position.checked_sub(1)?
+            };
+            Some((position, self.history.get(position)?))
+        });
+        match neighbor {
+            Some((position, _)) if position == self.current_position => self.context = None,
+            Some(neighbor) => self.context = Some(neighbor),
+            None => {
+                let _ = write!(self.stdout, "\x07");
+                let _ = self.stdout.flush();
+                return;
+            }
+        }
+        self.find_spans();
+        self.redraw();
+    }
+
+    /// The entry shown in the preview.
+    fn shown_entry(&self) -> Option<&Entry> {
+        self.context
+            .as_ref()
+            .map(|(_, entry)| entry)
+            .or(self.current_entry.as_ref())
     }
 
-    fn print_line(line: &str, highlight: &Regex, stdout: &mut RawTerminal<W>) {
-        let _ = write!(stdout, "\r\n");
-        let mut last_end = 0;
-        for m in highlight.find_iter(line) {
+    /// Shows `command` in place of the prompt for `duration`, then clears it again.
+    pub fn echo(&mut self, command: &str, duration: Duration) {
+        self.clear();
+        let mut height = 0;
+        for (i, line) in command.lines().enumerate() {
+            let newline = if i > 0 { "\r\n" } else { "" };
             let _ = write!(
-                stdout,
-                "{}{}{}{}{}{}",
-                &line[last_end..m.start()],
-                termion::color::Fg(termion::color::Red),
-                termion::style::Invert,
+                self.stdout,
+                "{newline}{}{line}{}",
                 termion::style::Bold,
-                m.as_str(),
                 termion::style::Reset
             );
-            last_end = m.end();
+            height += self.height(line, 0);
         }
-        let _ = write!(stdout, "{}", &line[last_end..]);
+        let _ = self.stdout.flush();
+        std::thread::sleep(duration);
+        self.current_input_height = height;
+        self.clear();
+        let _ = self.stdout.flush();
     }
 
-    fn input_to_regex(&self) -> Regex {
-        Regex::new(&format!("(?i){}", regex::escape(&self.input)))
-            .expect("Should be valid regex pattern")
+    pub fn handle_terminal_size_change(&mut self) {
+        let new_size = self.stdout.terminal_size().unwrap();
+        if new_size == self.terminal_size {
+            return;
+        }
+        // The terminal might have moved everything around
+        self.prompt_row = None;
+        if new_size.0 != self.terminal_size.0 {
+            // As drawn for the old size, which the terminal rewraps
+            let (prompt, _) = self.displayed_prompt();
+            let (last_row, _) =
+                wrap_position(&prompt, 0, new_size.0.into(), self.config.ambiguous_wide);
+            self.current_input_height = last_row + 1;
+        }
+        self.terminal_size = new_size;
+        // Both the prompt and the entry might need more or fewer rows now
+        self.redraw();
     }
 
-    fn prompt(&self) -> String {
-        format!("bck-i-search: {}", self.input)
+    /// Sets the terminal row (counting from 1) the prompt starts at, so listed matches can be
+    /// clicked.
+    pub fn set_prompt_row(&mut self, row: usize) {
+        self.prompt_row = Some(row);
     }
-}
-
-fn main() -> ExitCode {
-    let Ok(stdin) = File::open("/dev/tty") else {
-        eprintln!("[FATAL] Could not open TTY");
-        return ExitCode::FAILURE;
-    };
-    let Ok(stdout) = File::create("/dev/tty") else {
-        eprintln!("[FATAL] Could not open TTY");
-        return ExitCode::FAILURE;
-    };
-    let stdout = stdout.into_raw_mode().unwrap();
-    let history = HistoryIter::from_reader(BufReader::new(std::io::stdin()));
-    let mut prompt = Prompt::new(stdout, history).unwrap();
-    prompt.redraw();
 
-    let winch = Arc::new(AtomicBool::new(false));
-    signal_hook::flag::register(signal_hook::consts::SIGWINCH, Arc::clone(&winch))
-        .expect("Registering signal handler should work");
+    /// Keeps track of the prompt's row when drawing `rows` rows below it scrolls the terminal.
+    fn follow_scrolling(&mut self, rows: usize) {
+        let input_rows = self.current_input_height.max(1);
+        let terminal_rows = usize::from(self.terminal_size.1);
+        if let Some(row) = &mut self.prompt_row {
+            let overflow = (*row + input_rows - 1 + rows).saturating_sub(terminal_rows);
+            *row -= overflow.min(*row - 1);
+        }
+    }
 
-    let mut selection = None;
-    for key in stdin.keys() {
-        let Ok(key) = key else {
-            continue;
-        };
-        if winch.load(Ordering::Acquire) {
-            winch.store(false, Ordering::SeqCst);
-            prompt.handle_terminal_size_change();
-        }
-        match prompt.handle_key_press(key) {
-            PromptResult::Incomplete => (),
-            PromptResult::Selected(entry, execute, cursor_pos) => {
-                selection = Some((entry, execute, cursor_pos));
-                break;
+    /// Returns the index of the listed match shown at terminal row `row` (counting from 1).
+    fn clicked_match(&self, row: u16) -> Option<usize> {
+        let first_row = self.prompt_row? + self.current_input_height.max(1);
+        let mut offset = usize::from(row).checked_sub(first_row)?;
+        for (i, height) in self.list_heights.iter().enumerate() {
+            if offset < *height {
+                return Some(self.list_start + i);
             }
-            PromptResult::Quit => break,
+            offset -= height;
         }
+        None
     }
-    drop(prompt);
 
-    if let Some((entry, execute, cursor_pos)) = selection {
-        println!("{execute}");
-        println!("{cursor_pos}");
-        print!("{entry}\0");
+    /// Moves to the listed match at `index` and accepts it without running it, like Alt-Enter.
+    fn accept_listed(&mut self, index: usize) -> PromptResult {
+        if self.batching {
+            self.finish_batch();
+            self.batching = true;
+        }
+        let active = self.earlier_matches.len();
+        for _ in active..index {
+            self.next_match();
+        }
+        for _ in index..active {
+            self.previous_match();
+        }
+        self.accept(Execute::No, Key::End)
     }
 
-    ExitCode::SUCCESS
-}
+    /// How many rows the cursor can move up at most.
+    fn max_rows_up(&self) -> usize {
+        usize::from(self.terminal_size.1).saturating_sub(1)
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::{HistoryIter, ReusableIter};
+    /// Moves the cursor within the query to `position`.
+    fn move_cursor(&mut self, position: usize) {
+        self.cursor = position;
+        self.redraw();
+    }
 
-    fn collect_history(input: &[u8]) -> Vec<String> {
-        let reader = std::io::Cursor::new(input);
-        HistoryIter::from_reader(reader).collect()
+    /// Searches again, starting from the most recent entry. Needs to be called whenever the query
+    /// or how it's matched changes.
+    fn search_from_start(&mut self) {
+        if self.batching {
+            self.search_pending = true;
+            return;
+        }
+        self.matcher = self.compile_query();
+        self.reset_search();
+        self.update();
     }
 
-    #[test]
-    fn test_history_iter() {
-        let lines = collect_history(b"entry1\0entry2\0entry 3\nstill entry 3\0");
-        assert_eq!(
-            lines,
-            vec![
-                "entry1".to_string(),
-                "entry2".to_string(),
-                "entry 3\nstill entry 3".to_string()
-            ]
-        );
+    /// Forgets all matches, so the next search starts from the most recent entry.
+    fn reset_search(&mut self) {
+        self.history.reset_to(self.config.skip_newest);
+        self.preferred_pass = true;
+        self.sorted_matches = None;
+        self.partial = false;
+        self.forget_match_count();
+        self.earlier_matches.clear();
+        self.current_entry = None;
+        self.spans.clear();
+    }
+
+    /// Forgets the number of matches, e.g. for another query, but keeps how many there were at
+    /// least.
+    fn forget_match_count(&mut self) {
+        let known = self.earlier_matches.len() + usize::from(self.current_entry.is_some());
+        let counted = self.counter.as_ref().map_or(0, |counter| counter.count);
+        self.previous_count = self.match_count.unwrap_or(counted.max(known));
+        self.match_count = None;
+        self.restart_counter();
+    }
+
+    /// Starts counting the matches of the query over, if enabled.
+    fn restart_counter(&mut self) {
+        self.counter = (self.config.count_matches)
+            .map(|limit| MatchCounter::new(self.config.skip_newest, limit));
+    }
+
+    /// Counts matches in up to `budget` more entries with `Config::count_matches`, and shows
+    /// the count if it changed. Returns whether there's more to count.
+    pub fn count_matches(&mut self, budget: usize) -> bool {
+        let Some(counter) = self
+            .counter
+            .as_mut()
+            .filter(|counter| !counter.is_done() && self.match_count.is_none())
+        else {
+            return false;
+        };
+        let start = counter.position;
+        let available = self.history.read_to(start + budget);
+        let (config, matcher, deleted) = (&self.config, &self.matcher, &self.deleted);
+        let history = &mut self.history;
+        let matched = (start..available).map(|position| {
+            history
+                .get(position)
+                .is_some_and(|entry| rank_entry(&entry, config, matcher, deleted).is_some())
+        });
+        let count = counter.count;
+        counter.add(matched, budget);
+        if counter.exhausted {
+            self.match_count = Some(counter.count);
+        }
+        let (changed, done) = (counter.count != count, counter.is_done());
+        if changed || done {
+            self.redraw();
+        }
+        !done
+    }
+
+    /// Deletes the current match, so neither it nor other entries with the same command match
+    /// again, and advances to the next match. Rings the bell if there's none.
+    fn delete_current(&mut self) {
+        let Some(entry) = self.current_entry.take().filter(|_| self.context.is_none()) else {
+            let _ = write!(self.stdout, "\x07");
+            let _ = self.stdout.flush();
+            return;
+        };
+        self.deleted.insert(entry.command);
+        // Positions of later matches are unchanged, but counting them starts over. So search
+        // again and skip as many matches as were shown before.
+        let shown = self.earlier_matches.len();
+        self.reset_search();
+        for _ in 0..=shown {
+            self.next_match();
+        }
+        if self.current_entry.is_none() && !self.earlier_matches.is_empty() {
+            // The deleted entry was the last match, so show the one before it
+            self.previous_match();
+        } else {
+            self.redraw();
+        }
+    }
+
+    /// The query as it is now.
+    pub fn query(&self) -> &str {
+        &self.input
+    }
+
+    /// The byte range of the match in the selected entry, if the cursor was put after one.
+    pub fn selected_match(&self) -> Option<Range<usize>> {
+        self.selected_match.clone()
+    }
+
+    /// Returns the commands deleted with Ctrl-D so far.
+    pub fn take_deleted(&mut self) -> HashSet<String> {
+        std::mem::take(&mut self.deleted)
+    }
+
+    /// Searches again after the query was narrowed down, i.e. it only matches entries that
+    /// matched before, and only preferred ones if they were preferred before. So the search can
+    /// continue at the first match of the previous query instead of the most recent entry.
+    fn search_narrowed(&mut self) {
+        let first_match = self.earlier_matches.first().copied().or(self
+            .current_entry
+            .as_ref()
+            .map(|_| (self.current_position, self.preferred_pass)));
+        // Nothing matched before, so the whole history was searched already
+        let exhausted = self.current_entry.is_none() && self.match_count == Some(0);
+        if self.batching
+            || self.config.cycle_order == CycleOrder::Recent
+            || (first_match.is_none() && !exhausted)
+        {
+            self.search_from_start();
+            return;
+        }
+        self.matcher = self.compile_query();
+        if let Some((position, preferred_pass)) = first_match {
+            self.history.reset_to(position);
+            self.preferred_pass = preferred_pass;
+        }
+        self.forget_match_count();
+        self.earlier_matches.clear();
+        self.current_entry = None;
+        self.update();
+    }
+
+    /// Makes the next occurrence of the query in the current entry the current one, starting
+    /// over after the last. Rings the bell if there's only one.
+    fn next_occurrence(&mut self) {
+        let count = match (&self.context, &self.current_entry) {
+            (None, Some(_)) => self.spans.len(),
+            _ => 0,
+        };
+        if count < 2 {
+            let _ = write!(self.stdout, "\x07");
+            let _ = self.stdout.flush();
+            return;
+        }
+        self.occurrence = (self.occurrence + 1) % count;
+        self.redraw();
+    }
+
+    /// Goes back to the match shown before the current one. Rings the bell if there's none.
+    fn previous_match(&mut self) {
+        let Some((position, preferred_pass)) = self.earlier_matches.pop() else {
+            let _ = write!(self.stdout, "\x07");
+            let _ = self.stdout.flush();
+            return;
+        };
+        self.context = None;
+        self.current_position = position;
+        self.occurrence = 0;
+        if let Some(matches) = &mut self.sorted_matches {
+            // Each shown match was taken from the sorted matches in turn
+            let index = self.earlier_matches.len();
+            matches.reset_to(index + 1);
+            self.current_entry = matches.get(index).map(|(_, entry)| entry);
+        } else {
+            self.history.reset_to(position + 1);
+            self.preferred_pass = preferred_pass;
+            self.current_entry = self.history.get(position);
+        }
+        self.find_spans();
+        self.redraw();
+    }
+
+    /// Advances to the next match. Preferred matches (e.g. where the query starts a word) come
+    /// first, followed by all other matches, both in history order. With `CycleOrder::Recent`,
+    /// matches are ordered by score instead, then most recent first.
+    fn update(&mut self) {
+        self.next_match();
+        self.redraw();
+    }
+
+    /// Like `update`, but without redrawing.
+    fn next_match(&mut self) {
+        self.context = None;
+        self.occurrence = 0;
+        if self.current_entry.is_some() {
+            self.earlier_matches
+                .push((self.current_position, self.preferred_pass));
+        }
+        if self.config.cycle_order == CycleOrder::Recent && self.sorted_matches.is_none() {
+            // Collecting all matches can take a while, the indicator stays until the next redraw
+            self.indexing = true;
+            self.redraw();
+            self.indexing = false;
+        }
+        let config = &self.config;
+        let matcher = &self.matcher;
+        let deleted = &self.deleted;
+        let rank = |entry: &Entry| rank_entry(entry, config, matcher, deleted);
+        if config.cycle_order == CycleOrder::Recent {
+            let history = &mut self.history;
+            let partial = &mut self.partial;
+            let match_count = &mut self.match_count;
+            let matches = self.sorted_matches.get_or_insert_with(|| {
+                history.reset_to(config.skip_newest);
+                let matches: Vec<_> = history
+                    .by_ref()
+                    .take(config.max_scan.unwrap_or(DEFAULT_MAX_SCAN))
+                    .enumerate()
+                    .filter_map(|(i, entry)| Some((rank(&entry)?, config.skip_newest + i, entry)))
+                    .collect();
+                *partial = history.next().is_some();
+                *match_count = Some(matches.len());
+                ReusableIter::new(sort_matches(matches).into_iter())
+            });
+            let next = matches.next();
+            self.current_position = next.as_ref().map_or(0, |(position, _)| *position);
+            self.current_entry = next.map(|(_, entry)| entry);
+        } else {
+            loop {
+                let preferred_pass = self.preferred_pass;
+                self.current_entry = self.history.find(|x| {
+                    rank(x).is_some_and(|score| (score >= score::PREFERRED) == preferred_pass)
+                });
+                if self.current_entry.is_some() || !self.preferred_pass {
+                    self.current_position = self.history.position().saturating_sub(1);
+                    if self.current_entry.is_none() {
+                        self.match_count = Some(self.earlier_matches.len());
+                    }
+                    break;
+                }
+                self.preferred_pass = false;
+                self.history.reset_to(config.skip_newest);
+            }
+        }
+        // Without earlier matches there's nothing to start over with, which also ends the
+        // recursion
+        if self.current_entry.is_none() && self.config.wrap && !self.earlier_matches.is_empty() {
+            self.earlier_matches.clear();
+            match &mut self.sorted_matches {
+                Some(matches) => matches.reset(),
+                None => {
+                    self.history.reset_to(self.config.skip_newest);
+                    self.preferred_pass = true;
+                }
+            }
+            self.next_match();
+            return;
+        }
+        self.find_spans();
+    }
+
+    /// Finds what to highlight in the shown entry, whenever that changes.
+    fn find_spans(&mut self) {
+        self.spans = self
+            .shown_entry()
+            .map_or_else(Vec::new, |entry| self.occurrences(&entry.command));
+    }
+
+    pub fn redraw(&mut self) {
+        self.clear();
+        let (prompt, prompt_cursor) = self.displayed_prompt();
+        if self.count_dropped() {
+            // Dims the ordinal, which follows the status message
+            let start = self.status.unwrap_or_default().len();
+            let end = start + self.ordinal().trim_end().len();
+            let _ = write!(
+                self.stdout,
+                "{}{}{}{}{}",
+                &prompt[..start],
+                termion::style::Faint,
+                &prompt[start..end],
+                termion::style::Reset,
+                &prompt[end..]
+            );
+        } else {
+            let _ = write!(self.stdout, "{prompt}");
+        }
+        self.current_input_height = self.height(&prompt, 0);
+        self.rows_below_cursor = 0;
+        self.list_heights.clear();
+        self.follow_scrolling(0);
+        if self.indexing {
+            let _ = write!(
+                self.stdout,
+                "\r\n{}indexing…{}",
+                termion::style::Faint,
+                termion::style::Reset
+            );
+            self.move_to_prompt(1, &prompt, prompt_cursor);
+        } else if let Some(rows) = self
+            .config
+            .list
+            .filter(|_| self.context.is_none() && self.current_entry.is_some() && self.show_preview)
+        {
+            self.draw_list(rows, &prompt, prompt_cursor);
+        } else if let Some(entry) = (self.context.as_ref().map(|(_, entry)| entry))
+            .or(self.current_entry.as_ref())
+            .filter(|_| self.show_preview)
+        {
+            let (start, displayed) = if self.config.strip_command {
+                self.searched_text(&entry.command)
+            } else {
+                (0, entry.command.as_str())
+            };
+            // With their byte offsets in the entry, which the occurrences refer to
+            let mut lines: Vec<_> = split_lines(displayed)
+                .into_iter()
+                .map(|(offset, line)| (start + offset, line))
+                .collect();
+            let mut hidden = (0, 0);
+            if self.config.collapse_multiline && lines.len() > 1 {
+                let matched = lines
+                    .iter()
+                    .position(|&(offset, line)| {
+                        !spans_on_line(&self.spans, offset, line).is_empty()
+                    })
+                    .unwrap_or(0);
+                hidden = (matched, lines.len() - matched - 1);
+                lines = vec![lines[matched]];
+            }
+            if let Some((offset, first)) = lines.first_mut().filter(|_| self.config.trim_whitespace)
+            {
+                let trimmed = first.trim_start();
+                *offset += first.len() - trimmed.len();
+                *first = trimmed;
+            }
+            let available_rows = usize::from(self.terminal_size.1)
+                .saturating_sub(self.current_input_height + usize::from(self.config.status));
+            let (visible, overflow) = clamp_lines(&lines, available_rows);
+            lines.truncate(visible.len());
+            let mut indicator = String::new();
+            if entry.repeats > 0 {
+                indicator = format!(" (x{})", entry.repeats + 1);
+            }
+            if hidden != (0, 0) {
+                indicator.push_str(&format!(" (+{} above, +{} below)", hidden.0, hidden.1));
+            }
+            if self.context.is_some() && self.spans.is_empty() {
+                indicator.push_str(" (no match)");
+            }
+            if overflow > 0 {
+                indicator.push_str(&format!(" … (+{overflow} more lines)"));
+            }
+            let line_count = lines.len();
+            let columns = usize::from(self.terminal_size.0);
+            let mut entry_height = 0;
+            let style = HighlightStyle {
+                separate: self.config.separate_highlights,
+                color: !self.config.no_color,
+                current: None,
+                inverted: false,
+                highlight: self.config.highlight,
+            };
+            // Only worth pointing out if there's more than one occurrence
+            let current_match = (self.spans.get(self.occurrence))
+                .filter(|_| self.context.is_none() && self.spans.len() > 1);
+            let continuation_marker =
+                (self.config.continuation_marker.as_deref()).unwrap_or(DEFAULT_CONTINUATION_MARKER);
+            for (i, (mut offset, line)) in lines.into_iter().enumerate() {
+                // Whether `span` of the line at `offset` is part of the current match
+                let overlaps_current = |offset: usize, span: &Range<usize>| {
+                    current_match.is_some_and(|current| {
+                        !span.is_empty()
+                            && span.start + offset < current.end
+                            && current.start < span.end + offset
+                    })
+                };
+                // Continuation lines are marked, so they don't look like separate entries
+                let (marker, marker_width) = if i > 0 && !continuation_marker.is_empty() {
+                    (
+                        format!(
+                            "{}{continuation_marker}{}",
+                            termion::style::Faint,
+                            termion::style::Reset
+                        ),
+                        self.width(continuation_marker),
+                    )
+                } else {
+                    (String::new(), 0)
+                };
+                let mut line = line;
+                let mut cut = (false, false);
+                if self.config.truncate {
+                    let spans = spans_on_line(&self.spans, offset, line);
+                    let focus = (spans.iter().find(|span| overlaps_current(offset, span)))
+                        .or(spans.iter().find(|span| !span.is_empty()))
+                        .cloned();
+                    let mut available = columns.saturating_sub(marker_width);
+                    if i == line_count - 1 {
+                        available = available.saturating_sub(self.width(&indicator));
+                    }
+                    let shown = truncate_line(line, focus, available, self.config.ambiguous_wide);
+                    cut = (shown.start > 0, shown.end < line.len());
+                    offset += shown.start;
+                    line = &line[shown];
+                }
+                let spans = self.limit_spans(spans_on_line(&self.spans, offset, line));
+                let style = HighlightStyle {
+                    current: spans.iter().position(|span| overlaps_current(offset, span)),
+                    ..style
+                };
+                // How many rows there are, and where the last one starts
+                let (rows, last_row_start, mut last_row) = if self.config.truncate {
+                    let ellipsis = |cut: bool| {
+                        if cut {
+                            format!("{}…{}", termion::style::Faint, termion::style::Reset)
+                        } else {
+                            String::new()
+                        }
+                    };
+                    let prefix = format!("{marker}{}", ellipsis(cut.0));
+                    print_line(&prefix, line, &spans, style, &mut self.stdout);
+                    let _ = write!(self.stdout, "{}", ellipsis(cut.1));
+                    let ellipsis = |cut: bool| if cut { "…" } else { "" };
+                    (
+                        1,
+                        marker_width,
+                        format!("{}{line}{}", ellipsis(cut.0), ellipsis(cut.1)),
+                    )
+                } else if self.config.wrap_indent > 0 {
+                    let indent = self.config.wrap_indent;
+                    let rows = wrap_line(
+                        line,
+                        columns,
+                        marker_width,
+                        indent,
+                        self.config.ambiguous_wide,
+                    );
+                    print_wrapped_line(
+                        &marker,
+                        line,
+                        &rows,
+                        &spans,
+                        style,
+                        indent,
+                        &mut self.stdout,
+                    );
+                    let last_row = rows.last().expect("There's always at least one row");
+                    let last_indent = if rows.len() > 1 { indent } else { marker_width };
+                    (rows.len(), last_indent, line[last_row.clone()].to_string())
+                } else {
+                    print_line(&marker, line, &spans, style, &mut self.stdout);
+                    (1, marker_width, line.to_string())
+                };
+                if i == line_count - 1 && !indicator.is_empty() {
+                    let _ = write!(
+                        self.stdout,
+                        "{}{indicator}{}",
+                        termion::style::Faint,
+                        termion::style::Reset
+                    );
+                    last_row.push_str(&indicator);
+                }
+                // The terminal wraps the last row if it's too wide
+                entry_height += rows - 1 + self.height(&last_row, last_row_start);
+            }
+            self.move_to_prompt(entry_height, &prompt, prompt_cursor);
+        } else if self.current_entry.is_none() && !self.input.is_empty() {
+            // Otherwise it looks like the search is still running
+            let message = if self.earlier_matches.is_empty() {
+                "(no matches)"
+            } else {
+                "(no more matches)"
+            };
+            let _ = write!(
+                self.stdout,
+                "\r\n{}{message}{}",
+                termion::style::Faint,
+                termion::style::Reset
+            );
+            self.move_to_prompt(self.height(message, 0), &prompt, prompt_cursor);
+        } else if self.cursor < self.input.len() || self.config.status {
+            self.move_to_prompt(0, &prompt, prompt_cursor);
+        }
+        if self.config.status {
+            self.draw_status_bar();
+        }
+        let _ = self.stdout.flush();
+    }
+
+    /// Draws the status bar on the last row, leaving the cursor where it is.
+    fn draw_status_bar(&mut self) {
+        let columns = usize::from(self.terminal_size.0);
+        let mut text = self.status_bar();
+        // Wider text would wrap, and scroll everything else up
+        while self.width(&text) > columns {
+            text.pop();
+        }
+        let _ = write!(
+            self.stdout,
+            "{}{}{}{}{text}{}{}",
+            termion::cursor::Save,
+            termion::cursor::Goto(1, self.terminal_size.1),
+            termion::clear::CurrentLine,
+            termion::style::Invert,
+            termion::style::Reset,
+            termion::cursor::Restore
+        );
+    }
+
+    /// Draws up to `rows` matches around the current one below `prompt`, one line each.
+    fn draw_list(&mut self, rows: usize, prompt: &str, prompt_cursor: usize) {
+        let rows = rows.min(
+            self.max_rows_up()
+                .saturating_sub(self.current_input_height - 1 + usize::from(self.config.status)),
+        );
+        let active = self.earlier_matches.len();
+        self.list_start = list_window(self.list_start, active, rows.max(1));
+        let mut entries: Vec<_> = (self.list_start..active)
+            .filter_map(|index| self.earlier_match(index))
+            .collect();
+        entries.extend(self.current_entry.clone());
+        entries.extend(self.upcoming_matches((self.list_start + rows).saturating_sub(active + 1)));
+        let mut height = 0;
+        for (i, entry) in entries.iter().take(rows).enumerate() {
+            // The first matching line stands in for multiline entries
+            let spans = self.occurrences(&entry.command);
+            let lines = split_lines(&entry.command);
+            let (mut offset, mut line) = lines
+                .iter()
+                .find(|&&(offset, line)| !spans_on_line(&spans, offset, line).is_empty())
+                .or(lines.first())
+                .copied()
+                .unwrap_or_default();
+            if self.config.trim_whitespace {
+                let trimmed = line.trim_start();
+                offset += line.len() - trimmed.len();
+                line = trimmed;
+            }
+            let style = HighlightStyle {
+                separate: self.config.separate_highlights,
+                color: !self.config.no_color,
+                current: None,
+                inverted: self.list_start + i == active,
+                highlight: self.config.highlight,
+            };
+            print_line(
+                "",
+                line,
+                &self.limit_spans(spans_on_line(&spans, offset, line)),
+                style,
+                &mut self.stdout,
+            );
+            let mut shown = line.to_string();
+            if lines.len() > 1 {
+                let _ = write!(
+                    self.stdout,
+                    "{} …{}",
+                    termion::style::Faint,
+                    termion::style::Reset
+                );
+                shown.push_str(" …");
+            }
+            let rows = self.height(&shown, 0);
+            self.list_heights.push(rows);
+            height += rows;
+        }
+        self.move_to_prompt(height, prompt, prompt_cursor);
+    }
+
+    /// Returns the match shown at `index` (counting from the first match) before the current one.
+    fn earlier_match(&mut self, index: usize) -> Option<Entry> {
+        match &mut self.sorted_matches {
+            Some(matches) => matches.get(index).map(|(_, entry)| entry),
+            None => {
+                let (position, _) = *self.earlier_matches.get(index)?;
+                self.history.get(position)
+            }
+        }
+    }
+
+    /// Returns up to `count` matches following the current one, without advancing the search.
+    fn upcoming_matches(&mut self, count: usize) -> Vec<Entry> {
+        let current_entry = self.current_entry.clone();
+        let current_position = self.current_position;
+        let earlier_matches = self.earlier_matches.clone();
+        let preferred_pass = self.preferred_pass;
+        let match_count = self.match_count;
+        let occurrence = self.occurrence;
+        let spans = self.spans.clone();
+        let position = self.history.position();
+        let sorted_position = self.sorted_matches.as_ref().map(ReusableIter::position);
+        let mut upcoming = Vec::new();
+        while upcoming.len() < count && self.current_entry.is_some() {
+            self.next_match();
+            // Starting over with `Config::wrap` would show matches twice
+            if self.earlier_matches.len() != earlier_matches.len() + upcoming.len() + 1 {
+                break;
+            }
+            upcoming.extend(self.current_entry.clone());
+        }
+        self.current_entry = current_entry;
+        self.current_position = current_position;
+        self.earlier_matches = earlier_matches;
+        self.preferred_pass = preferred_pass;
+        self.match_count = match_count;
+        self.occurrence = occurrence;
+        self.spans = spans;
+        self.history.reset_to(position);
+        if let (Some(matches), Some(position)) = (&mut self.sorted_matches, sorted_position) {
+            matches.reset_to(position);
+        }
+        upcoming
+    }
+
+    fn width(&self, text: &str) -> usize {
+        text_width(text, self.config.ambiguous_wide)
+    }
+
+    /// Returns how many rows `text` takes when printed at column `start` (see `wrap_position`).
+    fn height(&self, text: &str, start: usize) -> usize {
+        let columns = usize::from(self.terminal_size.0);
+        wrap_position(text, start, columns, self.config.ambiguous_wide).0 + 1
+    }
+
+    /// Moves the cursor `rows` rows up, to the last row of `prompt`, and then to the query's
+    /// cursor at byte `cursor` of `prompt` (see `displayed_prompt`).
+    fn move_to_prompt(&mut self, rows: usize, prompt: &str, cursor: usize) {
+        // Keeps the last row free for the status bar, also when everything above was drawn at
+        // the bottom of the terminal
+        let rows = if self.config.status {
+            let _ = write!(self.stdout, "\r\n");
+            rows + 1
+        } else {
+            rows
+        };
+        self.follow_scrolling(rows);
+        let columns = usize::from(self.terminal_size.0);
+        let before_cursor = &prompt[..cursor];
+        let (mut cursor_row, mut cursor_col) =
+            wrap_position(before_cursor, 0, columns, self.config.ambiguous_wide);
+        // After a full row, the cursor is placed at the start of the next one
+        if cursor_col >= columns {
+            (cursor_row, cursor_col) = (cursor_row + 1, 0);
+        }
+        let last_row = self.height(prompt, 0) - 1;
+        self.rows_below_cursor = last_row.saturating_sub(cursor_row);
+        // The terminal can't move above its first row anyway
+        let rows = (rows + self.rows_below_cursor).min(self.max_rows_up());
+        if rows > 0 {
+            let _ = write!(
+                self.stdout,
+                "{}",
+                termion::cursor::Up(rows.try_into().unwrap())
+            );
+        }
+        let _ = write!(self.stdout, "\r");
+        // Moving by zero columns still moves by one
+        if cursor_col > 0 {
+            let _ = write!(
+                self.stdout,
+                "{}",
+                termion::cursor::Right(cursor_col.try_into().unwrap())
+            );
+        }
+    }
+
+    /// The prompt as it's drawn, and the byte offset of the query's cursor in it. Without
+    /// prompt wrapping, only the part of the query around the cursor that fits into the row is
+    /// shown, with an ellipsis where it's cut off.
+    fn displayed_prompt(&self) -> (String, usize) {
+        let prompt = self.prompt();
+        let head = prompt.len() - self.input.len();
+        if self.config.prompt_wrap == PromptWrap::On {
+            return (prompt, head + self.cursor);
+        }
+        let columns = usize::from(self.terminal_size.0);
+        // The last column stays free for the cursor, so the terminal doesn't move on to the next
+        // row
+        let available = columns.saturating_sub(self.width(&prompt[..head]) + 1);
+        let window = query_window(
+            &self.input,
+            self.cursor,
+            available,
+            self.config.ambiguous_wide,
+        );
+        let lead = if window.start > 0 { "…" } else { "" };
+        let trail = if window.end < self.input.len() {
+            "…"
+        } else {
+            ""
+        };
+        let cursor = head + lead.len() + self.cursor - window.start;
+        (
+            format!("{}{lead}{}{trail}", &prompt[..head], &self.input[window]),
+            cursor,
+        )
+    }
+
+    fn clear(&mut self) {
+        let _ = write!(
+            self.stdout,
+            "\r{}{}",
+            if self.current_input_height > self.rows_below_cursor + 1 {
+                let rows = (self.current_input_height - self.rows_below_cursor - 1)
+                    .min(self.max_rows_up());
+                termion::cursor::Up(rows.try_into().unwrap()).to_string()
+            } else {
+                String::new()
+            },
+            termion::clear::AfterCursor
+        );
+    }
+
+    /// Returns the part of `command` the query is matched against, and its byte offset in
+    /// `command`.
+    fn searched_text<'a>(&self, command: &'a str) -> (usize, &'a str) {
+        // The arguments are what's left at the end of the command
+        let start = (self.config.command.as_deref())
+            .and_then(|name| command_args(command, name))
+            .map_or(0, |args| command.len() - args.len());
+        if self.config.command_only {
+            let name = command_name_range(&command[start..]);
+            (
+                start + name.start,
+                &command[start + name.start..start + name.end],
+            )
+        } else {
+            (start, &command[start..])
+        }
+    }
+
+    /// Returns the byte ranges in `command` where the query occurs, in order. The ranges are the
+    /// highlighted ones, e.g. single characters in fuzzy mode.
+    fn occurrences(&self, command: &str) -> Vec<Range<usize>> {
+        let (offset, searched) = self.searched_text(command);
+        self.highlight_spans(searched)
+            .into_iter()
+            .filter(|span| !span.is_empty())
+            .map(|span| span.start + offset..span.end + offset)
+            .collect()
+    }
+
+    /// Returns the spans of a line to highlight, within the configured limits.
+    fn limit_spans(&self, mut spans: Vec<Range<usize>>) -> Vec<Range<usize>> {
+        if let Some(max) = self.config.max_highlights {
+            spans.truncate(max);
+        }
+        let min_length = self
+            .config
+            .min_highlight_length
+            .unwrap_or(DEFAULT_MIN_HIGHLIGHT_LENGTH);
+        if self.input.chars().count() < min_length {
+            spans.clear();
+        }
+        spans
+    }
+
+    /// Returns the byte ranges in `text` to highlight. With environment expansion, the matching
+    /// happens on the expanded text and the ranges refer to the unexpanded text.
+    fn highlight_spans(&self, text: &str) -> Vec<Range<usize>> {
+        if !self.config.expand_env {
+            return self.matcher.spans(text);
+        }
+        let expanded = Expanded::from_env(text, self.config.keep_undefined_env);
+        let mut spans: Vec<Range<usize>> = Vec::new();
+        for range in self.matcher.spans(&expanded.text) {
+            let span = expanded.to_original(range);
+            // Several matches inside one variable map to the same range
+            match spans.last_mut() {
+                Some(last) if span.start < last.end => last.end = last.end.max(span.end),
+                _ => spans.push(span),
+            }
+        }
+        spans
+    }
+
+    /// Prepares the query for matching, as it's matched right now.
+    fn compile_query(&self) -> Matcher {
+        let options = MatchOptions {
+            mode: self.match_mode,
+            case: self.case_mode,
+            prefix: self.prefix,
+            suffix: self.config.suffix,
+            loose_whitespace: self.config.loose_whitespace,
+        };
+        Matcher::new(&self.input, options)
+    }
+
+    fn prompt(&self) -> String {
+        let prefix = &self.prompt_prefix;
+        let status = self.status.unwrap_or_default();
+        // Matching works without it, but nothing's highlighted
+        let invalid = if self.matcher.highlight().is_none() {
+            "(invalid pattern) "
+        } else {
+            ""
+        };
+        // All of the rest is in the status bar then
+        if self.config.status {
+            return format!("{status}{invalid}{prefix}{}", self.input);
+        }
+        let partial = if self.partial { "(partial) " } else { "" };
+        let mode = match self.match_mode {
+            MatchMode::Substring => "",
+            MatchMode::Fuzzy => "(fuzzy) ",
+            MatchMode::Glob => "(glob) ",
+        };
+        let anchor = if self.prefix && self.match_mode == MatchMode::Substring {
+            "(prefix) "
+        } else {
+            ""
+        };
+        let case = match self.case_mode {
+            CaseMode::Smart => "",
+            CaseMode::Sensitive => "(case-sensitive) ",
+            CaseMode::Insensitive => "(ignore case) ",
+        };
+        let ordinal = self.ordinal();
+        format!(
+            "{status}{ordinal}{partial}{invalid}{mode}{anchor}{case}{prefix}{}",
+            self.input
+        )
+    }
+
+    /// The ordinal of the current match in the prompt, and how many there are once that's known.
+    fn ordinal(&self) -> String {
+        match (&self.current_entry, self.match_total()) {
+            (None, _) => String::new(),
+            (Some(_), None) => format!("[{}] ", self.earlier_matches.len() + 1),
+            (Some(_), Some(total)) => format!("[{}/{total}] ", self.earlier_matches.len() + 1),
+        }
+    }
+
+    /// Whether the query has less than half the matches of the previous one, to show in the
+    /// prompt unless `Config::no_count_drop` is set.
+    fn count_dropped(&self) -> bool {
+        !self.config.no_count_drop
+            && !self.config.status
+            && self.current_entry.is_some()
+            && self
+                .match_count
+                .is_some_and(|count| count * 2 < self.previous_count)
+    }
+
+    /// How many matches there are, once that's known or some were counted (see
+    /// `Config::count_matches`).
+    fn match_total(&self) -> Option<String> {
+        let known = self.earlier_matches.len() + usize::from(self.current_entry.is_some());
+        self.match_count.map(|count| count.to_string()).or_else(|| {
+            (self.counter.as_ref())
+                .filter(|counter| counter.count > 0)
+                .map(|counter| counter.label(known))
+        })
+    }
+
+    /// The text of the status bar (see `Config::status`), which shows what `prompt` shows
+    /// otherwise.
+    fn status_bar(&self) -> String {
+        let mut mode = match self.match_mode {
+            MatchMode::Substring => "substring",
+            MatchMode::Fuzzy => "fuzzy",
+            MatchMode::Glob => "glob",
+        }
+        .to_string();
+        if self.match_mode == MatchMode::Substring {
+            if self.prefix {
+                mode.push_str(", prefix");
+            }
+            if self.config.suffix {
+                mode.push_str(", suffix");
+            }
+        }
+        let case = match self.case_mode {
+            CaseMode::Smart if self.case_mode.is_sensitive(&self.input) => "smart case (sensitive)",
+            CaseMode::Smart => "smart case",
+            CaseMode::Sensitive => "case-sensitive",
+            CaseMode::Insensitive => "ignore case",
+        };
+        let ordinal = self.earlier_matches.len() + 1;
+        let partial = if self.partial { " (partial)" } else { "" };
+        let matches = match (&self.current_entry, self.match_total()) {
+            (None, _) => "no match".to_string(),
+            (Some(_), None) => format!("match {ordinal}"),
+            (Some(_), Some(total)) => format!("match {ordinal} of {total}{partial}"),
+        };
+        format!(" {mode} | {case} | {matches} ")
+    }
+}
+
+/// Returns how well `entry` matches, if it does. That's the best matching output with
+/// `Config::outputs`, otherwise the part of the command that's searched has to match. Commands
+/// in `deleted` never match.
+fn rank_entry(
+    entry: &Entry,
+    config: &Config,
+    matcher: &Matcher,
+    deleted: &HashSet<String>,
+) -> Option<f64> {
+    if deleted.contains(&entry.command) {
+        return None;
+    }
+    let command = match &config.command {
+        Some(name) => command_args(&entry.command, name)?,
+        None => entry.command.as_str(),
+    };
+    let command = if config.command_only {
+        command_name(command)
+    } else {
+        command
+    };
+    if config.outputs {
+        return entry
+            .outputs
+            .iter()
+            .filter_map(|path| matcher.score(path))
+            .reduce(f64::max);
+    }
+    let text = if config.expand_env {
+        Cow::Owned(Expanded::from_env(command, config.keep_undefined_env).text)
+    } else {
+        Cow::Borrowed(command)
+    };
+    matcher.score(&text)
+}
+
+/// Counts matches a few entries at a time, up to a limit, see `Config::count_matches`.
+#[derive(Debug, PartialEq)]
+struct MatchCounter {
+    /// Position of the next entry to count
+    position: usize,
+    count: usize,
+    limit: usize,
+    /// Whether the end of the history was reached
+    exhausted: bool,
+}
+
+impl MatchCounter {
+    fn new(position: usize, limit: usize) -> Self {
+        Self {
+            position,
+            count: 0,
+            limit,
+            exhausted: false,
+        }
+    }
+
+    /// Counts the entries after the ones counted so far that `matched`, stopping at the limit.
+    /// The history ends if there are fewer than `requested` entries.
+    fn add(&mut self, matched: impl Iterator<Item = bool>, requested: usize) {
+        let mut counted = 0;
+        for matched in matched.take(requested) {
+            counted += 1;
+            self.count += usize::from(matched);
+            if self.count >= self.limit {
+                break;
+            }
+        }
+        self.position += counted;
+        self.exhausted = counted < requested && self.count < self.limit;
+    }
+
+    /// Whether there's nothing left to count.
+    fn is_done(&self) -> bool {
+        self.exhausted || self.count >= self.limit
+    }
+
+    /// The number of matches as shown, given that there are at least `known` matches. Unless
+    /// all were counted, there are more.
+    fn label(&self, known: usize) -> String {
+        if self.exhausted {
+            self.count.to_string()
+        } else {
+            format!("{}+", self.count.max(known))
+        }
+    }
+}
+
+/// Whether `key`, bound to `action`, only changes the query, i.e. can be handled without the
+/// query's matches.
+fn edits_query(key: Key, action: Option<Action>) -> bool {
+    match action {
+        Some(
+            Action::DeleteChar | Action::DeleteWord | Action::DeleteWhitespaceWord | Action::Clear,
+        ) => true,
+        Some(_) => false,
+        None => matches!(key, Key::Char(ch) if ch != '\n' && ch != '\t'),
+    }
+}
+
+/// How matches are highlighted.
+#[derive(Clone, Copy)]
+struct HighlightStyle {
+    /// Whether highlights directly following another one are underlined
+    separate: bool,
+    /// Whether highlights are colored, otherwise they are only inverted and bold
+    color: bool,
+    /// The index of the span with the current match, which is brighter (or underlined without
+    /// color) than the others
+    current: Option<usize>,
+    /// Whether the whole line is inverted, and inverted highlights aren't
+    inverted: bool,
+    highlight: Highlight,
+}
+
+/// Prints `line` with `spans` highlighted. With `separate`, highlights that directly follow
+/// another highlight are underlined, so they don't look like a single match.
+fn print_line(
+    prefix: &str,
+    line: &str,
+    spans: &[Range<usize>],
+    style: HighlightStyle,
+    stdout: &mut impl Write,
+) {
+    let _ = write!(stdout, "\r\n{prefix}");
+    // Reapplied after every highlight, which resets all styles
+    let base = if style.inverted {
+        termion::style::Invert.to_string()
+    } else {
+        String::new()
+    };
+    let _ = write!(stdout, "{base}");
+    let highlight = style.highlight;
+    let highlight_fg = highlight.fg.filter(|_| style.color);
+    let mut last_end = 0;
+    let mut highlighted = false;
+    // Zero-width matches (e.g. for an empty query) would only produce stray escape codes
+    for (i, span) in spans
+        .iter()
+        .enumerate()
+        .filter(|(_, span)| !span.is_empty())
+    {
+        let adjacent = style.separate && highlighted && span.start == last_end;
+        let current = style.current == Some(i);
+        // The current match gets the light variant of the color, or is underlined without one
+        let fg = highlight_fg.map(|fg| if current && fg < 8 { fg + 8 } else { fg });
+        let underline = highlight.underline || adjacent || (current && fg == highlight_fg);
+        let _ = write!(
+            stdout,
+            "{}{}{}{}{}{}{}{base}",
+            &line[last_end..span.start],
+            fg.map(|fg| termion::color::Fg(termion::color::AnsiValue(fg)).to_string())
+                .unwrap_or_default(),
+            if highlight.invert != style.inverted {
+                termion::style::Invert.to_string()
+            } else {
+                String::new()
+            },
+            if highlight.bold {
+                termion::style::Bold.to_string()
+            } else {
+                String::new()
+            },
+            if underline {
+                termion::style::Underline.to_string()
+            } else {
+                String::new()
+            },
+            &line[span.clone()],
+            termion::style::Reset
+        );
+        last_end = span.end;
+        highlighted = true;
+    }
+    let _ = write!(stdout, "{}", &line[last_end..]);
+    if style.inverted {
+        let _ = write!(stdout, "{}", termion::style::Reset);
+    }
+}
+
+/// Like `print_line`, but prints `line` in the given rows (see `wrap_line`) instead of leaving
+/// wrapping to the terminal, so continuation rows can be indented by `indent` columns. The first
+/// row starts with `prefix`.
+fn print_wrapped_line(
+    prefix: &str,
+    line: &str,
+    rows: &[Range<usize>],
+    spans: &[Range<usize>],
+    style: HighlightStyle,
+    indent: usize,
+    stdout: &mut impl Write,
+) {
+    let indent = " ".repeat(indent);
+    for (i, row) in rows.iter().enumerate() {
+        let mut row_spans = Vec::new();
+        let mut current = None;
+        for (j, span) in spans.iter().enumerate() {
+            let start = span.start.max(row.start);
+            let end = span.end.min(row.end);
+            if start < end {
+                if style.current == Some(j) {
+                    current = Some(row_spans.len());
+                }
+                row_spans.push(start - row.start..end - row.start);
+            }
+        }
+        let prefix = if i == 0 { prefix } else { &indent };
+        let style = HighlightStyle { current, ..style };
+        print_line(prefix, &line[row.clone()], &row_spans, style, stdout);
+    }
+}
+
+/// Returns the index of the first of `rows` listed matches, so the list scrolls as little as
+/// possible from `start` to show the match at `active`.
+fn list_window(start: usize, active: usize, rows: usize) -> usize {
+    if active < start {
+        active
+    } else if active >= start + rows {
+        active + 1 - rows
+    } else {
+        start
+    }
+}
+
+/// Returns the byte range of `line` to show in `columns` columns when long lines are truncated,
+/// leaving room for an ellipsis wherever the line is cut off. If `focus` (a byte range in `line`)
+/// is past the cutoff, the range is scrolled to end with it.
+fn truncate_line(
+    line: &str,
+    focus: Option<Range<usize>>,
+    columns: usize,
+    ambiguous_wide: bool,
+) -> Range<usize> {
+    let width = |text: &str| text_width(text, ambiguous_wide);
+    if width(line) <= columns {
+        return 0..line.len();
+    }
+    let ellipsis = width("…");
+    let focus = focus.unwrap_or(0..0);
+    let mut start = 0;
+    if width(&line[..focus.end]) + ellipsis > columns {
+        // Scrolled as little as possible, with an ellipsis on each side that's cut off
+        let trailing = if focus.end < line.len() { ellipsis } else { 0 };
+        let available = columns.saturating_sub(ellipsis + trailing);
+        start = focus.start;
+        let mut used = width(&line[focus.clone()]);
+        for (pos, ch) in line[..focus.start].char_indices().rev() {
+            used += width(ch.encode_utf8(&mut [0; 4]));
+            if used > available {
+                break;
+            }
+            start = pos;
+        }
+    }
+    let lead = if start > 0 { ellipsis } else { 0 };
+    if lead + width(&line[start..]) <= columns {
+        return start..line.len();
+    }
+    let available = columns.saturating_sub(lead + ellipsis);
+    let mut end = start;
+    let mut used = 0;
+    for (pos, ch) in line[start..].char_indices() {
+        used += width(ch.encode_utf8(&mut [0; 4]));
+        // At least one character, even if the terminal is too narrow
+        if used > available && end > start {
+            break;
+        }
+        end = start + pos + ch.len_utf8();
+    }
+    start..end
+}
+
+/// Returns the byte range of `query` to show in `columns` columns when the prompt is kept on one
+/// row, including the `cursor` (a byte offset). A query that's too long is scrolled to end at the
+/// cursor, like `truncate_line` does for the current match.
+fn query_window(query: &str, cursor: usize, columns: usize, ambiguous_wide: bool) -> Range<usize> {
+    truncate_line(query, Some(cursor..cursor), columns, ambiguous_wide)
+}
+
+/// Returns the first lines of `lines` that fit into `height` rows (but at least one line) and
+/// how many lines don't fit.
+fn clamp_lines<T>(lines: &[T], height: usize) -> (&[T], usize) {
+    let visible = lines.len().min(height.max(1));
+    (&lines[..visible], lines.len() - visible)
+}
+
+/// Returns the number of columns `text` occupies, with characters of ambiguous width (in East
+/// Asian contexts) being either narrow or wide.
+fn text_width(text: &str, ambiguous_wide: bool) -> usize {
+    let version = UnicodeVersion {
+        version: LATEST_UNICODE_VERSION.version,
+        ambiguous_are_wide: ambiguous_wide,
+    };
+    unicode_column_width(text, Some(version))
+}
+
+/// Splits `text` into lines like `str::lines`, with the byte offset of each line in `text`.
+fn split_lines(text: &str) -> Vec<(usize, &str)> {
+    let mut offset = 0;
+    text.split_inclusive('\n')
+        .map(|line| {
+            let start = offset;
+            offset += line.len();
+            let line = line
+                .strip_suffix('\n')
+                .map_or(line, |line| line.strip_suffix('\r').unwrap_or(line));
+            (start, line)
+        })
+        .collect()
+}
+
+/// Returns the parts of `spans` (byte ranges in a text) that are on `line`, which starts at byte
+/// `start` of that text, as byte ranges in `line`.
+fn spans_on_line(spans: &[Range<usize>], start: usize, line: &str) -> Vec<Range<usize>> {
+    let end = start + line.len();
+    spans
+        .iter()
+        .filter(|span| span.start < end && start < span.end)
+        .map(|span| span.start.max(start) - start..span.end.min(end) - start)
+        .collect()
+}
+
+/// Returns the row (counting from 0) and column the terminal ends up in when printing `text` at
+/// column `start` with rows of `columns` columns. Like in terminals, a wide character that
+/// doesn't fit into the rest of a row goes to the next one, leaving a blank column, while
+/// zero-width characters like combining accents never start a new row. The column is `columns`
+/// after filling a row, as the terminal only moves on with the next character.
+fn wrap_position(text: &str, start: usize, columns: usize, ambiguous_wide: bool) -> (usize, usize) {
+    let (mut row, mut column) = (0, start);
+    for ch in text.chars() {
+        let width = text_width(ch.encode_utf8(&mut [0; 4]), ambiguous_wide);
+        if column + width > columns && column > 0 {
+            row += 1;
+            column = 0;
+        }
+        column += width;
+    }
+    (row, column)
+}
+
+/// Splits `line` into rows of at most `columns` columns, where the first row is indented by
+/// `first_indent` columns and all others by `indent` columns. Returns the byte range of each row.
+fn wrap_line(
+    line: &str,
+    columns: usize,
+    first_indent: usize,
+    indent: usize,
+    ambiguous_wide: bool,
+) -> Vec<Range<usize>> {
+    let mut rows = Vec::new();
+    let mut start = 0;
+    let mut width = 0;
+    let mut available = columns.saturating_sub(first_indent).max(1);
+    for (pos, ch) in line.char_indices() {
+        let ch_width = text_width(ch.encode_utf8(&mut [0; 4]), ambiguous_wide);
+        if width + ch_width > available && pos > start {
+            rows.push(start..pos);
+            start = pos;
+            width = 0;
+            available = columns.saturating_sub(indent).max(1);
+        }
+        width += ch_width;
+    }
+    rows.push(start..line.len());
+    rows
+}
+
+/// Reads the history from `reader` as configured, most recent first unless `Config::reverse`
+/// is set. Only reads it upfront where the order needs all of it.
+fn read_history(
+    reader: impl BufRead + 'static,
+    config: &mut Config,
+) -> Box<dyn Iterator<Item = Entry>> {
+    let input_format = config.input_format;
+    let history = HistoryIter::from_reader(reader, input_format.separator());
+    let history = if input_format == InputFormat::Zsh {
+        history.with_continuations()
+    } else {
+        history
+    };
+    // Most recent first, so this keeps the part of huge histories that matters most
+    let history = history.take(config.max_entries.unwrap_or(usize::MAX));
+    // The newest entries are last when reversed, so they're left out instead of being skipped
+    let skip_newest = if config.reverse {
+        std::mem::take(&mut config.skip_newest)
+    } else {
+        0
+    };
+    history_entries(history, config, skip_newest)
+}
+
+/// Turns history `records`, most recent first, into entries as configured. With
+/// `Config::reverse`, the `skip_newest` most recent entries are left out.
+fn history_entries(
+    records: impl Iterator<Item = String> + 'static,
+    config: &Config,
+    skip_newest: usize,
+) -> Box<dyn Iterator<Item = Entry>> {
+    let metadata = config.metadata || config.outputs;
+    let skip_comments = config.skip_comments;
+    let input_format = config.input_format;
+    let history = records
+        .map(move |record| parse_record(record, input_format, metadata))
+        .filter(move |entry| !(skip_comments && entry.is_blank_or_comment()));
+    let history = FoldRepeats::new(history, config.fold_repeats);
+    let history: Box<dyn Iterator<Item = Entry>> = match config.sort {
+        HistorySort::Recency => Box::new(history),
+        HistorySort::Frequency => Box::new(sort_by_frequency(history).into_iter()),
+    };
+    let history = Dedup::new(history, !config.keep_duplicates);
+    // Duplicates are removed first, so the most recent entry of each command is kept
+    if config.reverse {
+        Box::new(oldest_first(history, skip_newest).into_iter())
+    } else {
+        Box::new(history)
+    }
+}
+
+/// Returns the entry the interactive search shows first for `query`, without the terminal.
+fn first_match(
+    history: impl Iterator<Item = Entry>,
+    config: &Config,
+    query: &str,
+) -> Option<Entry> {
+    let options = MatchOptions {
+        mode: config.match_mode,
+        case: config.case,
+        prefix: config.prefix,
+        suffix: config.suffix,
+        loose_whitespace: config.loose_whitespace,
+    };
+    let matcher = Matcher::new(query, options);
+    let rank = |entry: &Entry| rank_entry(entry, config, &matcher, &HashSet::new());
+    let history = history.skip(config.skip_newest);
+    if config.cycle_order == CycleOrder::Recent {
+        let matches: Vec<_> = history
+            .take(config.max_scan.unwrap_or(DEFAULT_MAX_SCAN))
+            .enumerate()
+            .filter_map(|(i, entry)| Some((rank(&entry)?, i, entry)))
+            .collect();
+        return sort_matches(matches)
+            .into_iter()
+            .next()
+            .map(|(_, entry)| entry);
+    }
+    // Like the interactive search, a preferred match wins over any earlier one
+    let mut fallback = None;
+    for entry in history {
+        match rank(&entry) {
+            Some(score) if score >= score::PREFERRED => return Some(entry),
+            Some(_) if fallback.is_none() => fallback = Some(entry),
+            _ => (),
+        }
+    }
+    fallback
+}
+
+/// Explains why the TTY couldn't be opened, with a hint if standard output isn't a terminal
+/// either, i.e. this doesn't run interactively at all.
+fn tty_error(err: &std::io::Error, stdout_is_tty: bool) -> String {
+    let reason = if err.raw_os_error() == Some(libc::ENXIO) {
+        " (there's no controlling terminal)"
+    } else if err.kind() == ErrorKind::PermissionDenied {
+        " (no permission to use it)"
+    } else {
+        ""
+    };
+    let hint = if stdout_is_tty {
+        ""
+    } else {
+        ". The search needs a terminal, use --first with --query to print the first match instead"
+    };
+    format!("Could not open TTY{reason}: {err}{hint}")
+}
+
+/// How many entries are looked at for `Config::count_matches` before checking for input again.
+const COUNT_BUDGET: usize = 5000;
+
+/// How long to wait for the terminal to report the cursor position.
+const CURSOR_ROW_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// How long the selected entry is shown with `--echo-selection`.
+const ECHO_DURATION: Duration = Duration::from_millis(400);
+
+/// Handles input from `events`, which reads from `tty`, until the search ends. Closing the TTY
+/// ends it like quitting.
+fn run_search<I: Iterator<Item = Entry>, W: Write + TerminalSize>(
+    prompt: &mut Prompt<I, W>,
+    events: &mut tty::EventReader,
+    tty: &File,
+) -> PromptResult {
+    loop {
+        // Matches are counted in between events, a few entries at a time so keys are still
+        // handled right away
+        let input = if prompt.count_matches(COUNT_BUDGET) {
+            match events.next_timeout(Duration::ZERO) {
+                Ok(input) => input,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return PromptResult::Quit,
+            }
+        } else {
+            match events.next() {
+                Some(input) => input,
+                None => return PromptResult::Quit,
+            }
+        };
+        let event = match input {
+            tty::Input::Event(Ok(event)) => event,
+            tty::Input::Event(Err(_)) => continue,
+            tty::Input::Resize => {
+                prompt.handle_terminal_size_change();
+                continue;
+            }
+        };
+        // Handle all input that's available already at once, so e.g. fast typing or pasting
+        // (without bracketed paste) only searches once
+        prompt.start_batch();
+        let mut result = prompt.handle_event(event);
+        while result == PromptResult::Incomplete && tty::input_pending(tty) {
+            match events.next() {
+                Some(tty::Input::Event(Ok(event))) => result = prompt.handle_event(event),
+                Some(tty::Input::Event(Err(_))) => (),
+                Some(tty::Input::Resize) => prompt.handle_terminal_size_change(),
+                None => result = PromptResult::Quit,
+            }
+        }
+        prompt.finish_batch();
+        if result != PromptResult::Incomplete {
+            return result;
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let mut config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("[FATAL] {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if config.help {
+        print!("{}", config::USAGE);
+        return ExitCode::SUCCESS;
+    }
+    if config.version {
+        println!("kontrolleurs {}", env!("CARGO_PKG_VERSION"));
+        return ExitCode::SUCCESS;
+    }
+    // See https://no-color.org
+    if std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty()) {
+        config.no_color = true;
+    }
+    if config.prompt.is_none() {
+        config.prompt = std::env::var(PROMPT_VAR).ok();
+    }
+    if let Ok(highlight) = std::env::var(HIGHLIGHT_VAR) {
+        config.highlight = Highlight::parse(&highlight);
+    }
+    // Only a --keys file has to exist
+    let explicit_keys = config.keys.is_some();
+    if let Some(path) = config.keys.take().or_else(keys::default_path) {
+        match std::fs::read_to_string(&path) {
+            // On top of --accept-keys
+            Ok(text) => match config.bindings.apply(&text) {
+                Ok(()) => (),
+                Err(err) => {
+                    eprintln!("[FATAL] Invalid key bindings in {}: {err}", path.display());
+                    return ExitCode::FAILURE;
+                }
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound && !explicit_keys => {}
+            Err(err) => {
+                eprintln!(
+                    "[FATAL] Could not read key bindings {}: {err}",
+                    path.display()
+                );
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let metrics = config
+        .metrics_file
+        .take()
+        .map(|path| (path, Instant::now(), metrics::mode(&config)));
+    let history_path = config.history.take();
+    let reader: Box<dyn BufRead> = match &history_path {
+        Some(path) => match File::open(path) {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(err) => {
+                eprintln!("[FATAL] Could not open history {}: {err}", path.display());
+                return ExitCode::FAILURE;
+            }
+        },
+        None => Box::new(BufReader::new(std::io::stdin())),
+    };
+    // Also needed to delete from the history on exit
+    let metadata = config.metadata || config.outputs;
+    let input_format = config.input_format;
+    let history = read_history(reader, &mut config);
+    if config.first {
+        // Neither the terminal nor any of the interactive options are needed then
+        let query = config.query.take().unwrap_or_default();
+        return match first_match(history, &config, &query) {
+            Some(entry) => {
+                println!("{}", entry.command);
+                ExitCode::SUCCESS
+            }
+            None => PromptResult::NoMatch.exit_code().into(),
+        };
+    }
+    let tty = File::open("/dev/tty").and_then(|stdin| Ok((stdin, File::create("/dev/tty")?)));
+    let (stdin, stdout) = match tty {
+        Ok(tty) => tty,
+        Err(err) => {
+            eprintln!(
+                "[FATAL] {}",
+                tty_error(&err, std::io::stdout().is_terminal())
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+    // Best effort, a panic or SIGTERM (SIGINT, SIGHUP e.g. when the terminal window is closed)
+    // just leaves the terminal in raw mode otherwise
+    let _ = tty::restore_on_panic(Path::new("/dev/tty"));
+    let _ = tty::restore_on_signals(Path::new("/dev/tty"), &[SIGTERM, SIGINT, SIGHUP]);
+    // Without it, pasting works too, just slower
+    let paste_mode = stdout
+        .try_clone()
+        .and_then(tty::BracketedPaste::enable)
+        .ok();
+    let mut stdout = stdout.into_raw_mode().unwrap();
+    // Clicks are found relative to the prompt, so they're only reported if its row is known
+    let prompt_row = (config.list.is_some())
+        .then(|| tty::cursor_row(&stdin, &mut *stdout, CURSOR_ROW_TIMEOUT).ok())
+        .flatten();
+    let mouse = prompt_row.and_then(|_| {
+        stdout
+            .try_clone()
+            .and_then(tty::MouseReporting::enable)
+            .ok()
+    });
+    let echo_selection = config.echo_selection;
+    // The socket always gets JSON, and so does stdout if the socket isn't available
+    let format = if config.socket.is_some() {
+        OutputFormat::Json
+    } else {
+        config.format
+    };
+    let socket = config.socket.take();
+    let background_suffix = config
+        .background_suffix
+        .take()
+        .unwrap_or_else(|| DEFAULT_BACKGROUND_SUFFIX.to_string());
+    let last_query_path = state::last_query_path().filter(|_| config.resume);
+    let query = config
+        .query
+        .take()
+        .or_else(|| last_query_path.as_deref().and_then(state::load_query));
+    let commandline = config.commandline.take();
+    let mut prompt = Prompt::new(stdout, history, config).unwrap();
+    if let Some(row) = prompt_row {
+        prompt.set_prompt_row(row.into());
+    }
+    // Without a query, this shows the most recent entry right away
+    prompt.set_input(query.unwrap_or_default());
+
+    let mut selection = None;
+    let mut outcome = "quit";
+    let mut events = match stdin.try_clone() {
+        Ok(stdin) => tty::EventReader::new(stdin),
+        Err(err) => {
+            // The TTY is open already, so this isn't about opening it
+            eprintln!("[FATAL] Could not read from the terminal: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    // Redraws right away, also while waiting for a key
+    events
+        .watch_resize()
+        .expect("Registering signal handler should work");
+    let result = run_search(&mut prompt, &mut events, &stdin);
+    let exit_code = result.exit_code();
+    match result {
+        PromptResult::Incomplete | PromptResult::Quit => (),
+        PromptResult::Selected(entry, execute, cursor_pos) => {
+            outcome = match execute {
+                Execute::No => "selected",
+                Execute::Yes => "executed",
+                Execute::Background => "background",
+            };
+            if echo_selection {
+                prompt.echo(&entry, ECHO_DURATION);
+            }
+            selection = Some((entry, execute, cursor_pos, prompt.selected_match()));
+        }
+        PromptResult::Copied => outcome = "copied",
+        PromptResult::NoMatch => outcome = "no-match",
+    }
+    let deleted = prompt.take_deleted();
+    if let Some(path) = &last_query_path {
+        if let Err(err) = state::save_query(path, prompt.query()) {
+            eprintln!("[WARN] Could not save query {}: {err}", path.display());
+        }
+    }
+    drop(prompt);
+    drop(paste_mode);
+    drop(mouse);
+    if let Some(path) = history_path.filter(|_| !deleted.is_empty()) {
+        if let Err(err) = delete_from_history(&path, input_format, metadata, &deleted) {
+            eprintln!(
+                "[WARN] Could not delete from history {}: {err}",
+                path.display()
+            );
+        }
+    }
+    if let Some((path, launched, mode)) = metrics {
+        // Metrics are best effort and must never get in the way of the selection
+        let _ = metrics::record(&path, launched.elapsed(), outcome, &mode);
+    }
+
+    // Hand the original command line back on quit, so it can be restored
+    let selection = selection.or_else(|| {
+        commandline.map(|commandline| {
+            let cursor_pos = commandline.chars().count();
+            (commandline, Execute::No, cursor_pos, None)
+        })
+    });
+    if let Some((entry, execute, cursor_pos, matched)) = selection {
+        let mut out = output::connect_or_stdout(socket.as_deref());
+        let _ = output::write_selection(
+            &mut out,
+            format,
+            &entry,
+            execute,
+            cursor_pos,
+            matched,
+            &background_suffix,
+        );
+    }
+
+    ExitCode::from(exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, io::ErrorKind, time::Duration};
+
+    use termion::event::{Event, Key, MouseButton, MouseEvent};
+
+    use kontrolleurs::{
+        config::{Highlight, InputFormat, DEFAULT_HIGHLIGHT},
+        keys::Bindings,
+        MatchOptions, Matcher,
+    };
+
+    use super::{
+        adjust_cursor, clamp_lines, command_args, command_name, command_name_range,
+        delete_whitespace_word, delete_word, first_match, list_window, next_word_end, oldest_first,
+        previous_word_start, print_line, print_wrapped_line, query_window, read_history,
+        remove_records, run_search, sort_by_frequency, sort_matches, spans_on_line, split_lines,
+        text_width, truncate_line, tty_error, wrap_line, wrap_position, Config, CycleOrder, Dedup,
+        Entry, Execute, FoldRepeats, HighlightStyle, MatchCounter, MatchMode, Prompt, PromptResult,
+        PromptWrap, ReusableIter,
+    };
+    use crate::{terminal_size::TestTerminal, tty};
+
+    const COLORED: HighlightStyle = HighlightStyle {
+        separate: false,
+        color: true,
+        current: None,
+        inverted: false,
+        highlight: DEFAULT_HIGHLIGHT,
+    };
+
+    fn test_prompt(
+        history: &[&str],
+        columns: u16,
+    ) -> Prompt<std::vec::IntoIter<Entry>, TestTerminal> {
+        test_prompt_with_config(history, columns, Config::default())
+    }
+
+    fn test_prompt_with_config(
+        history: &[&str],
+        columns: u16,
+        config: Config,
+    ) -> Prompt<std::vec::IntoIter<Entry>, TestTerminal> {
+        let history: Vec<_> = history.iter().map(|s| Entry::from(s.to_string())).collect();
+        Prompt::new(TestTerminal::new(columns, 24), history.into_iter(), config).unwrap()
+    }
+
+    #[test]
+    fn test_reusable_iter_read_to() {
+        let mut iter = ReusableIter::new(["spam", "eggs", "ham"].into_iter());
+        assert_eq!(iter.next(), Some("spam"));
+        assert_eq!(iter.read_to(2), 2);
+        assert_eq!(iter.read_to(5), 3);
+        // Read ahead, but not returned yet
+        assert_eq!(iter.position(), 1);
+        assert_eq!(iter.next(), Some("eggs"));
+        assert_eq!(iter.get(2), Some("ham"));
+    }
+
+    #[test]
+    fn test_reusable_iter_push() {
+        let mut iter = ReusableIter::new(["spam"].into_iter());
+        assert_eq!(iter.next(), Some("spam"));
+        assert_eq!(iter.next(), None);
+        iter.push(["eggs", "ham"]);
+        assert_eq!(iter.next(), Some("eggs"));
+        iter.reset();
+        assert_eq!(iter.get(2), Some("ham"));
+        assert_eq!(
+            iter.by_ref().collect::<Vec<_>>(),
+            vec!["spam", "eggs", "ham"]
+        );
+        iter.push(["bacon"]);
+        iter.reset_to(1);
+        assert_eq!(iter.get(3), Some("bacon"));
+        assert_eq!(iter.collect::<Vec<_>>(), vec!["eggs", "ham", "bacon"]);
+    }
+
+    #[test]
+    fn test_push_entries() {
+        let records = |records: &[&str]| {
+            let records: Vec<_> = records.iter().map(|record| record.to_string()).collect();
+            records.into_iter()
+        };
+        let config = Config {
+            fold_repeats: true,
+            ..Config::default()
+        };
+        let mut prompt = test_prompt_with_config(&["ls", "make"], 80, config);
+        prompt.set_input("make".to_string());
+        prompt.handle_key_press(Key::Ctrl('r'));
+        assert_eq!(prompt.current_entry, None);
+        // Read like the rest of the history
+        prompt.push_entries(records(&["make all", "make all", "make", "make test"]));
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "make");
+        prompt.handle_key_press(Key::Ctrl('r'));
+        let entry = prompt.current_entry.clone().unwrap();
+        assert_eq!((entry.command.as_str(), entry.repeats), ("make all", 1));
+        // The current match stays
+        prompt.push_entries(records(&["make check"]));
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "make all");
+        prompt.handle_key_press(Key::Ctrl('r'));
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "make test");
+        prompt.handle_key_press(Key::Ctrl('r'));
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "make check");
+    }
+
+    #[test]
+    fn test_match_counter() {
+        let mut counter = MatchCounter::new(2, 3);
+        counter.add([true, false].into_iter(), 2);
+        assert_eq!((counter.position, counter.count), (4, 1));
+        assert!(!counter.is_done());
+        assert_eq!(counter.label(1), "1+");
+        // At least as many as were shown already
+        assert_eq!(counter.label(2), "2+");
+        // Stops at the limit
+        counter.add([true, true, true].into_iter(), 5);
+        assert_eq!((counter.position, counter.count), (6, 3));
+        assert!(counter.is_done());
+        assert_eq!(counter.label(1), "3+");
+
+        // All matches counted
+        let mut counter = MatchCounter::new(0, 3);
+        counter.add([false, true].into_iter(), 5);
+        assert!(counter.is_done());
+        assert_eq!(counter.label(1), "1");
+    }
+
+    #[test]
+    fn test_count_matches() {
+        let config = Config {
+            count_matches: Some(3),
+            ..Config::default()
+        };
+        let history = ["git push", "ls", "git pull", "git log", "git am", "git mv"];
+        let mut prompt = test_prompt_with_config(&history, 80, config);
+        prompt.set_input("git".to_string());
+        assert_eq!(prompt.prompt(), "[1] bck-i-search: git");
+        assert!(prompt.count_matches(2));
+        assert_eq!(prompt.prompt(), "[1/1+] bck-i-search: git");
+        assert!(!prompt.count_matches(2));
+        assert_eq!(prompt.prompt(), "[1/3+] bck-i-search: git");
+        // Counting starts over for another query, and stops at the end of the history
+        prompt.set_input("git p".to_string());
+        assert!(!prompt.count_matches(10));
+        assert_eq!(prompt.prompt(), "[1/2] bck-i-search: git p");
+        assert_eq!(prompt.match_count, Some(2));
+    }
+
+    #[test]
+    fn test_count_drop() {
+        let history = ["git push", "ls", "git pull", "git log", "git am", "git mv"];
+        let config = Config {
+            count_matches: Some(10),
+            ..Config::default()
+        };
+        let mut prompt = test_prompt_with_config(&history, 80, config);
+        prompt.set_input("git".to_string());
+        prompt.count_matches(10);
+        assert!(!prompt.count_dropped());
+        // From 5 matches to 2
+        prompt.set_input("git p".to_string());
+        prompt.count_matches(10);
+        assert!(prompt.count_dropped());
+        prompt.redraw();
+        assert!(prompt.stdout.take_output().contains(&format!(
+            "{}[1/2]{} bck-i-search: git p",
+            termion::style::Faint,
+            termion::style::Reset
+        )));
+        // Not by more than half
+        prompt.set_input("git pu".to_string());
+        prompt.count_matches(10);
+        assert!(!prompt.count_dropped());
+
+        let config = Config {
+            count_matches: Some(10),
+            no_count_drop: true,
+            ..Config::default()
+        };
+        let mut prompt = test_prompt_with_config(&history, 80, config);
+        prompt.set_input("git".to_string());
+        prompt.count_matches(10);
+        prompt.set_input("git p".to_string());
+        prompt.count_matches(10);
+        assert!(!prompt.count_dropped());
+        prompt.redraw();
+        assert!(!prompt
+            .stdout
+            .take_output()
+            .contains(&termion::style::Faint.to_string()));
+    }
+
+    #[test]
+    fn test_reusable_iter() {
+        let mut iter = ReusableIter::new(["spam", "eggs"].iter());
+        assert_eq!(iter.next(), Some("spam").as_ref());
+        assert_eq!(iter.next(), Some("eggs").as_ref());
+        assert_eq!(iter.next(), None);
+
+        iter.reset();
+        assert_eq!(iter.next(), Some("spam").as_ref());
+
+        iter.reset();
+        assert_eq!(vec![&"spam", &"eggs"], iter.collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_fold_repeats() {
+        let entries =
+            ["ls", "ls", "ls", "make", "ls"].map(|command| Entry::from(command.to_string()));
+        let folded: Vec<_> = FoldRepeats::new(entries.clone().into_iter(), true)
+            .map(|entry| (entry.command, entry.repeats))
+            .collect();
+        assert_eq!(
+            folded,
+            vec![
+                ("ls".to_string(), 2),
+                ("make".to_string(), 0),
+                ("ls".to_string(), 0)
+            ]
+        );
+        assert_eq!(FoldRepeats::new(entries.into_iter(), false).count(), 5);
+    }
+
+    #[test]
+    fn test_dedup() {
+        let entries = ["ls", "ls", "cd /tmp", "git status", "ls", "cd /tmp", "make"]
+            .map(|command| Entry::from(command.to_string()));
+        let deduped: Vec<_> = Dedup::new(entries.clone().into_iter(), true)
+            .map(|entry| entry.command)
+            .collect();
+        assert_eq!(deduped, vec!["ls", "cd /tmp", "git status", "make"]);
+        assert_eq!(Dedup::new(entries.into_iter(), false).count(), 7);
+    }
+
+    #[test]
+    fn test_dedup_keeps_folded_repeats() {
+        let entries = ["ls", "ls", "cd", "ls"].map(|command| Entry::from(command.to_string()));
+        let deduped: Vec<_> = Dedup::new(FoldRepeats::new(entries.into_iter(), true), true)
+            .map(|entry| (entry.command, entry.repeats))
+            .collect();
+        assert_eq!(deduped, vec![("ls".to_string(), 1), ("cd".to_string(), 0)]);
+    }
+
+    #[test]
+    fn test_redraw_shows_repeats() {
+        let history = FoldRepeats::new(
+            ["make", "make"]
+                .map(|command| Entry::from(command.to_string()))
+                .into_iter(),
+            true,
+        );
+        let mut prompt =
+            Prompt::new(TestTerminal::new(80, 24), history, Config::default()).unwrap();
+        prompt.set_input("make".to_string());
+        assert!(prompt.stdout.take_output().contains(" (x2)"));
+        prompt.update();
+        assert_eq!(prompt.current_entry, None);
+    }
+
+    #[test]
+    fn test_reusable_iter_reset_to() {
+        let mut iter = ReusableIter::new(["spam", "eggs", "ham"].into_iter());
+        iter.reset_to(2);
+        assert_eq!(iter.position(), 2);
+        assert_eq!(iter.next(), Some("ham"));
+        iter.reset_to(5);
+        assert_eq!(iter.next(), None);
+        iter.reset_to(0);
+        assert_eq!(iter.next(), Some("spam"));
+    }
+
+    #[test]
+    fn test_reusable_iter_steps_backward() {
+        let mut iter = ReusableIter::new(1..=3);
+        assert_eq!(iter.next(), Some(1));
+        assert_eq!(iter.next(), Some(2));
+        // One step back
+        iter.reset_to(1);
+        assert_eq!(iter.position(), 1);
+        assert_eq!(iter.next(), Some(2));
+        assert_eq!(iter.next(), Some(3));
+        assert_eq!(iter.next(), None);
+        // Back from the end, then forward again
+        iter.reset_to(2);
+        assert_eq!(iter.get(1), Some(2));
+        assert_eq!(iter.next(), Some(3));
+        iter.reset_to(0);
+        assert_eq!(iter.collect::<Vec<_>>(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_previous_match() {
+        let mut prompt = test_prompt(&["ls -a", "cd", "ls -l", "ls"], 80);
+        prompt.set_input("ls".to_string());
+        let current =
+            |prompt: &Prompt<_, _>| prompt.current_entry.as_ref().unwrap().command.clone();
+        // Already at the first match
+        prompt.stdout.take_output();
+        prompt.handle_key_press(Key::Ctrl('s'));
+        assert_eq!(prompt.stdout.take_output(), "\x07");
+        assert_eq!(current(&prompt), "ls -a");
+        prompt.handle_key_press(Key::Ctrl('r'));
+        prompt.handle_key_press(Key::Ctrl('r'));
+        assert_eq!(current(&prompt), "ls");
+        prompt.handle_key_press(Key::Up);
+        assert_eq!(current(&prompt), "ls -l");
+        prompt.handle_key_press(Key::Ctrl('r'));
+        assert_eq!(current(&prompt), "ls");
+        // Past the last match
+        prompt.handle_key_press(Key::Ctrl('r'));
+        assert_eq!(prompt.current_entry, None);
+        prompt.handle_key_press(Key::Ctrl('s'));
+        assert_eq!(current(&prompt), "ls");
+        assert_eq!(prompt.current_position, 3);
+    }
+
+    #[test]
+    fn test_previous_match_sorted() {
+        let config = Config {
+            cycle_order: CycleOrder::Recent,
+            ..Config::default()
+        };
+        let mut prompt = test_prompt_with_config(&["git pull", "pull", "git push"], 80, config);
+        prompt.set_input("pu".to_string());
+        let current =
+            |prompt: &Prompt<_, _>| prompt.current_entry.as_ref().unwrap().command.clone();
+        assert_eq!(current(&prompt), "git pull");
+        prompt.handle_key_press(Key::Ctrl('r'));
+        prompt.handle_key_press(Key::Ctrl('r'));
+        assert_eq!(current(&prompt), "git push");
+        prompt.handle_key_press(Key::Ctrl('s'));
+        assert_eq!(current(&prompt), "pull");
+        prompt.handle_key_press(Key::Ctrl('s'));
+        assert_eq!(current(&prompt), "git pull");
+        prompt.handle_key_press(Key::Ctrl('r'));
+        assert_eq!(current(&prompt), "pull");
+    }
+
+    #[test]
+    fn test_skip_newest() {
+        for cycle_order in [CycleOrder::History, CycleOrder::Recent] {
+            let config = Config {
+                skip_newest: 1,
+                cycle_order,
+                ..Config::default()
+            };
+            let mut prompt = test_prompt_with_config(&["ls -a", "ls -b", "ls -c"], 80, config);
+            prompt.set_input("ls".to_string());
+            assert_eq!(prompt.current_entry.as_ref().unwrap().command, "ls -b");
+            // Still reachable by navigating explicitly
+            prompt.handle_key_press(Key::Alt('n'));
+            assert_eq!(prompt.context.as_ref().unwrap().1.command, "ls -a");
+            prompt.update();
+            assert_eq!(prompt.current_entry.as_ref().unwrap().command, "ls -c");
+            prompt.update();
+            assert_eq!(prompt.current_entry, None);
+        }
+    }
+
+    #[test]
+    fn test_reusable_iter_get() {
+        let mut iter = ReusableIter::new(["spam", "eggs", "ham"].into_iter());
+        assert_eq!(iter.next(), Some("spam"));
+        assert_eq!(iter.position(), 1);
+        assert_eq!(iter.get(2), Some("ham"));
+        assert_eq!(iter.get(0), Some("spam"));
+        assert_eq!(iter.get(3), None);
+        // Looking ahead doesn't advance
+        assert_eq!(iter.next(), Some("eggs"));
+        assert_eq!(iter.next(), Some("ham"));
+        assert_eq!(iter.next(), None);
+        iter.reset();
+        assert_eq!(iter.position(), 0);
+        assert_eq!(iter.get(1), Some("eggs"));
+    }
+
+    #[test]
+    fn test_delete_word() {
+        let mut input = "git push --force".to_string();
+        assert_eq!(delete_word(&mut input, 16), 11);
+        assert_eq!(input, "git push --");
+        assert_eq!(delete_word(&mut input, 11), 4);
+        assert_eq!(input, "git ");
+        input.push_str("  ");
+        assert_eq!(delete_word(&mut input, 6), 0);
+        assert_eq!(input, "");
+        assert_eq!(delete_word(&mut input, 0), 0);
+        assert_eq!(input, "");
+        // In the middle
+        let mut input = "git push --force".to_string();
+        assert_eq!(delete_word(&mut input, 9), 4);
+        assert_eq!(input, "git --force");
+    }
+
+    #[test]
+    fn test_delete_whitespace_word() {
+        let mut input = "git push --force  ".to_string();
+        assert_eq!(delete_whitespace_word(&mut input, 18), 9);
+        assert_eq!(input, "git push ");
+        assert_eq!(delete_whitespace_word(&mut input, 9), 4);
+        assert_eq!(input, "git ");
+        let mut input = "git".to_string();
+        assert_eq!(delete_whitespace_word(&mut input, 3), 0);
+        assert_eq!(input, "");
+        assert_eq!(delete_whitespace_word(&mut input, 0), 0);
+        // In the middle
+        let mut input = "ls -la /tmp".to_string();
+        assert_eq!(delete_whitespace_word(&mut input, 6), 3);
+        assert_eq!(input, "ls  /tmp");
+    }
+
+    #[test]
+    fn test_delete_whitespace_word_and_clear() {
+        let mut prompt = test_prompt(&["git push", "cat foo.txt"], 80);
+        prompt.set_input("cat foo.txt git".to_string());
+        assert_eq!(prompt.current_entry, None);
+        prompt.handle_key_press(Key::Ctrl('w'));
+        prompt.handle_key_press(Key::Ctrl('w'));
+        assert_eq!(prompt.input, "cat ");
+        assert_eq!(
+            prompt.current_entry.as_ref().unwrap().command,
+            "cat foo.txt"
+        );
+        prompt.handle_key_press(Key::Ctrl('u'));
+        assert_eq!((prompt.input.as_str(), prompt.cursor), ("", 0));
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "git push");
+    }
+
+    #[test]
+    fn test_word_movement() {
+        let input = "git push --force";
+        assert_eq!(previous_word_start(input, 16), 11);
+        assert_eq!(previous_word_start(input, 11), 4);
+        assert_eq!(previous_word_start(input, 2), 0);
+        assert_eq!(next_word_end(input, 0), 3);
+        assert_eq!(next_word_end(input, 3), 8);
+        assert_eq!(next_word_end(input, 8), 16);
+        assert_eq!(next_word_end(input, 16), 16);
+    }
+
+    #[test]
+    fn test_adjust_cursor() {
+        assert_eq!(adjust_cursor(3, 8, Key::Left), 2);
+        assert_eq!(adjust_cursor(0, 8, Key::Left), 0);
+        assert_eq!(adjust_cursor(3, 8, Key::Right), 4);
+        assert_eq!(adjust_cursor(8, 8, Key::Right), 8);
+        assert_eq!(adjust_cursor(3, 8, Key::Home), 0);
+        assert_eq!(adjust_cursor(3, 8, Key::End), 8);
+        assert_eq!(adjust_cursor(3, 8, Key::Char('\n')), 3);
+    }
+
+    #[test]
+    fn test_accept_with_end() {
+        let mut prompt = test_prompt(&["echo grün\nls"], 80);
+        prompt.set_input("echo".to_string());
+        // The end of the whole entry in characters
+        assert_eq!(
+            prompt.handle_key_press(Key::End),
+            PromptResult::Selected("echo grün\nls".to_string(), Execute::No, 12)
+        );
+    }
+
+    #[test]
+    fn test_accept_to_edit() {
+        let mut prompt = test_prompt(&["git push origin", "ls"], 80);
+        prompt.set_input("push".to_string());
+        assert_eq!(
+            prompt.handle_key_press(Key::Alt('\r')),
+            PromptResult::Selected("git push origin".to_string(), Execute::No, 15)
+        );
+    }
+
+    #[test]
+    fn test_edit_query_in_the_middle() {
+        let mut prompt = test_prompt(&["git push"], 80);
+        prompt.set_input("gt".to_string());
+        assert_eq!(prompt.current_entry, None);
+        prompt.stdout.take_output();
+        assert_eq!(prompt.handle_key_press(Key::Left), PromptResult::Incomplete);
+        // The cursor is right after "g"
+        assert!(prompt.stdout.take_output().ends_with(&format!(
+            "(no matches){}{}\r{}",
+            termion::style::Reset,
+            termion::cursor::Up(1),
+            termion::cursor::Right(15)
+        )));
+        prompt.handle_key_press(Key::Char('i'));
+        assert_eq!(prompt.input, "git");
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "git push");
+        // Still within the query, so the cursor keys keep editing it
+        assert_eq!(
+            prompt.handle_key_press(Key::Right),
+            PromptResult::Incomplete
+        );
+        assert_eq!(prompt.cursor, 3);
+        // With a match and the cursor at the end, they accept again
+        assert!(matches!(
+            prompt.handle_key_press(Key::Left),
+            PromptResult::Selected(..)
+        ));
+    }
+
+    #[test]
+    fn test_home_and_end_edit_query() {
+        let mut prompt = test_prompt(&["git push", "git pull"], 80);
+        prompt.set_input("push".to_string());
+        // With the cursor at the end of the query, they accept
+        assert_eq!(
+            prompt.handle_key_press(Key::Home),
+            PromptResult::Selected("git push".to_string(), Execute::No, 0)
+        );
+        prompt.set_input("push".to_string());
+        prompt.handle_key_press(Key::Alt('b'));
+        assert_eq!(prompt.cursor, 0);
+        // Within the query, they move there instead
+        prompt.handle_key_press(Key::Right);
+        assert_eq!(prompt.handle_key_press(Key::Home), PromptResult::Incomplete);
+        assert_eq!(prompt.cursor, 0);
+        prompt.handle_key_press(Key::Char('t'));
+        prompt.handle_key_press(Key::Char(' '));
+        assert_eq!(prompt.handle_key_press(Key::End), PromptResult::Incomplete);
+        assert_eq!((prompt.input.as_str(), prompt.cursor), ("t push", 6));
+        // Back at the end, they accept again
+        assert_eq!(
+            prompt.handle_key_press(Key::End),
+            PromptResult::Selected("git push".to_string(), Execute::No, 8)
+        );
+    }
+
+    #[test]
+    fn test_home_and_end_edit_query_without_match() {
+        let config = Config {
+            bindings: Bindings::parse("accept_cursor =").unwrap(),
+            ..Config::default()
+        };
+        let mut prompt = test_prompt_with_config(&["git push"], 80, config);
+        prompt.set_input("push".to_string());
+        assert_eq!(prompt.handle_key_press(Key::Home), PromptResult::Incomplete);
+        assert_eq!(prompt.cursor, 0);
+        assert_eq!(prompt.handle_key_press(Key::End), PromptResult::Incomplete);
+        assert_eq!(prompt.cursor, 4);
+        // Nothing to accept, so they edit even if they could accept
+        let mut prompt = test_prompt(&["git push"], 80);
+        prompt.set_input("gt".to_string());
+        assert_eq!(prompt.handle_key_press(Key::Home), PromptResult::Incomplete);
+        assert_eq!(prompt.cursor, 0);
+    }
+
+    #[test]
+    fn test_no_matches() {
+        let mut prompt = test_prompt(&["git push"], 80);
+        prompt.set_input("gt".to_string());
+        assert!(prompt.stdout.take_output().ends_with(&format!(
+            "bck-i-search: gt\r\n{}(no matches){}{}\r{}",
+            termion::style::Faint,
+            termion::style::Reset,
+            termion::cursor::Up(1),
+            termion::cursor::Right(16)
+        )));
+        prompt.handle_key_press(Key::Backspace);
+        assert!(!prompt.stdout.take_output().contains("no matches"));
+        prompt.handle_key_press(Key::Ctrl('r'));
+        assert!(prompt.stdout.take_output().contains("(no more matches)"));
+        // Browsing without a query doesn't need it
+        prompt.set_input(String::new());
+        prompt.handle_key_press(Key::Ctrl('r'));
+        assert!(!prompt.stdout.take_output().contains("matches"));
+    }
+
+    #[test]
+    fn test_accept_stale_match() {
+        let mut prompt = test_prompt(&["git push", "ls"], 80);
+        prompt.set_input("git".to_string());
+        // Found under a different query, e.g. when a search got lost
+        prompt.current_entry = Some(Entry::from("cargo build".to_string()));
+        assert_eq!(
+            prompt.handle_key_press(Key::Char('\n')),
+            PromptResult::Incomplete
+        );
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "git push");
+        assert_eq!(
+            prompt.handle_key_press(Key::Char('\n')),
+            PromptResult::Selected("git push".to_string(), Execute::Yes, 3)
+        );
+
+        let mut prompt = test_prompt(&["ls"], 80);
+        prompt.set_input("git".to_string());
+        prompt.current_entry = Some(Entry::from("cargo build".to_string()));
+        assert_eq!(
+            prompt.handle_key_press(Key::Char('\n')),
+            PromptResult::Incomplete
+        );
+        assert_eq!(prompt.current_entry, None);
+        assert_eq!(
+            prompt.handle_key_press(Key::Char('\n')),
+            PromptResult::NoMatch
+        );
+    }
+
+    #[test]
+    fn test_wrap() {
+        for cycle_order in [CycleOrder::History, CycleOrder::Recent] {
+            let config = Config {
+                wrap: true,
+                cycle_order,
+                ..Config::default()
+            };
+            let mut prompt = test_prompt_with_config(&["git push", "ls", "git pull"], 80, config);
+            prompt.set_input("git".to_string());
+            prompt.handle_key_press(Key::Ctrl('r'));
+            assert_eq!(prompt.current_entry.as_ref().unwrap().command, "git pull");
+            prompt.handle_key_press(Key::Ctrl('r'));
+            assert_eq!(prompt.current_entry.as_ref().unwrap().command, "git push");
+            assert_eq!(prompt.prompt(), "[1/2] bck-i-search: git");
+        }
+    }
+
+    #[test]
+    fn test_wrap_without_matches() {
+        let config = Config {
+            wrap: true,
+            ..Config::default()
+        };
+        let mut prompt = test_prompt_with_config(&["ls"], 80, config);
+        prompt.set_input("git".to_string());
+        prompt.handle_key_press(Key::Ctrl('r'));
+        assert_eq!(prompt.current_entry, None);
+        assert_eq!(prompt.match_count, Some(0));
+    }
+
+    #[test]
+    fn test_status_bar() {
+        let config = Config {
+            status: true,
+            ..Config::default()
+        };
+        let mut prompt = test_prompt_with_config(&["git push", "git pull"], 80, config);
+        prompt.stdout.size = (80, 10);
+        prompt.handle_terminal_size_change();
+        prompt.set_input("git".to_string());
+        prompt.handle_key_press(Key::Ctrl('f'));
+        let output = prompt.stdout.take_output();
+        // The ordinal and mode aren't in front of the query then
+        assert!(output.contains(&format!(
+            "{}bck-i-search: git\r\n",
+            termion::clear::AfterCursor
+        )));
+        // A row is kept free below the entry
+        assert!(output.contains(&format!(" push\r\n{}", termion::cursor::Up(2))));
+        assert!(output.ends_with(&format!(
+            "{}{}{}{} fuzzy | smart case | match 1 {}{}",
+            termion::cursor::Save,
+            termion::cursor::Goto(1, 10),
+            termion::clear::CurrentLine,
+            termion::style::Invert,
+            termion::style::Reset,
+            termion::cursor::Restore
+        )));
+        // Cleared along with everything else below the prompt
+        assert_eq!(prompt.handle_key_press(Key::Esc), PromptResult::Quit);
+        assert!(prompt
+            .stdout
+            .take_output()
+            .ends_with(&termion::clear::AfterCursor.to_string()));
+    }
+
+    #[test]
+    fn test_list_window() {
+        assert_eq!(list_window(0, 0, 3), 0);
+        assert_eq!(list_window(0, 2, 3), 0);
+        // Scrolls down just enough to show the active match as last one
+        assert_eq!(list_window(0, 3, 3), 1);
+        assert_eq!(list_window(1, 7, 3), 5);
+        // And up to show it as first one
+        assert_eq!(list_window(5, 4, 3), 4);
+        assert_eq!(list_window(5, 0, 3), 0);
+    }
+
+    #[test]
+    fn test_list() {
+        let config = Config {
+            list: Some(2),
+            ..Config::default()
+        };
+        let history = ["git push", "ls", "git pull\ngit log", "git log"];
+        let mut prompt = test_prompt_with_config(&history, 80, config);
+        let (red, invert, bold, reset) = (
+            termion::color::Fg(termion::color::Red),
+            termion::style::Invert,
+            termion::style::Bold,
+            termion::style::Reset,
+        );
+        prompt.set_input("log".to_string());
+        let output = prompt.stdout.take_output();
+        // The matching line of the multiline entry, and below it the next match
+        assert!(output.contains(&format!(
+            "\r\n{invert}git {red}{bold}log{reset}{invert}{reset}{} …{reset}\r\n\
+             git {red}{invert}{bold}log{reset}{}",
+            termion::style::Faint,
+            termion::cursor::Up(2)
+        )));
+        // Looking ahead didn't move on
+        prompt.handle_key_press(Key::Down);
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "git log");
+        assert_eq!(prompt.prompt(), "[2] bck-i-search: log");
+
+        prompt.set_input("git".to_string());
+        prompt.handle_key_press(Key::Down);
+        prompt.handle_key_press(Key::Down);
+        assert_eq!(prompt.list_start, 1);
+        assert!(prompt.stdout.take_output().ends_with(&format!(
+            "\r\n{red}{invert}{bold}git{reset} pull{} …{reset}\r\n\
+             {invert}{red}{bold}git{reset}{invert} log{reset}{}\r{}",
+            termion::style::Faint,
+            termion::cursor::Up(2),
+            termion::cursor::Right(21)
+        )));
+        prompt.handle_key_press(Key::Up);
+        assert_eq!(prompt.list_start, 1);
+        assert_eq!(
+            prompt.handle_key_press(Key::Char('\n')),
+            PromptResult::Selected("git pull\ngit log".to_string(), Execute::Yes, 3)
+        );
+    }
+
+    #[test]
+    fn test_click_listed_match() {
+        let config = Config {
+            list: Some(3),
+            ..Config::default()
+        };
+        let history = ["git push", "ls", "git pull", "git log", "git am"];
+        let mut prompt = test_prompt_with_config(&history, 80, config);
+        // Near the bottom, so the list scrolls the terminal up by two rows
+        prompt.set_prompt_row(23);
+        prompt.set_input("git".to_string());
+        assert_eq!(prompt.prompt_row, Some(21));
+        assert_eq!(prompt.clicked_match(21), None);
+        assert_eq!(prompt.clicked_match(22), Some(0));
+        assert_eq!(prompt.clicked_match(24), Some(2));
+        prompt.handle_key_press(Key::Down);
+        let click = |row| Event::Mouse(MouseEvent::Press(MouseButton::Left, 5, row));
+        assert_eq!(
+            prompt.handle_event(click(24)),
+            PromptResult::Selected("git log".to_string(), Execute::No, 7)
+        );
+
+        // Entries taking several rows, and fewer than fit
+        let config = Config {
+            list: Some(3),
+            ..Config::default()
+        };
+        let mut prompt =
+            test_prompt_with_config(&["git push --force-with-lease", "git am"], 20, config);
+        prompt.set_prompt_row(1);
+        prompt.set_input("git".to_string());
+        prompt.handle_key_press(Key::Down);
+        // The prompt takes two rows, too
+        assert_eq!(prompt.clicked_match(2), None);
+        assert_eq!(prompt.clicked_match(4), Some(0));
+        assert_eq!(prompt.clicked_match(5), Some(1));
+        assert_eq!(prompt.clicked_match(6), None);
+        assert_eq!(
+            prompt.handle_event(click(3)),
+            PromptResult::Selected("git push --force-with-lease".to_string(), Execute::No, 27)
+        );
+    }
+
+    #[test]
+    fn test_selected_match() {
+        let config = Config {
+            trim_whitespace: true,
+            truncate: true,
+            ..Config::default()
+        };
+        let entry = "  git commit -m 'fix push'";
+        let mut prompt = test_prompt_with_config(&[entry], 12, config);
+        prompt.set_input("push".to_string());
+        assert_eq!(
+            prompt.handle_key_press(Key::Char('\n')),
+            PromptResult::Selected(entry.to_string(), Execute::Yes, 25)
+        );
+        // In the original entry, not the trimmed and truncated one shown
+        assert_eq!(prompt.selected_match(), Some(21..25));
+        assert_eq!(&entry[21..25], "push");
+
+        let mut prompt = test_prompt(&["git push"], 80);
+        prompt.handle_key_press(Key::Char('\n'));
+        assert_eq!(prompt.selected_match(), None);
+    }
+
+    #[test]
+    fn test_trim_whitespace() {
+        let config = Config {
+            trim_whitespace: true,
+            ..Config::default()
+        };
+        let mut prompt = test_prompt_with_config(&["  \tls -l\n  pwd"], 80, config);
+        prompt.set_input("ls".to_string());
+        let output = prompt.stdout.take_output();
+        assert!(output.contains(&format!(
+            "\r\n{}{}{}ls{} -l\r\n{}│ {}  pwd",
+            termion::color::Fg(termion::color::Red),
+            termion::style::Invert,
+            termion::style::Bold,
+            termion::style::Reset,
+            termion::style::Faint,
+            termion::style::Reset
+        )));
+        // Selected as it is, with the cursor after the match in the original entry
+        assert_eq!(
+            prompt.handle_key_press(Key::Char('\n')),
+            PromptResult::Selected("  \tls -l\n  pwd".to_string(), Execute::Yes, 5)
+        );
+    }
+
+    #[test]
+    fn test_edit_query_words() {
+        let config = Config {
+            bindings: Bindings::parse("accept_cursor =").unwrap(),
+            ..Config::default()
+        };
+        let mut prompt = test_prompt_with_config(&["git push --force"], 80, config);
+        prompt.set_input("git pull force".to_string());
+        prompt.handle_key_press(Key::Alt('b'));
+        prompt.handle_key_press(Key::Left);
+        prompt.handle_key_press(Key::Alt('\x7f'));
+        assert_eq!((prompt.input.as_str(), prompt.cursor), ("git  force", 4));
+        prompt.handle_key_press(Key::Char('p'));
+        prompt.handle_key_press(Key::Backspace);
+        prompt.handle_key_press(Key::Char('p'));
+        prompt.handle_key_press(Key::Right);
+        assert_eq!(prompt.cursor, 6);
+        prompt.handle_key_press(Key::Alt('f'));
+        assert_eq!(prompt.cursor, prompt.input.len());
+        assert_eq!(
+            prompt.current_entry.as_ref().unwrap().command,
+            "git push --force"
+        );
+    }
+
+    #[test]
+    fn test_cursor_on_earlier_prompt_row() {
+        let mut prompt = test_prompt(&[], 10);
+        prompt.set_input("abcdefgh".to_string());
+        prompt.stdout.take_output();
+        for _ in 0..5 {
+            prompt.handle_key_press(Key::Left);
+        }
+        // "bck-i-search: abc" ends on the second of three rows, below them "(no matches)" takes
+        // two more
+        let output = prompt.stdout.take_output();
+        assert!(output.ends_with(&format!(
+            "{}\r{}",
+            termion::cursor::Up(3),
+            termion::cursor::Right(7)
+        )));
+        prompt.redraw();
+        // Clearing starts from the cursor's row
+        assert!(prompt.stdout.take_output().starts_with(&format!(
+            "\r{}{}",
+            termion::cursor::Up(1),
+            termion::clear::AfterCursor
+        )));
+    }
+
+    #[test]
+    fn test_excluded_terms() {
+        let mut prompt = test_prompt(
+            &["git push", "git pull", "echo hi!", "cargo push", "git log"],
+            80,
+        );
+        prompt.set_input("git !push".to_string());
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "git pull");
+        // Excluded terms aren't highlighted
+        assert_eq!(prompt.highlight_spans("git pull"), vec![0..3]);
+        prompt.update();
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "git log");
+        prompt.update();
+        assert_eq!(prompt.current_entry, None);
+        // Excluded terms only
+        prompt.set_input("!git !push".to_string());
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "echo hi!");
+        prompt.update();
+        assert_eq!(prompt.current_entry, None);
+        // Typing an excluded term excludes less and less
+        prompt.set_input("git !pu".to_string());
+        prompt.handle_key_press(Key::Char('s'));
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "git pull");
+        prompt.set_input("\\!".to_string());
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "echo hi!");
+    }
+
+    #[test]
+    fn test_words_match_in_any_order() {
+        let mut prompt = test_prompt(&["docker ps", "docker system prune", "prune docker"], 80);
+        prompt.set_input("prune docker ".to_string());
+        assert_eq!(
+            prompt.current_entry.as_ref().unwrap().command,
+            "docker system prune"
+        );
+        assert_eq!(
+            prompt.highlight_spans("docker system prune"),
+            vec![0..6, 14..19]
+        );
+        prompt.update();
+        assert_eq!(
+            prompt.current_entry.as_ref().unwrap().command,
+            "prune docker"
+        );
+        prompt.update();
+        assert_eq!(prompt.current_entry, None);
+    }
+
+    #[test]
+    fn test_escaped_space_matches_literally() {
+        let mut prompt = test_prompt(&["git commit -a", "git  commit"], 80);
+        prompt.set_input(r"git\ \ commit".to_string());
+        assert_eq!(
+            prompt.current_entry.as_ref().unwrap().command,
+            "git  commit"
+        );
+    }
+
+    #[test]
+    fn test_command_name() {
+        assert_eq!(command_name("git push -f"), "git");
+        assert_eq!(command_name("  \tgit"), "git");
+        assert_eq!(command_name("./build.sh --release"), "./build.sh");
+        // Assignments only apply to the command
+        assert_eq!(command_name("FOO=bar make -j8"), "make");
+        assert_eq!(command_name("A=1  _B2= cargo test"), "cargo");
+        assert_eq!(command_name("FOO=bar"), "");
+        assert_eq!(command_name(""), "");
+        // Not assignments
+        assert_eq!(command_name("=foo bar"), "=foo");
+        assert_eq!(command_name("1A=b c"), "1A=b");
+        assert_eq!(command_name("echo A=b"), "echo");
+        assert_eq!(command_name_range("FOO=bar make -j8"), 8..12);
+    }
+
+    #[test]
+    fn test_command_only() {
+        let config = Config {
+            command_only: true,
+            ..Config::default()
+        };
+        let history = ["echo git", "git push", "FOO=1 git commit", "gitk"];
+        let mut prompt = test_prompt_with_config(&history, 80, config);
+        prompt.set_input("git".to_string());
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "git push");
+        prompt.handle_key_press(Key::Ctrl('r'));
+        assert_eq!(
+            prompt.current_entry.as_ref().unwrap().command,
+            "FOO=1 git commit"
+        );
+        prompt.handle_key_press(Key::Ctrl('r'));
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "gitk");
+        prompt.handle_key_press(Key::Ctrl('r'));
+        assert_eq!(prompt.current_entry, None);
+
+        // Only the command name is highlighted, and the cursor placed after the match in it
+        prompt.set_input("it".to_string());
+        prompt.handle_key_press(Key::Ctrl('r'));
+        let highlighted = format!(
+            "{}{}{}it{}",
+            termion::color::Fg(termion::color::Red),
+            termion::style::Invert,
+            termion::style::Bold,
+            termion::style::Reset
+        );
+        assert!(prompt
+            .stdout
+            .take_output()
+            .contains(&format!("\r\nFOO=1 g{highlighted} commit")));
+        assert_eq!(
+            prompt.handle_key_press(Key::Char('\n')),
+            PromptResult::Selected("FOO=1 git commit".to_string(), Execute::Yes, 9)
+        );
+    }
+
+    #[test]
+    fn test_command_args() {
+        assert_eq!(command_args("git push -f", "git"), Some("push -f"));
+        assert_eq!(command_args("  git\tpush", "git"), Some("push"));
+        assert_eq!(command_args("git", "git"), Some(""));
+        assert_eq!(command_args("gitk --all", "git"), None);
+        assert_eq!(command_args("echo git", "git"), None);
+    }
+
+    #[test]
+    fn test_show_context() {
+        let mut prompt = test_prompt(&["ls", "cd /tmp", "ls -l", "make"], 80);
+        prompt.set_input("ls -".to_string());
+        prompt.handle_key_press(Key::Alt('p'));
+        let output = prompt.stdout.take_output();
+        assert!(output.contains("\r\nmake\x1b[2m (no match)"));
+        prompt.handle_key_press(Key::Alt('p'));
+        assert_eq!(prompt.stdout.take_output(), "\x07");
+        prompt.handle_key_press(Key::Alt('n'));
+        assert_eq!(prompt.context, None);
+        prompt.handle_key_press(Key::Alt('n'));
+        assert_eq!(
+            prompt.handle_key_press(Key::Char('\n')),
+            PromptResult::Selected("cd /tmp".to_string(), Execute::Yes, 7)
+        );
+    }
+
+    #[test]
+    fn test_indexing_indicator() {
+        let config = Config {
+            cycle_order: CycleOrder::Recent,
+            ..Config::default()
+        };
+        let mut prompt = test_prompt_with_config(&["ls"], 80, config);
+        prompt.set_input("l".to_string());
+        let output = prompt.stdout.take_output();
+        let (indexing, done) = output.split_once("indexing…").unwrap();
+        assert!(indexing.ends_with("bck-i-search: l\r\n\x1b[2m"));
+        assert!(done.contains("bck-i-search: l\r\n"));
+        // Cycling through the collected matches doesn't index again
+        prompt.update();
+        assert!(!prompt.stdout.take_output().contains("indexing"));
+
+        let mut prompt = test_prompt(&["ls"], 80);
+        prompt.set_input("l".to_string());
+        assert!(!prompt.stdout.take_output().contains("indexing"));
+    }
+
+    #[test]
+    fn test_outputs() {
+        let config = Config {
+            outputs: true,
+            ..Config::default()
+        };
+        let history = ["#output=report.pdf\nmake report", "ls report.pdf"];
+        let history: Vec<_> = history
+            .iter()
+            .map(|record| Entry::with_metadata(record.to_string()))
+            .collect();
+        let mut prompt =
+            Prompt::new(TestTerminal::new(80, 24), history.into_iter(), config).unwrap();
+        prompt.set_input("pdf".to_string());
+        assert_eq!(
+            prompt.current_entry.as_ref().unwrap().command,
+            "make report"
+        );
+        prompt.update();
+        assert_eq!(prompt.current_entry, None);
+    }
+
+    #[test]
+    fn test_smart_case() {
+        let mut prompt = test_prompt(&["cat readme", "cat README"], 80);
+        prompt.set_input("readme".to_string());
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "cat readme");
+        prompt.update();
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "cat README");
+        prompt.set_input("README".to_string());
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "cat README");
+        assert_eq!(
+            prompt.highlight_spans("cat readme\ncat README"),
+            vec![15..21]
+        );
+        prompt.update();
+        assert_eq!(prompt.current_entry, None);
+    }
+
+    #[test]
+    fn test_toggle_case() {
+        let mut prompt = test_prompt(&["cat readme", "cat README"], 80);
+        prompt.set_input("readme".to_string());
+        prompt.handle_key_press(Key::Ctrl('t'));
+        assert!(prompt.prompt().starts_with("[1] (case-sensitive) "));
+        prompt.update();
+        assert_eq!(prompt.current_entry, None);
+        prompt.set_input("README".to_string());
+        prompt.handle_key_press(Key::Ctrl('t'));
+        assert!(prompt.prompt().starts_with("[1] (ignore case) "));
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "cat readme");
+        prompt.handle_key_press(Key::Ctrl('t'));
+        assert_eq!(prompt.prompt(), "[1] bck-i-search: README");
+    }
+
+    #[test]
+    fn test_prompt_without_wrapping() {
+        let config = Config {
+            prompt_wrap: PromptWrap::Off,
+            ..Config::default()
+        };
+        let mut prompt = test_prompt_with_config(&["ls"], 20, config);
+        prompt.set_input("abcdefghijklmnopqrstuvwxyz".to_string());
+        // 14 columns for the prompt, 5 for the query and the last one for the cursor
+        assert_eq!(
+            prompt.displayed_prompt(),
+            ("bck-i-search: …wxyz".to_string(), 21)
+        );
+        assert_eq!(prompt.current_input_height, 1);
+        let output = prompt.stdout.take_output();
+        assert!(output.contains("bck-i-search: …wxyz\r\n"));
+        assert!(output.ends_with(&format!("\r{}", termion::cursor::Right(19))));
+        prompt.move_cursor(0);
+        assert_eq!(
+            prompt.displayed_prompt(),
+            ("bck-i-search: abcd…".to_string(), 14)
+        );
+        let output = prompt.stdout.take_output();
+        assert!(output.contains("bck-i-search: abcd…\r\n"));
+        assert!(output.ends_with(&format!("\r{}", termion::cursor::Right(14))));
+        prompt.stdout.size = (30, 24);
+        prompt.handle_terminal_size_change();
+        assert_eq!(prompt.current_input_height, 1);
+        assert_eq!(prompt.displayed_prompt().0, "bck-i-search: abcdefghijklmn…");
+    }
+
+    #[test]
+    fn test_wide_prompt_height() {
+        let config = Config {
+            prompt: Some("🔍🔍🔍 ".to_string()),
+            ..Config::default()
+        };
+        let mut prompt = test_prompt_with_config(&["ls"], 4, config);
+        // The prompt is 7 columns wide plus the ordinal and the query
+        prompt.set_input("ls".to_string());
+        assert_eq!(prompt.current_input_height, 4);
+        prompt.stdout.size = (5, 24);
+        prompt.handle_terminal_size_change();
+        assert_eq!(prompt.current_input_height, 3);
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        let config = Config {
+            match_mode: MatchMode::Fuzzy,
+            ..Config::default()
+        };
+        let entry = format!("echo {}", "ab".repeat(10000));
+        let mut prompt = test_prompt_with_config(&[&entry], 80, config);
+        prompt.paste(&"ab".repeat(10000));
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, entry);
+        assert!(prompt
+            .prompt()
+            .starts_with("[1] (invalid pattern) (fuzzy) bck-i-search: abab"));
+        assert!(prompt.spans.is_empty());
+    }
+
+    #[test]
+    fn test_accept_invalid_pattern() {
+        let config = Config {
+            match_mode: MatchMode::Fuzzy,
+            ..Config::default()
+        };
+        let query = "ab".repeat(10000);
+        let entries = [format!("echo {query}"), format!("printf {query}")];
+        let mut prompt = test_prompt_with_config(&[&entries[0], &entries[1]], 80, config);
+        prompt.paste(&query);
+        prompt.handle_key_press(Key::Ctrl('r'));
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, entries[1]);
+        assert_eq!(
+            prompt.handle_key_press(Key::Char('\n')),
+            PromptResult::Selected(entries[1].clone(), Execute::Yes, entries[1].len())
+        );
+    }
+
+    #[test]
+    fn test_highlight_is_only_compiled_for_new_query() {
+        let mut prompt = test_prompt(&["git push", "git pull"], 80);
+        prompt.set_input("git".to_string());
+        let compiled =
+            |prompt: &Prompt<_, _>| prompt.compile_query().highlight().unwrap().to_string();
+        assert_eq!(
+            prompt.matcher.highlight().unwrap().as_str(),
+            compiled(&prompt)
+        );
+        // Redraws and cycling keep whatever query was compiled
+        prompt.matcher = Matcher::new("pu", MatchOptions::default());
+        prompt.stdout.take_output();
+        prompt.handle_key_press(Key::Ctrl('r'));
+        prompt.redraw();
+        let output = prompt.stdout.take_output();
+        assert!(output.contains(&format!("git {}", termion::color::Fg(termion::color::Red))));
+        prompt.handle_key_press(Key::Char(' '));
+        assert_eq!(
+            prompt.matcher.highlight().unwrap().as_str(),
+            compiled(&prompt)
+        );
+    }
+
+    #[test]
+    fn test_redraw_after_rows_change() {
+        let mut prompt = test_prompt(&["echo 1\necho 2\necho 3\necho 4"], 80);
+        prompt.set_input("echo".to_string());
+        prompt.stdout.take_output();
+        // Same size, nothing to do
+        prompt.handle_terminal_size_change();
+        assert_eq!(prompt.stdout.take_output(), "");
+        // Regression: with fewer rows than the entry is high, the cursor used to be moved up by
+        // the entry's full height
+        prompt.stdout.size = (80, 3);
+        prompt.handle_terminal_size_change();
+        let output = prompt.stdout.take_output();
+        assert!(output.contains("[1] bck-i-search: echo"));
+        assert!(output.ends_with(&format!(
+            "{}\r{}",
+            termion::cursor::Up(2),
+            termion::cursor::Right(22)
+        )));
+    }
+
+    #[test]
+    fn test_clamp_lines() {
+        let lines = ["one", "two", "three"];
+        assert_eq!(clamp_lines(&lines, 5), (&lines[..], 0));
+        assert_eq!(clamp_lines(&lines, 3), (&lines[..], 0));
+        assert_eq!(clamp_lines(&lines, 2), (&lines[..2], 1));
+        assert_eq!(clamp_lines(&lines, 0), (&lines[..1], 2));
+    }
+
+    #[test]
+    fn test_redraw_clamps_tall_entries() {
+        let mut prompt = test_prompt(&["echo 1\necho 2\necho 3\necho 4"], 80);
+        prompt.stdout.size = (80, 3);
+        prompt.handle_terminal_size_change();
+        prompt.set_input("echo".to_string());
+        let output = prompt.stdout.take_output();
+        assert!(output.contains(" 2"));
+        assert!(!output.contains(" 3"));
+        assert!(output.contains("… (+2 more lines)"));
+        assert!(output.ends_with(&format!(
+            "{}\r{}",
+            termion::cursor::Up(2),
+            termion::cursor::Right(22)
+        )));
+    }
+
+    #[test]
+    fn test_match_ordinal() {
+        let mut prompt = test_prompt(&["ls", "cd", "ls -l"], 80);
+        prompt.set_input("ls".to_string());
+        assert_eq!(prompt.prompt(), "[1] bck-i-search: ls");
+        prompt.update();
+        assert_eq!(prompt.prompt(), "[2] bck-i-search: ls");
+        // Only known once all matches were seen
+        prompt.update();
+        assert_eq!(prompt.prompt(), "bck-i-search: ls");
+        prompt.previous_match();
+        assert_eq!(prompt.prompt(), "[2/2] bck-i-search: ls");
+        prompt.previous_match();
+        assert_eq!(prompt.prompt(), "[1/2] bck-i-search: ls");
+        prompt.set_input("cd".to_string());
+        assert_eq!(prompt.prompt(), "[1] bck-i-search: cd");
+    }
+
+    #[test]
+    fn test_match_ordinal_sorted() {
+        let config = Config {
+            cycle_order: CycleOrder::Recent,
+            ..Config::default()
+        };
+        let mut prompt = test_prompt_with_config(&["ls", "cd", "ls -l"], 80, config);
+        prompt.set_input("ls".to_string());
+        assert_eq!(prompt.prompt(), "[1/2] bck-i-search: ls");
+        prompt.update();
+        assert_eq!(prompt.prompt(), "[2/2] bck-i-search: ls");
+    }
+
+    #[test]
+    fn test_tty_error() {
+        let err = std::io::Error::from_raw_os_error(libc::ENXIO);
+        let message = tty_error(&err, true);
+        assert!(message.starts_with("Could not open TTY (there's no controlling terminal): "));
+        assert!(message.ends_with(&err.to_string()));
+        let err = std::io::Error::from(ErrorKind::PermissionDenied);
+        assert_eq!(
+            tty_error(&err, true),
+            format!("Could not open TTY (no permission to use it): {err}")
+        );
+        let err = std::io::Error::from(ErrorKind::NotFound);
+        let message = tty_error(&err, false);
+        assert!(message.starts_with(&format!("Could not open TTY: {err}. ")));
+    }
+
+    #[test]
+    fn test_first_match() {
+        let first = |history: &str, config: &mut Config, query| {
+            let history = read_history(std::io::Cursor::new(history.to_string()), config);
+            first_match(history, config, query).map(|entry| entry.command)
+        };
+        let history = "git pull\0ls\0git push\0";
+        assert_eq!(
+            first(history, &mut Config::default(), "git").as_deref(),
+            Some("git pull")
+        );
+        assert_eq!(first(history, &mut Config::default(), "make"), None);
+        let mut config = Config {
+            skip_newest: 1,
+            ..Config::default()
+        };
+        assert_eq!(
+            first(history, &mut config, "git").as_deref(),
+            Some("git push")
+        );
+        let mut config = Config {
+            reverse: true,
+            ..Config::default()
+        };
+        assert_eq!(
+            first(history, &mut config, "git").as_deref(),
+            Some("git push")
+        );
+        // A match at the start of a word wins, like when searching interactively
+        let mut prompt = test_prompt(&["legit", "git"], 80);
+        prompt.set_input("git".to_string());
+        let shown = prompt.current_entry.map(|entry| entry.command);
+        assert_eq!(first("legit\0git\0", &mut Config::default(), "git"), shown);
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        let mut prompt = test_prompt(&["ls"], 80);
+        prompt.set_input("ls".to_string());
+        let selected = prompt.handle_key_press(Key::Char('\n'));
+        assert_eq!(selected.exit_code(), 0);
+        prompt.set_input("cd".to_string());
+        let no_match = prompt.handle_key_press(Key::Char('\n'));
+        assert_eq!(no_match, PromptResult::NoMatch);
+        assert_eq!(no_match.exit_code(), 3);
+        let quit = prompt.handle_key_press(Key::Esc);
+        assert_eq!(quit, PromptResult::Quit);
+        assert_eq!(quit.exit_code(), 2);
+    }
+
+    #[test]
+    fn test_closed_tty_quits() {
+        let path = std::env::temp_dir().join(format!("kontrolleurs-tty-{}", std::process::id()));
+        // A key, then the end of input like from a closed TTY
+        std::fs::write(&path, "l").unwrap();
+        let tty = std::fs::File::open(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let mut events = tty::EventReader::new(tty.try_clone().unwrap());
+        let mut prompt = test_prompt(&["ls"], 80);
+        let result = run_search(&mut prompt, &mut events, &tty);
+        assert_eq!(prompt.input, "l");
+        assert_eq!(result, PromptResult::Quit);
+        assert_eq!(result.exit_code(), 2);
+    }
+
+    #[test]
+    fn test_browse_without_query() {
+        for suffix in [false, true] {
+            let config = Config {
+                suffix,
+                ..Config::default()
+            };
+            let mut prompt = test_prompt_with_config(&["ls", "cd /tmp", "git log"], 80, config);
+            prompt.set_input(String::new());
+            assert_eq!(prompt.current_entry.as_ref().unwrap().command, "ls");
+            prompt.handle_key_press(Key::Ctrl('r'));
+            assert_eq!(prompt.current_entry.as_ref().unwrap().command, "cd /tmp");
+            assert_eq!(
+                prompt.handle_key_press(Key::Char('\n')),
+                PromptResult::Selected("cd /tmp".to_string(), Execute::Yes, 7)
+            );
+            // Typing filters again, from the most recent entry
+            prompt.handle_key_press(Key::Char('g'));
+            assert_eq!(prompt.current_entry.as_ref().unwrap().command, "git log");
+        }
+    }
+
+    #[test]
+    fn test_fuzzy_mode() {
+        let mut prompt = test_prompt(&["git checkout main", "git commit"], 80);
+        prompt.set_input("gco".to_string());
+        assert_eq!(prompt.current_entry, None);
+        prompt.handle_key_press(Key::Ctrl('f'));
+        assert_eq!(
+            prompt.current_entry.as_ref().unwrap().command,
+            "git checkout main"
+        );
+        let output = prompt.stdout.take_output();
+        assert!(output.contains("(fuzzy) bck-i-search: gco"));
+        assert_eq!(
+            output.matches(&termion::style::Invert.to_string()).count(),
+            3
+        );
+        prompt.update();
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "git commit");
+    }
+
+    #[test]
+    fn test_glob_mode() {
+        let config = Config {
+            match_mode: MatchMode::Glob,
+            ..Config::default()
+        };
+        let mut prompt =
+            test_prompt_with_config(&["git pull", "git push -f", "ls *.rs"], 80, config);
+        prompt.set_input("git*push".to_string());
+        assert_eq!(
+            prompt.current_entry.as_ref().unwrap().command,
+            "git push -f"
+        );
+        assert!(prompt.prompt().ends_with("(glob) bck-i-search: git*push"));
+        assert_eq!(prompt.highlight_spans("git push -f"), vec![0..8]);
+        // Everything else is literal
+        prompt.set_input("*.r?".to_string());
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "ls *.rs");
+        assert_eq!(prompt.highlight_spans("ls *.rs"), vec![4..7]);
+        // Matches everything, like an empty query
+        prompt.set_input("*".to_string());
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "git pull");
+        assert_eq!(
+            prompt.handle_key_press(Key::Char('\n')),
+            PromptResult::Selected("git pull".to_string(), Execute::Yes, 8)
+        );
+        // Fuzzy and back
+        prompt.handle_key_press(Key::Ctrl('f'));
+        assert_eq!(prompt.match_mode, MatchMode::Fuzzy);
+        prompt.handle_key_press(Key::Ctrl('f'));
+        assert_eq!(prompt.match_mode, MatchMode::Glob);
+    }
+
+    #[test]
+    fn test_fuzzy_regex_escapes_characters() {
+        let mut prompt = test_prompt(&["echo $((1+2))"], 80);
+        prompt.handle_key_press(Key::Ctrl('f'));
+        prompt.set_input("$(+)".to_string());
+        assert_eq!(
+            prompt.highlight_spans("echo $((1+2))"),
+            vec![5..6, 6..7, 9..10, 11..12]
+        );
     }
 
     #[test]
-    fn test_history_iter_missing_traling_null() {
-        let lines = collect_history(b"first entry");
-        assert_eq!(lines, vec!["first entry".to_string()]);
+    fn test_min_highlight_length() {
+        let config = Config {
+            min_highlight_length: Some(2),
+            ..Config::default()
+        };
+        let mut prompt = test_prompt_with_config(&["ls -la"], 80, config);
+        prompt.set_input("l".to_string());
+        assert!(prompt
+            .stdout
+            .take_output()
+            .ends_with("\r\nls -la\x1b[1A\r\x1b[19C"));
+        prompt.set_input("ls".to_string());
+        let output = prompt.stdout.take_output();
+        assert_eq!(
+            output.matches(&termion::style::Invert.to_string()).count(),
+            1
+        );
     }
 
     #[test]
-    fn test_history_iter_invalid_utf_8() {
-        let lines = collect_history(b"first en\xc3try\0second entry\0");
-        assert_eq!(lines, vec!["second entry".to_string()]);
+    fn test_max_highlights() {
+        let config = Config {
+            max_highlights: Some(2),
+            ..Config::default()
+        };
+        let mut prompt = test_prompt_with_config(&["ls; ls; ls; ls"], 80, config);
+        prompt.set_input("ls".to_string());
+        let output = prompt.stdout.take_output();
+        assert_eq!(
+            output.matches(&termion::style::Invert.to_string()).count(),
+            2
+        );
+        assert!(output.contains(&format!("{}; ls; ls", termion::style::Reset)));
     }
 
     #[test]
-    fn test_reusable_iter() {
-        let mut iter = ReusableIter::new(["spam", "eggs"].iter());
-        assert_eq!(iter.next(), Some("spam").as_ref());
-        assert_eq!(iter.next(), Some("eggs").as_ref());
-        assert_eq!(iter.next(), None);
+    fn test_echo_clears_afterwards() {
+        let mut prompt = test_prompt(&[], 5);
+        prompt.echo("make all\nls", Duration::ZERO);
+        let output = prompt.stdout.take_output();
+        assert!(output.contains(&format!(
+            "\r\n{}ls{}",
+            termion::style::Bold,
+            termion::style::Reset
+        )));
+        // "make all" wraps once
+        assert!(output.ends_with(&format!(
+            "\r{}{}",
+            termion::cursor::Up(2),
+            termion::clear::AfterCursor
+        )));
+    }
 
-        iter.reset();
-        assert_eq!(iter.next(), Some("spam").as_ref());
+    #[test]
+    fn test_command_filter() {
+        let config = Config {
+            command: Some("git".to_string()),
+            strip_command: true,
+            ..Config::default()
+        };
+        let mut prompt =
+            test_prompt_with_config(&["git log", "cargo test", "git status"], 80, config);
+        prompt.set_input("st".to_string());
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "git status");
+        let output = prompt.stdout.take_output();
+        assert!(!output.contains("git"));
+        assert_eq!(
+            prompt.handle_key_press(termion::event::Key::Right),
+            PromptResult::Selected("git status".to_string(), Execute::No, 7)
+        );
+        // Only the arguments are searched
+        prompt.set_input("git".to_string());
+        assert_eq!(prompt.current_entry, None);
+    }
 
-        iter.reset();
-        assert_eq!(vec![&"spam", &"eggs"], iter.collect::<Vec<_>>());
+    #[test]
+    fn test_next_occurrence() {
+        let mut prompt = test_prompt(&["ls; ls -l; ls", "ls notes"], 80);
+        prompt.set_input("ls".to_string());
+        prompt.stdout.take_output();
+        prompt.handle_key_press(Key::Ctrl('o'));
+        let output = prompt.stdout.take_output();
+        assert_eq!(
+            output
+                .matches(&termion::color::Fg(termion::color::LightRed).to_string())
+                .count(),
+            1
+        );
+        assert!(output.contains(&format!(
+            "{}; {}",
+            termion::style::Reset,
+            termion::color::Fg(termion::color::LightRed)
+        )));
+        prompt.handle_key_press(Key::Ctrl('o'));
+        assert_eq!(
+            prompt.handle_key_press(Key::Char('\n')),
+            PromptResult::Selected("ls; ls -l; ls".to_string(), Execute::Yes, 13)
+        );
+        // Starts over after the last one
+        prompt.handle_key_press(Key::Ctrl('o'));
+        assert_eq!(
+            prompt.handle_key_press(Key::Char('\n')),
+            PromptResult::Selected("ls; ls -l; ls".to_string(), Execute::Yes, 2)
+        );
+        // The next entry starts with its first occurrence, and there's only one
+        prompt.handle_key_press(Key::Ctrl('o'));
+        prompt.update();
+        prompt.stdout.take_output();
+        prompt.handle_key_press(Key::Ctrl('o'));
+        assert_eq!(prompt.stdout.take_output(), "\x07");
+        assert_eq!(
+            prompt.handle_key_press(Key::Char('\n')),
+            PromptResult::Selected("ls notes".to_string(), Execute::Yes, 2)
+        );
+    }
+
+    #[test]
+    fn test_bracketed_paste_searches_once() {
+        let command = "x".repeat(50);
+        let mut prompt = test_prompt(&["ls", &command], 80);
+        prompt.stdout.take_output();
+        prompt.handle_event(Event::Unsupported(tty::PASTE_START.to_vec()));
+        for ch in "x".repeat(50).chars().chain(['\n']) {
+            prompt.handle_event(Event::Key(Key::Char(ch)));
+        }
+        assert_eq!(prompt.stdout.take_output(), "");
+        prompt.handle_event(Event::Unsupported(tty::PASTE_END.to_vec()));
+        assert_eq!(prompt.input, command);
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, command);
+        let output = prompt.stdout.take_output();
+        assert_eq!(
+            output
+                .matches(&termion::clear::AfterCursor.to_string())
+                .count(),
+            1
+        );
+        // Keys are handled as usual afterwards
+        assert_eq!(
+            prompt.handle_event(Event::Key(Key::Char('\n'))),
+            PromptResult::Selected(command, Execute::Yes, 50)
+        );
+    }
+
+    #[test]
+    fn test_batch_searches_once() {
+        let mut prompt = test_prompt(&["git push", "ls -l", "ls"], 80);
+        prompt.stdout.take_output();
+        prompt.start_batch();
+        for ch in "ls -".chars() {
+            prompt.handle_key_press(Key::Char(ch));
+        }
+        prompt.handle_key_press(Key::Backspace);
+        assert_eq!(prompt.stdout.take_output(), "");
+        prompt.finish_batch();
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "ls -l");
+        let output = prompt.stdout.take_output();
+        assert_eq!(
+            output
+                .matches(&termion::clear::AfterCursor.to_string())
+                .count(),
+            1
+        );
+        // Other keys see the matches for the query so far
+        prompt.start_batch();
+        prompt.handle_key_press(Key::Char('l'));
+        prompt.handle_key_press(Key::Ctrl('r'));
+        prompt.finish_batch();
+        assert_eq!(prompt.input, "ls l");
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "ls");
+    }
+
+    #[test]
+    fn test_narrowing_finds_the_same_matches() {
+        let history = [
+            "git status",
+            "cargo build --release",
+            "echo build",
+            "cargo test",
+            "make",
+            "cargo build",
+            "rebuild all",
+        ];
+        let all_matches = |prompt: &mut Prompt<_, _>| {
+            let mut matches = Vec::new();
+            while let Some(entry) = &prompt.current_entry {
+                matches.push(entry.command.clone());
+                prompt.update();
+            }
+            matches
+        };
+        // Typed character by character, cycling through the matches after the first `prefix`
+        // characters
+        for (typed, prefix, cycled) in [
+            ("build", 1, 0),
+            ("build", 1, 2),
+            ("cargo b", 1, 1),
+            ("cargo b", 6, 2),
+            ("bx", 1, 0),
+        ] {
+            let mut prompt = test_prompt(&history, 80);
+            for (i, ch) in typed.chars().enumerate() {
+                if i == prefix {
+                    for _ in 0..cycled {
+                        prompt.handle_key_press(Key::Ctrl('r'));
+                    }
+                }
+                prompt.handle_key_press(Key::Char(ch));
+            }
+            let mut searched = test_prompt(&history, 80);
+            searched.set_input(typed.to_string());
+            assert_eq!(prompt.current_entry, searched.current_entry);
+            assert_eq!(all_matches(&mut prompt), all_matches(&mut searched));
+        }
+    }
+
+    #[test]
+    fn test_narrowing_after_last_match() {
+        let mut prompt = test_prompt(&["git status", "make", "make install"], 80);
+        prompt.set_input("mak".to_string());
+        prompt.update();
+        prompt.update();
+        assert_eq!(prompt.current_entry, None);
+        // Starts over at the first match of the previous query
+        prompt.handle_key_press(Key::Char('e'));
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "make");
+        assert_eq!(prompt.prompt(), "[1] bck-i-search: make");
+        prompt.set_input("makx".to_string());
+        prompt.handle_key_press(Key::Char('y'));
+        assert_eq!(prompt.current_entry, None);
+        assert_eq!(prompt.match_count, Some(0));
+    }
+
+    #[test]
+    fn test_cursor_in_characters() {
+        let mut prompt = test_prompt(&["echo grüße; ls", "echo 日本語 | wc"], 80);
+        prompt.set_input("wc".to_string());
+        assert_eq!(
+            prompt.handle_key_press(Key::Char('\n')),
+            PromptResult::Selected("echo 日本語 | wc".to_string(), Execute::Yes, 13)
+        );
+        prompt.set_input("ls".to_string());
+        assert_eq!(
+            prompt.handle_key_press(Key::Left),
+            PromptResult::Selected("echo grüße; ls".to_string(), Execute::No, 13)
+        );
+    }
+
+    #[test]
+    fn test_copy_entry_without_match() {
+        let mut prompt = test_prompt(&["ls"], 80);
+        prompt.set_input("cargo".to_string());
+        prompt.stdout.take_output();
+        assert_eq!(
+            prompt.handle_key_press(Key::Ctrl('y')),
+            PromptResult::Incomplete
+        );
+        assert_eq!(prompt.stdout.take_output(), "\x07");
+    }
+
+    #[test]
+    fn test_delete_current() {
+        let mut prompt = test_prompt(&["git push", "git pull", "git log"], 80);
+        prompt.set_input("git".to_string());
+        prompt.handle_key_press(Key::Ctrl('r'));
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "git pull");
+        prompt.handle_key_press(Key::Ctrl('d'));
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "git log");
+        assert_eq!(prompt.prompt(), "[2] bck-i-search: git");
+        // Gone for good, also after searching again
+        prompt.handle_key_press(Key::Backspace);
+        prompt.handle_key_press(Key::Char('t'));
+        prompt.handle_key_press(Key::Ctrl('r'));
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "git log");
+        // The last match is deleted, the one before it is shown
+        prompt.handle_key_press(Key::Ctrl('d'));
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "git push");
+        prompt.handle_key_press(Key::Ctrl('d'));
+        assert_eq!(prompt.current_entry, None);
+        prompt.stdout.take_output();
+        prompt.handle_key_press(Key::Ctrl('d'));
+        assert_eq!(prompt.stdout.take_output(), "\x07");
+        let mut deleted: Vec<_> = prompt.take_deleted().into_iter().collect();
+        deleted.sort();
+        assert_eq!(deleted, vec!["git log", "git pull", "git push"]);
+    }
+
+    #[test]
+    fn test_tab() {
+        let mut prompt = test_prompt(&["git push", "git pull", "git log"], 80);
+        prompt.set_input("git".to_string());
+        prompt.handle_key_press(Key::Char('\t'));
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "git pull");
+        prompt.handle_key_press(Key::Char('\t'));
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "git log");
+        prompt.handle_key_press(Key::BackTab);
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "git pull");
+        assert_eq!(prompt.input, "git");
+        assert_eq!(prompt.prompt(), "[2] bck-i-search: git");
+    }
+
+    #[test]
+    fn test_custom_bindings() {
+        let config = Config {
+            bindings: Bindings::parse("next_match = ctrl-n\nquit = ctrl-g\naccept = tab").unwrap(),
+            ..Config::default()
+        };
+        let mut prompt = test_prompt_with_config(&["git push", "git pull"], 80, config);
+        prompt.set_input("git".to_string());
+        prompt.handle_key_press(Key::Ctrl('n'));
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "git pull");
+        // Ctrl-R isn't bound anymore and does nothing
+        prompt.handle_key_press(Key::Ctrl('r'));
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "git pull");
+        // Neither does Enter, which doesn't insert a newline either
+        assert_eq!(
+            prompt.handle_key_press(Key::Char('\n')),
+            PromptResult::Incomplete
+        );
+        assert_eq!(prompt.input, "git");
+        assert_eq!(
+            prompt.handle_key_press(Key::Char('\t')),
+            PromptResult::Selected("git pull".to_string(), Execute::Yes, 3)
+        );
+        assert_eq!(prompt.handle_key_press(Key::Esc), PromptResult::Incomplete);
+        assert_eq!(prompt.handle_key_press(Key::Ctrl('g')), PromptResult::Quit);
+    }
+
+    #[test]
+    fn test_bind_accept_cursor() {
+        let config = Config {
+            bindings: Bindings::parse("accept_cursor = end, ctrl-e").unwrap(),
+            ..Config::default()
+        };
+        let mut prompt = test_prompt_with_config(&["git push"], 80, config);
+        prompt.set_input("push".to_string());
+        // Not accepting anymore, so it only moves within the query
+        assert_eq!(prompt.handle_key_press(Key::Left), PromptResult::Incomplete);
+        assert_eq!(prompt.cursor, 3);
+        // Until the cursor is back at the end
+        assert_eq!(
+            prompt.handle_key_press(Key::Ctrl('e')),
+            PromptResult::Incomplete
+        );
+        assert_eq!(prompt.handle_key_press(Key::End), PromptResult::Incomplete);
+        assert_eq!(prompt.cursor, 4);
+        assert_eq!(
+            prompt.handle_key_press(Key::Ctrl('e')),
+            PromptResult::Selected("git push".to_string(), Execute::No, 8)
+        );
+    }
+
+    #[test]
+    fn test_remove_records() {
+        let deleted = HashSet::from(["git push".to_string()]);
+        assert_eq!(
+            remove_records(b"ls\0git push\0make\0", InputFormat::Fish, false, &deleted),
+            b"ls\0make\0"
+        );
+        // Undecodable records are kept, the last one might lack the separator
+        assert_eq!(
+            remove_records(b"ls\n\xff\ngit push", InputFormat::Bash, false, &deleted),
+            b"ls\n\xff\n"
+        );
+        assert_eq!(
+            remove_records(
+                b"#when=1700000000\ngit push\0ls\0",
+                InputFormat::Fish,
+                true,
+                &deleted
+            ),
+            b"ls\0"
+        );
+        let deleted = HashSet::from(["for i in 1 2\ndo echo $i\ndone".to_string()]);
+        assert_eq!(
+            remove_records(
+                b": 1:0;ls\n: 2:0;for i in 1 2\\\ndo echo $i\\\ndone\n: 3:0;make\n",
+                InputFormat::Zsh,
+                false,
+                &deleted
+            ),
+            b": 1:0;ls\n: 3:0;make\n"
+        );
+    }
+
+    #[test]
+    fn test_status_until_next_key() {
+        let mut prompt = test_prompt(&["ls"], 80);
+        prompt.status = Some("(no clipboard helper) ");
+        assert_eq!(prompt.prompt(), "(no clipboard helper) bck-i-search: ");
+        prompt.handle_key_press(Key::Char('l'));
+        assert_eq!(prompt.prompt(), "[1] bck-i-search: l");
+    }
+
+    #[test]
+    fn test_prefix() {
+        let mut prompt = test_prompt(&["make && cargo test", "cargo build", "ls"], 80);
+        prompt.set_input("cargo b".to_string());
+        prompt.handle_key_press(Key::Alt('a'));
+        assert_eq!(
+            prompt.current_entry.as_ref().unwrap().command,
+            "cargo build"
+        );
+        assert_eq!(prompt.prompt(), "[1] (prefix) bck-i-search: cargo b");
+        assert_eq!(prompt.highlight_spans("cargo build"), vec![0..7]);
+        assert_eq!(
+            prompt.handle_key_press(Key::Char('\n')),
+            PromptResult::Selected("cargo build".to_string(), Execute::Yes, 7)
+        );
+        // Containing the query isn't enough
+        prompt.set_input("cargo".to_string());
+        assert_eq!(
+            prompt.current_entry.as_ref().unwrap().command,
+            "cargo build"
+        );
+        prompt.update();
+        assert_eq!(prompt.current_entry, None);
+        prompt.handle_key_press(Key::Alt('a'));
+        assert_eq!(
+            prompt.current_entry.as_ref().unwrap().command,
+            "make && cargo test"
+        );
+    }
+
+    #[test]
+    fn test_loose_whitespace_highlights_across_lines() {
+        let config = Config {
+            loose_whitespace: true,
+            ..Config::default()
+        };
+        let mut prompt = test_prompt_with_config(&["git\n  commit -m x"], 80, config);
+        prompt.set_input(r"git\ commit".to_string());
+        assert_eq!(prompt.spans, vec![0..12]);
+        let output = prompt.stdout.take_output();
+        let highlight = |text| {
+            format!(
+                "{}{}{}{text}{}",
+                termion::color::Fg(termion::color::Red),
+                termion::style::Invert,
+                termion::style::Bold,
+                termion::style::Reset
+            )
+        };
+        // Each line highlights its part of the match, the indentation included
+        assert!(output.contains(&format!("\r\n{}", highlight("git"))));
+        assert!(output.contains(&format!("{} -m x", highlight("  commit"))));
+    }
+
+    #[test]
+    fn test_suffix_highlights_end_of_line() {
+        let config = Config {
+            suffix: true,
+            ..Config::default()
+        };
+        let mut prompt =
+            test_prompt_with_config(&["vim a.txt b.md", "cat a.TXT\necho txt"], 80, config);
+        prompt.set_input("txt".to_string());
+        let output = prompt.stdout.take_output();
+        let highlight = |color: &dyn termion::color::Color, text| {
+            format!(
+                "{}{}{}{text}{}",
+                termion::color::Fg(color),
+                termion::style::Invert,
+                termion::style::Bold,
+                termion::style::Reset
+            )
+        };
+        // The first occurrence is the current one
+        assert!(output.contains(&format!(
+            "\r\ncat a.{}",
+            highlight(&termion::color::LightRed, "TXT")
+        )));
+        assert!(output.contains(&format!(
+            "│ {}echo {}",
+            termion::style::Reset,
+            highlight(&termion::color::Red, "txt")
+        )));
+    }
+
+    #[test]
+    fn test_max_scan_marks_prompt_as_partial() {
+        let config = Config {
+            cycle_order: CycleOrder::Recent,
+            max_scan: Some(2),
+            ..Config::default()
+        };
+        let mut prompt = test_prompt_with_config(&["ls", "ls -l", "ls -la"], 80, config);
+        prompt.set_input("ls".to_string());
+        assert!(prompt.partial);
+        assert!(prompt.prompt().starts_with("[1/2] (partial) "));
+        prompt.update();
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "ls -l");
+        prompt.update();
+        assert_eq!(prompt.current_entry, None);
+    }
+
+    #[test]
+    fn test_print_line_skips_zero_width_spans() {
+        let mut output = Vec::new();
+        print_line("", "git push", &[0..0, 4..4], COLORED, &mut output);
+        assert_eq!(output, b"\r\ngit push");
+    }
+
+    #[test]
+    fn test_spans_found_with_match() {
+        let highlighted = |text| {
+            format!(
+                "{}{}{text}{}",
+                termion::style::Invert,
+                termion::style::Bold,
+                termion::style::Reset
+            )
+        };
+        // The current occurrence is underlined, too
+        let current = |text| {
+            format!(
+                "{}{}{}{text}{}",
+                termion::style::Invert,
+                termion::style::Bold,
+                termion::style::Underline,
+                termion::style::Reset
+            )
+        };
+        let config = Config {
+            no_color: true,
+            ..Config::default()
+        };
+        let history = ["cargo build", "git push origin\ngit log"];
+        let mut prompt = test_prompt_with_config(&history, 80, config);
+        prompt.set_input("or pu".to_string());
+        assert_eq!(prompt.spans, vec![4..6, 9..11]);
+        assert!(prompt.stdout.take_output().contains(&format!(
+            "git {}sh {}igin",
+            current("pu"),
+            highlighted("or")
+        )));
+
+        prompt.handle_key_press(Key::Ctrl('f'));
+        prompt.set_input("cbd".to_string());
+        assert_eq!(prompt.spans, vec![0..1, 6..7, 10..11]);
+        assert!(prompt.stdout.take_output().contains(&format!(
+            "{}argo {}uil{}",
+            current("c"),
+            highlighted("b"),
+            highlighted("d")
+        )));
+        // Fuzzy matches can continue on the next line
+        prompt.set_input("gpl".to_string());
+        assert_eq!(prompt.spans, vec![0..1, 4..5, 20..21]);
+        let output = prompt.stdout.take_output();
+        assert!(output.contains(&format!("{}it {}ush", current("g"), highlighted("p"))));
+        assert!(output.contains(&format!("git {}og", highlighted("l"))));
+    }
+
+    #[test]
+    fn test_split_lines() {
+        assert_eq!(
+            split_lines("git push\r\ngit log\n"),
+            vec![(0, "git push"), (10, "git log")]
+        );
+        assert_eq!(split_lines("ls\n\nls"), vec![(0, "ls"), (3, ""), (4, "ls")]);
+        assert!(split_lines("").is_empty());
+    }
+
+    #[test]
+    fn test_spans_on_line() {
+        // Lines of "git push\ngit log"
+        assert_eq!(spans_on_line(&[0..3, 9..12], 0, "git push"), vec![0..3]);
+        assert_eq!(spans_on_line(&[0..3, 9..12], 9, "git log"), vec![0..3]);
+        // Spans across lines are split
+        assert_eq!(
+            spans_on_line(&[0..1, 4..13], 0, "git push"),
+            vec![0..1, 4..8]
+        );
+        assert_eq!(spans_on_line(&[0..1, 4..13], 9, "git log"), vec![0..4]);
+    }
+
+    #[test]
+    fn test_print_line_highlights_spans() {
+        let mut output = Vec::new();
+        print_line("", "git push", &[0..3, 4..8], COLORED, &mut output);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("\r\n\x1b"));
+        assert_eq!(output.matches("\x1b[m").count(), 2);
+        assert!(output.ends_with(&format!("push{}", termion::style::Reset)));
+    }
+
+    #[test]
+    fn test_print_line_without_color() {
+        let mut output = Vec::new();
+        let style = HighlightStyle {
+            color: false,
+            ..COLORED
+        };
+        print_line("", "git push", &[0..3, 4..8], style, &mut output);
+        let highlighted = |text| {
+            format!(
+                "{}{}{text}{}",
+                termion::style::Invert,
+                termion::style::Bold,
+                termion::style::Reset
+            )
+        };
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!("\r\n{} {}", highlighted("git"), highlighted("push"))
+        );
+    }
+
+    #[test]
+    fn test_print_line_marks_current_span() {
+        let print = |style| {
+            let mut output = Vec::new();
+            print_line("", "ls; ls", &[0..2, 4..6], style, &mut output);
+            String::from_utf8(output).unwrap()
+        };
+        let output = print(HighlightStyle {
+            current: Some(1),
+            ..COLORED
+        });
+        assert!(output.contains(&format!(
+            "; {}{}",
+            termion::color::Fg(termion::color::LightRed),
+            termion::style::Invert
+        )));
+        let light_red = termion::color::Fg(termion::color::LightRed).to_string();
+        assert!(!print(COLORED).contains(&light_red));
+        let output = print(HighlightStyle {
+            color: false,
+            current: Some(0),
+            ..COLORED
+        });
+        assert_eq!(
+            output
+                .matches(&termion::style::Underline.to_string())
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_print_line_with_highlight() {
+        let print = |highlight, current| {
+            let mut output = Vec::new();
+            let style = HighlightStyle {
+                current,
+                highlight,
+                ..COLORED
+            };
+            print_line("", "ls; ls", &[0..2, 4..6], style, &mut output);
+            String::from_utf8(output).unwrap()
+        };
+        let green = Highlight::parse("fg=green,underline");
+        assert_eq!(
+            print(green, Some(1)),
+            format!(
+                "\r\n{}{}ls{}; {}{}ls{}",
+                termion::color::Fg(termion::color::Green),
+                termion::style::Underline,
+                termion::style::Reset,
+                termion::color::Fg(termion::color::LightGreen),
+                termion::style::Underline,
+                termion::style::Reset
+            )
+        );
+        // Without a light variant, the current match is underlined
+        let output = print(Highlight::parse("fg=208,bold"), Some(0));
+        assert!(output.starts_with(&format!(
+            "\r\n{}{}{}ls{}",
+            termion::color::Fg(termion::color::AnsiValue(208)),
+            termion::style::Bold,
+            termion::style::Underline,
+            termion::style::Reset
+        )));
+    }
+
+    #[test]
+    fn test_print_line_separates_adjacent_spans() {
+        let underlined = |separate| {
+            let mut output = Vec::new();
+            let style = HighlightStyle {
+                separate,
+                ..COLORED
+            };
+            print_line("", "abab", &[0..2, 2..4], style, &mut output);
+            String::from_utf8(output)
+                .unwrap()
+                .matches(&termion::style::Underline.to_string())
+                .count()
+        };
+        assert_eq!(underlined(false), 0);
+        assert_eq!(underlined(true), 1);
+    }
+
+    #[test]
+    fn test_sort_matches() {
+        let entry = |command: &str, timestamp| Entry {
+            command: command.to_string(),
+            timestamp,
+            ..Entry::default()
+        };
+        let sorted = sort_matches(vec![
+            (0.0, 0, entry("old", Some(1))),
+            (0.0, 1, entry("unknown", None)),
+            (0.0, 2, entry("new", Some(3))),
+            (1.0, 3, entry("word start", Some(2))),
+        ]);
+        let commands: Vec<_> = sorted
+            .iter()
+            .map(|(position, entry)| (*position, entry.command.as_str()))
+            .collect();
+        assert_eq!(
+            commands,
+            vec![(3, "word start"), (2, "new"), (0, "old"), (1, "unknown")]
+        );
+    }
+
+    #[test]
+    fn test_oldest_first() {
+        let entries =
+            ["ls -c", "ls -b", "ls -a", "cd"].map(|command| Entry::from(command.to_string()));
+        let mut history = ReusableIter::new(oldest_first(entries.into_iter(), 1).into_iter());
+        // The same again after a reset
+        for _ in 0..2 {
+            let commands: Vec<_> = history.by_ref().map(|entry| entry.command).collect();
+            assert_eq!(commands, vec!["cd", "ls -a", "ls -b"]);
+            history.reset();
+        }
+
+        let entries = ["ls -c", "ls -b", "ls -a"].map(|command| Entry::from(command.to_string()));
+        let history = oldest_first(entries.into_iter(), 0);
+        let mut prompt = Prompt::new(
+            TestTerminal::new(80, 24),
+            history.into_iter(),
+            Config::default(),
+        )
+        .unwrap();
+        prompt.set_input("ls".to_string());
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "ls -a");
+        prompt.update();
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "ls -b");
+        prompt.search_from_start();
+        assert_eq!(prompt.current_entry.as_ref().unwrap().command, "ls -a");
+    }
+
+    #[test]
+    fn test_sort_by_frequency() {
+        let entries = ["ls", "cd", "git status", "cd", "make", "ls", "cd"]
+            .map(|command| Entry::from(command.to_string()));
+        let sorted: Vec<_> = sort_by_frequency(entries.into_iter())
+            .into_iter()
+            .map(|entry| entry.command)
+            .collect();
+        // "ls" is more recent than "cd", and "git status" than "make"
+        assert_eq!(sorted, vec!["cd", "ls", "git status", "make"]);
+    }
+
+    #[test]
+    fn test_sort_by_frequency_counts_folded_repeats() {
+        let mut folded = Entry::from("make".to_string());
+        folded.repeats = 2;
+        let entries = [
+            Entry::from("ls".to_string()),
+            Entry::from("ls".to_string()),
+            folded,
+        ];
+        let sorted = sort_by_frequency(entries.into_iter());
+        assert_eq!(sorted[0].command, "make");
+        assert_eq!(sorted.len(), 2);
+    }
+
+    #[test]
+    fn test_text_width() {
+        assert_eq!(text_width("±5°", false), 3);
+        assert_eq!(text_width("±5°", true), 5);
+        assert_eq!(text_width("界", false), 2);
+    }
+
+    #[test]
+    fn test_redraw_with_wide_ambiguous_characters() {
+        let config = Config {
+            ambiguous_wide: true,
+            ..Config::default()
+        };
+        let mut prompt = test_prompt_with_config(&["echo °°°°°"], 12, config);
+        prompt.set_input("°".to_string());
+        let output = prompt.stdout.take_output();
+        // The prompt takes 20 columns, the entry 15, so both wrap
+        assert!(output.ends_with(&format!(
+            "{}\r{}",
+            termion::cursor::Up(2),
+            termion::cursor::Right(8)
+        )));
+    }
+
+    #[test]
+    fn test_wrap_line() {
+        assert_eq!(wrap_line("", 10, 0, 2, false), vec![0..0]);
+        assert_eq!(wrap_line("short", 10, 0, 2, false), vec![0..5]);
+        // Room for a prefix in the first row
+        assert_eq!(wrap_line("01234567abc", 10, 2, 2, false), vec![0..8, 8..11]);
+        assert_eq!(
+            wrap_line("0123456789abcdefghijkl", 10, 0, 2, false),
+            vec![0..10, 10..18, 18..22]
+        );
+        // Wide characters don't get split across rows
+        assert_eq!(wrap_line("012345678界", 10, 0, 2, false), vec![0..9, 9..12]);
+        // Neither do ambiguous characters if they're wide
+        assert_eq!(wrap_line("012345678°", 10, 0, 2, false), vec![0..11]);
+        assert_eq!(wrap_line("012345678°", 10, 0, 2, true), vec![0..9, 9..11]);
+    }
+
+    #[test]
+    fn test_query_window() {
+        let query = "abcdefghijklmnopqrst";
+        assert_eq!(query_window("short", 5, 10, false), 0..5);
+        assert_eq!(query_window(query, 20, 10, false), 11..20);
+        assert_eq!(query_window(query, 0, 10, false), 0..9);
+        // Scrolled to end at the cursor, with room for an ellipsis on both sides
+        assert_eq!(query_window(query, 15, 10, false), 7..15);
+        assert_eq!(query_window("界界界界界界", 18, 7, false), 9..18);
+    }
+
+    #[test]
+    fn test_truncate_line() {
+        let line = "0123456789abcdefghij";
+        assert_eq!(truncate_line("short", None, 10, false), 0..5);
+        assert_eq!(truncate_line(line, None, 10, false), 0..9);
+        assert_eq!(truncate_line(line, Some(2..4), 10, false), 0..9);
+        // A match near the end is scrolled into view, up to the trailing ellipsis
+        assert_eq!(truncate_line(line, Some(16..18), 10, false), 10..18);
+        assert_eq!(truncate_line(line, Some(18..20), 10, false), 11..20);
+        // Too long to show completely, so at least its start is shown
+        assert_eq!(truncate_line(line, Some(5..20), 10, false), 5..13);
+        assert_eq!(truncate_line("界界界界界界", Some(15..18), 7, false), 9..18);
+        // The ellipsis itself is wide then
+        assert_eq!(truncate_line(line, None, 10, true), 0..8);
+    }
+
+    #[test]
+    fn test_redraw_truncates_long_lines() {
+        let config = Config {
+            truncate: true,
+            ..Config::default()
+        };
+        let entry = format!("echo {} | grep push", "a".repeat(40));
+        let mut prompt = test_prompt_with_config(&[&entry], 30, config);
+        prompt.set_input("grep".to_string());
+        let output = prompt.stdout.take_output();
+        // Scrolled to the match, in a single row
+        assert!(output.contains(&format!(
+            "{faint}…{reset}{} | ",
+            "a".repeat(21),
+            faint = termion::style::Faint,
+            reset = termion::style::Reset
+        )));
+        assert!(output.ends_with(&format!(
+            "grep{reset}{}…{reset}{}\r{}",
+            termion::style::Faint,
+            termion::cursor::Up(1),
+            termion::cursor::Right(22),
+            reset = termion::style::Reset
+        )));
+        // Only the display is truncated
+        assert!(matches!(
+            prompt.handle_key_press(Key::Char('\n')),
+            PromptResult::Selected(command, ..) if command == entry
+        ));
+    }
+
+    #[test]
+    fn test_wrap_position() {
+        assert_eq!(wrap_position("", 0, 10, false), (0, 0));
+        assert_eq!(wrap_position("0123456789", 0, 10, false), (0, 10));
+        assert_eq!(wrap_position("0123456789a", 0, 10, false), (1, 1));
+        assert_eq!(wrap_position("012345", 6, 10, false), (1, 2));
+        // 20 columns, but the wide character doesn't fit into the last column of the first row
+        assert_eq!(wrap_position("012345678界012345678", 0, 10, false), (2, 1));
+        assert_eq!(wrap_position("01234567界", 0, 10, false), (0, 10));
+        // Ambiguous characters only if they're wide
+        assert_eq!(wrap_position("012345678°", 0, 10, false), (0, 10));
+        assert_eq!(wrap_position("012345678°", 0, 10, true), (1, 2));
+        // Combining characters stay with the character before them, also at the end of a row
+        assert_eq!(wrap_position(&"e\u{301}".repeat(10), 0, 10, false), (0, 10));
+        assert_eq!(wrap_position(&"e\u{301}".repeat(11), 0, 10, false), (1, 1));
+    }
+
+    #[test]
+    fn test_redraw_with_wide_character_at_end_of_row() {
+        let entry = format!("echo {}界{}", "0".repeat(24), "1".repeat(29));
+        let mut prompt = test_prompt(&[&entry], 30);
+        prompt.set_input("echo".to_string());
+        // 60 columns, but three rows
+        assert!(prompt.stdout.take_output().ends_with(&format!(
+            "{}\r{}",
+            termion::cursor::Up(3),
+            termion::cursor::Right(22)
+        )));
+
+        // The query's cursor moves to the next row along with the wide character
+        let mut prompt = test_prompt(&["ls"], 17);
+        prompt.set_input("界界".to_string());
+        assert!(prompt.stdout.take_output().ends_with(&format!(
+            "{}\r{}",
+            termion::cursor::Up(1),
+            termion::cursor::Right(2)
+        )));
+    }
+
+    #[test]
+    fn test_print_wrapped_line() {
+        let mut output = Vec::new();
+        print_wrapped_line(
+            "> ",
+            "01234567abc",
+            &[0..8, 8..11],
+            &[],
+            COLORED,
+            2,
+            &mut output,
+        );
+        assert_eq!(output, b"\r\n> 01234567\r\n  abc");
+    }
+
+    #[test]
+    fn test_redraw_moves_cursor_back_to_prompt() {
+        let mut prompt = test_prompt(&["ls", "git push"], 80);
+        prompt.set_input("push".to_string());
+        let output = prompt.stdout.take_output();
+        assert!(output.contains("[1] bck-i-search: push\r\ngit "));
+        assert!(output.ends_with(&format!(
+            "{}\r{}",
+            termion::cursor::Up(1),
+            termion::cursor::Right(22)
+        )));
+    }
+
+    #[test]
+    fn test_continuation_marker_height() {
+        let history = ["echo 1\necho 22222\necho 3"];
+        let mut prompt = test_prompt(&history, 10);
+        prompt.set_input("2".to_string());
+        let output = prompt.stdout.take_output();
+        assert!(output.contains(&format!(
+            "\r\n{}│ {}echo ",
+            termion::style::Faint,
+            termion::style::Reset
+        )));
+        // The marker makes the second line one column too wide, so it takes two rows
+        assert!(output.ends_with(&format!(
+            "{}\r{}",
+            termion::cursor::Up(4),
+            termion::cursor::Right(9)
+        )));
+
+        let config = Config {
+            continuation_marker: Some(String::new()),
+            ..Config::default()
+        };
+        let mut prompt = test_prompt_with_config(&history, 10, config);
+        prompt.set_input("2".to_string());
+        assert!(prompt.stdout.take_output().ends_with(&format!(
+            "{}\r{}",
+            termion::cursor::Up(3),
+            termion::cursor::Right(9)
+        )));
+    }
+
+    #[test]
+    fn test_redraw_counts_wrapped_rows() {
+        let mut prompt = test_prompt(&["echo one\necho twotwotwo"], 10);
+        prompt.set_input("two".to_string());
+        let output = prompt.stdout.take_output();
+        // The second line of the entry wraps once, the prompt occupies three rows
+        assert!(output.ends_with(&format!(
+            "{}\r{}",
+            termion::cursor::Up(3),
+            termion::cursor::Right(1)
+        )));
+        prompt.redraw();
+        let output = prompt.stdout.take_output();
+        assert!(output.starts_with(&format!("\r{}", termion::cursor::Up(2))));
     }
 }
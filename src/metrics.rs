@@ -0,0 +1,68 @@
+use std::{fs::OpenOptions, io::Write, path::Path, time::Duration};
+
+use kontrolleurs::config::{Config, CycleOrder, HistorySort, MatchMode};
+
+/// Appends a single line with the time from launch to selection, how the search ended and the
+/// active search mode to the file at `path`.
+pub fn record(path: &Path, elapsed: Duration, outcome: &str, mode: &str) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(format_record(elapsed, outcome, mode).as_bytes())
+}
+
+/// Describes the search mode of `config`, e.g. `recent+suffix`.
+pub fn mode(config: &Config) -> String {
+    let mut mode = match config.cycle_order {
+        CycleOrder::History => "history",
+        CycleOrder::Recent => "recent",
+    }
+    .to_string();
+    if config.sort == HistorySort::Frequency {
+        mode.push_str("+frequency");
+    }
+    match config.match_mode {
+        MatchMode::Substring => (),
+        MatchMode::Fuzzy => mode.push_str("+fuzzy"),
+        MatchMode::Glob => mode.push_str("+glob"),
+    }
+    if config.prefix {
+        mode.push_str("+prefix");
+    }
+    if config.suffix {
+        mode.push_str("+suffix");
+    }
+    mode
+}
+
+fn format_record(elapsed: Duration, outcome: &str, mode: &str) -> String {
+    format!(
+        "elapsed_ms={} outcome={outcome} mode={mode}\n",
+        elapsed.as_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{format_record, mode};
+    use kontrolleurs::config::{Config, CycleOrder};
+
+    #[test]
+    fn test_format_record() {
+        assert_eq!(
+            format_record(Duration::from_micros(1_234_567), "executed", "history"),
+            "elapsed_ms=1234 outcome=executed mode=history\n"
+        );
+    }
+
+    #[test]
+    fn test_mode() {
+        assert_eq!(mode(&Config::default()), "history");
+        let config = Config {
+            cycle_order: CycleOrder::Recent,
+            suffix: true,
+            ..Config::default()
+        };
+        assert_eq!(mode(&config), "recent+suffix");
+    }
+}
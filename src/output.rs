@@ -0,0 +1,260 @@
+use std::{fmt, io::Write, ops::Range, os::unix::net::UnixStream, path::Path};
+
+use kontrolleurs::config::OutputFormat;
+
+/// Whether the shell should run the selected entry.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Execute {
+    No,
+    Yes,
+    /// Run it in the background, i.e. with a suffix like ` &` appended
+    Background,
+}
+
+impl fmt::Display for Execute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::No => "false",
+            Self::Yes => "true",
+            Self::Background => "background",
+        })
+    }
+}
+
+/// Writes the selected `entry` in `format`. `cursor` is the cursor position in characters.
+/// `matched` is the byte range of the match in `entry`, which only the JSON format includes.
+/// `background_suffix` is appended to the entry by the fish format when running it in the
+/// background, the lines format leaves that to the shell.
+pub fn write_selection(
+    out: &mut impl Write,
+    format: OutputFormat,
+    entry: &str,
+    execute: Execute,
+    cursor: usize,
+    matched: Option<Range<usize>>,
+    background_suffix: &str,
+) -> std::io::Result<()> {
+    match format {
+        OutputFormat::Lines => write!(out, "{execute}\n{cursor}\n{entry}\0"),
+        OutputFormat::Fish => {
+            writeln!(out, "commandline -rb -- {}", fish_quote(entry))?;
+            writeln!(out, "commandline -C {cursor}")?;
+            if execute == Execute::Background {
+                writeln!(out, "commandline -a -- {}", fish_quote(background_suffix))?;
+            }
+            if execute != Execute::No {
+                writeln!(out, "commandline -f execute")?;
+            }
+            Ok(())
+        }
+        OutputFormat::Json => {
+            let (start, end) = matched.map_or(("null".to_string(), "null".to_string()), |span| {
+                (span.start.to_string(), span.end.to_string())
+            });
+            write!(out, r#"{{"entry":{},"#, json_string(entry))?;
+            write!(out, r#""execute":{},"#, execute != Execute::No)?;
+            write!(out, r#""background":{},"#, execute == Execute::Background)?;
+            write!(out, r#""cursor":{cursor},"#)?;
+            writeln!(out, r#""match_start":{start},"match_end":{end}}}"#)
+        }
+    }
+}
+
+/// Connects to the Unix domain socket at `socket`, if given, and falls back to stdout if that's
+/// not possible.
+pub fn connect_or_stdout(socket: Option<&Path>) -> Box<dyn Write> {
+    match socket.map(UnixStream::connect) {
+        Some(Ok(stream)) => Box::new(stream),
+        Some(Err(err)) => {
+            eprintln!("[WARN] Could not connect to socket, writing to stdout instead: {err}");
+            Box::new(std::io::stdout())
+        }
+        None => Box::new(std::io::stdout()),
+    }
+}
+
+/// Converts a byte offset into `text` to the number of characters in front of it, which is
+/// what fish uses for cursor positions. Offsets past the end are clamped to the end.
+pub fn char_offset(text: &str, byte_offset: usize) -> usize {
+    text.char_indices()
+        .take_while(|(pos, _)| *pos < byte_offset)
+        .count()
+}
+
+/// Quotes `text` as JSON string.
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for ch in text.chars() {
+        match ch {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            ch if ch.is_control() => quoted.push_str(&format!("\\u{:04x}", u32::from(ch))),
+            ch => quoted.push(ch),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Quotes `text` as single fish argument.
+fn fish_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, os::unix::net::UnixListener};
+
+    use super::{char_offset, connect_or_stdout, json_string, write_selection, Execute};
+    use kontrolleurs::config::OutputFormat;
+
+    fn selection(format: OutputFormat, entry: &str, execute: Execute, cursor: usize) -> String {
+        let mut out = Vec::new();
+        write_selection(&mut out, format, entry, execute, cursor, None, " &").unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_lines() {
+        assert_eq!(
+            selection(OutputFormat::Lines, "ls\n-l", Execute::Yes, 2),
+            "true\n2\nls\n-l\0"
+        );
+        assert!(selection(OutputFormat::Lines, "ls", Execute::Background, 2)
+            .starts_with("background\n"));
+    }
+
+    #[test]
+    fn test_fish() {
+        assert_eq!(
+            selection(OutputFormat::Fish, r"echo 'it\'s'", Execute::No, 4),
+            "commandline -rb -- 'echo \\'it\\\\\\'s\\''\ncommandline -C 4\n"
+        );
+        assert!(selection(OutputFormat::Fish, "ls", Execute::Yes, 2)
+            .ends_with("commandline -C 2\ncommandline -f execute\n"));
+        assert!(
+            selection(OutputFormat::Fish, "make", Execute::Background, 4)
+                .ends_with("commandline -a -- ' &'\ncommandline -f execute\n")
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        assert_eq!(
+            selection(OutputFormat::Json, "ls", Execute::Background, 2),
+            "{\"entry\":\"ls\",\"execute\":true,\"background\":true,\"cursor\":2,\
+             \"match_start\":null,\"match_end\":null}\n"
+        );
+        let mut out = Vec::new();
+        let matched = Some(5..9);
+        write_selection(
+            &mut out,
+            OutputFormat::Json,
+            "git push",
+            Execute::No,
+            8,
+            matched,
+            "",
+        )
+        .unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .ends_with(",\"cursor\":8,\"match_start\":5,\"match_end\":9}\n"));
+        // The other formats leave it out
+        let mut out = Vec::new();
+        write_selection(
+            &mut out,
+            OutputFormat::Lines,
+            "ls",
+            Execute::No,
+            2,
+            Some(0..2),
+            "",
+        )
+        .unwrap();
+        assert_eq!(out, b"false\n2\nls\0");
+        assert_eq!(
+            json_string("say \"hi\"\\\n\x1b"),
+            r#""say \"hi\"\\\n\u001b""#
+        );
+    }
+
+    /// Decodes a JSON string as written by `json_string`.
+    fn unquote_json(quoted: &str) -> String {
+        let mut chars = quoted
+            .strip_prefix('"')
+            .and_then(|rest| rest.strip_suffix('"'))
+            .unwrap()
+            .chars();
+        let mut text = String::new();
+        while let Some(ch) = chars.next() {
+            if ch != '\\' {
+                text.push(ch);
+                continue;
+            }
+            text.push(match chars.next().unwrap() {
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                'u' => {
+                    let code: String = chars.by_ref().take(4).collect();
+                    char::from_u32(u32::from_str_radix(&code, 16).unwrap()).unwrap()
+                }
+                ch => ch,
+            });
+        }
+        text
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        for entry in [
+            r#"echo "it's" \"quoted\""#,
+            "printf 'a\\nb'\necho done",
+            "cat <<EOF\n\tindented\r\nEOF\x07",
+        ] {
+            let json = selection(OutputFormat::Json, entry, Execute::No, 0);
+            let quoted = json
+                .strip_prefix(r#"{"entry":"#)
+                .and_then(|rest| rest.split_once(r#","execute""#))
+                .unwrap()
+                .0;
+            assert!(!quoted.contains('\n'));
+            assert_eq!(unquote_json(quoted), entry);
+        }
+    }
+
+    #[test]
+    fn test_connect_to_socket() {
+        let path = std::env::temp_dir().join(format!("kontrolleurs-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let mut out = connect_or_stdout(Some(&path));
+        write_selection(&mut out, OutputFormat::Json, "ls", Execute::No, 0, None, "").unwrap();
+        drop(out);
+        let mut received = String::new();
+        listener
+            .accept()
+            .unwrap()
+            .0
+            .read_to_string(&mut received)
+            .unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(received.starts_with("{\"entry\":\"ls\""));
+    }
+
+    #[test]
+    fn test_char_offset() {
+        assert_eq!(char_offset("grün", 0), 0);
+        assert_eq!(char_offset("grün", 4), 3);
+        assert_eq!(char_offset("grün", 65536), 4);
+        assert_eq!(char_offset("git status", 3), 3);
+        assert_eq!(char_offset("echo café", 11), 9);
+        assert_eq!(char_offset("echo 日本語 | wc", 14), 8);
+        assert_eq!(char_offset("echo 日本語 | wc", 19), 13);
+    }
+}
@@ -0,0 +1,302 @@
+use crate::config::MatchMode;
+
+/// Scores of preferred matches, e.g. at the start of a word. In history order, preferred matches
+/// are cycled through before all others.
+pub const PREFERRED: f64 = 1.0;
+
+/// A strategy to decide whether and how well an entry matches the query.
+pub trait Scorer {
+    /// Returns `None` if `entry` doesn't match `query`, otherwise a score where higher is better.
+    fn score(&self, entry: &str, query: &str) -> Option<f64>;
+}
+
+/// Matches entries containing the query, preferring occurrences at the start of a word.
+pub struct WordStart;
+
+impl Scorer for WordStart {
+    fn score(&self, entry: &str, query: &str) -> Option<f64> {
+        find_word_start(entry, query).map(score)
+    }
+}
+
+/// Matches entries where the query is at the end of one of its lines, preferring occurrences
+/// that start a word.
+pub struct Suffix;
+
+impl Scorer for Suffix {
+    fn score(&self, entry: &str, query: &str) -> Option<f64> {
+        find_suffix(entry, query).map(score)
+    }
+}
+
+/// Matches entries where one of the lines starts with the query, preferring the first line.
+pub struct Prefix;
+
+impl Scorer for Prefix {
+    fn score(&self, entry: &str, query: &str) -> Option<f64> {
+        let line = entry.lines().position(|line| line.starts_with(query))?;
+        Some(score(line == 0))
+    }
+}
+
+/// Matches entries where one of the lines is the query, preferring the first line.
+pub struct Line;
+
+impl Scorer for Line {
+    fn score(&self, entry: &str, query: &str) -> Option<f64> {
+        let line = entry.lines().position(|line| line == query)?;
+        Some(score(line == 0))
+    }
+}
+
+/// Matches entries containing all characters of the query in order, but not necessarily next
+/// to each other. Matches starting at a word are preferred, and more compact matches score
+/// higher.
+pub struct Fuzzy;
+
+impl Scorer for Fuzzy {
+    fn score(&self, entry: &str, query: &str) -> Option<f64> {
+        let mut remaining = query.chars().peekable();
+        let mut matched = None;
+        for (pos, ch) in entry.char_indices() {
+            let Some(&next) = remaining.peek() else {
+                break;
+            };
+            if next == ch {
+                let (start, _) = matched.unwrap_or((pos, pos));
+                matched = Some((start, pos + ch.len_utf8()));
+                remaining.next();
+            }
+        }
+        if remaining.peek().is_some() {
+            return None;
+        }
+        let Some((start, end)) = matched else {
+            // Everything matches the empty query
+            return Some(PREFERRED);
+        };
+        let word_start = entry[..start]
+            .chars()
+            .next_back()
+            .is_none_or(|ch| !ch.is_alphanumeric());
+        let compactness = query.chars().count() as f64 / entry[start..end].chars().count() as f64;
+        Some(score(word_start) + compactness / 2.0)
+    }
+}
+
+/// Matches entries containing text that matches the query as glob pattern, i.e. where `*`
+/// matches any (possibly empty) text and `?` any single character. Matches starting at a word
+/// are preferred.
+pub struct Glob;
+
+impl Scorer for Glob {
+    fn score(&self, entry: &str, query: &str) -> Option<f64> {
+        // Leading and consecutive stars don't change what matches
+        let mut segments = query.split('*').filter(|segment| !segment.is_empty());
+        let Some(first) = segments.next() else {
+            return Some(PREFERRED);
+        };
+        let rest: Vec<_> = segments.collect();
+        let mut found = None;
+        for (start, _) in entry.char_indices() {
+            let Some(len) = match_segment(&entry[start..], first) else {
+                continue;
+            };
+            let mut end = start + len;
+            let all_found = rest.iter().all(|segment| {
+                find_segment(&entry[end..], segment).is_some_and(|segment_end| {
+                    end += segment_end;
+                    true
+                })
+            });
+            if !all_found {
+                // Later segments won't be found after later starts either
+                break;
+            }
+            if entry[..start]
+                .chars()
+                .next_back()
+                .is_none_or(|ch| !ch.is_alphanumeric())
+            {
+                return Some(PREFERRED);
+            }
+            found = Some(0.0);
+        }
+        found
+    }
+}
+
+/// Returns the scorer for `mode`. `prefix` and `suffix` only apply to substring matching.
+pub fn for_mode(mode: MatchMode, prefix: bool, suffix: bool) -> Box<dyn Scorer> {
+    match mode {
+        MatchMode::Substring if prefix && suffix => Box::new(Line),
+        MatchMode::Substring if prefix => Box::new(Prefix),
+        MatchMode::Substring if suffix => Box::new(Suffix),
+        MatchMode::Substring => Box::new(WordStart),
+        MatchMode::Fuzzy => Box::new(Fuzzy),
+        MatchMode::Glob => Box::new(Glob),
+    }
+}
+
+/// Returns the length of the start of `text` that matches `segment`, a glob pattern without
+/// stars, if any.
+fn match_segment(text: &str, segment: &str) -> Option<usize> {
+    let mut text_chars = text.char_indices();
+    for pattern_ch in segment.chars() {
+        let (_, ch) = text_chars.next()?;
+        if pattern_ch != '?' && pattern_ch != ch {
+            return None;
+        }
+    }
+    Some(text_chars.next().map_or(text.len(), |(pos, _)| pos))
+}
+
+/// Returns where the first match of `segment`, a glob pattern without stars, in `text` ends.
+fn find_segment(text: &str, segment: &str) -> Option<usize> {
+    text.char_indices()
+        .find_map(|(start, _)| Some(start + match_segment(&text[start..], segment)?))
+}
+
+fn score(preferred: bool) -> f64 {
+    if preferred {
+        PREFERRED
+    } else {
+        0.0
+    }
+}
+
+/// Returns `None` if `needle` doesn't occur in `text`, otherwise whether any occurrence is at the
+/// start of a word.
+fn find_word_start(text: &str, needle: &str) -> Option<bool> {
+    let mut found = None;
+    for (pos, _) in text.match_indices(needle) {
+        if text[..pos]
+            .chars()
+            .next_back()
+            .is_none_or(|ch| !ch.is_alphanumeric())
+        {
+            return Some(true);
+        }
+        found = Some(false);
+    }
+    found
+}
+
+/// Like `find_word_start`, but only considers occurrences at the end of one of the lines of
+/// `text`.
+fn find_suffix(text: &str, needle: &str) -> Option<bool> {
+    let mut found = None;
+    for line in text.lines() {
+        if let Some(rest) = line.strip_suffix(needle) {
+            if rest
+                .chars()
+                .next_back()
+                .is_none_or(|ch| !ch.is_alphanumeric())
+            {
+                return Some(true);
+            }
+            found = Some(false);
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        find_suffix, find_word_start, Fuzzy, Glob, Line, Prefix, Scorer, Suffix, WordStart,
+    };
+
+    fn ordered(scorer: &impl Scorer, entries: &[&str], query: &str) -> Vec<String> {
+        let mut scored: Vec<_> = entries
+            .iter()
+            .filter_map(|entry| Some((scorer.score(entry, query)?, entry.to_string())))
+            .collect();
+        scored.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        scored.into_iter().map(|(_, entry)| entry).collect()
+    }
+
+    #[test]
+    fn test_find_word_start() {
+        assert_eq!(find_word_start("cargo build", "cargo"), Some(true));
+        assert_eq!(find_word_start("cd x && cargo build", "cargo"), Some(true));
+        assert_eq!(find_word_start("/usr/bin/ls -l", "ls"), Some(true));
+        assert_eq!(find_word_start("false", "ls"), Some(false));
+        assert_eq!(find_word_start("false; ls", "ls"), Some(true));
+        assert_eq!(find_word_start("git push", "ls"), None);
+        assert_eq!(find_word_start("git push", ""), Some(true));
+    }
+
+    #[test]
+    fn test_find_suffix() {
+        assert_eq!(find_suffix("cat notes.txt", ".txt"), Some(false));
+        assert_eq!(find_suffix("cat notes.txt", "notes.txt"), Some(true));
+        assert_eq!(find_suffix("cat notes.txt\nls", "s.txt"), Some(false));
+        assert_eq!(find_suffix("cat notes.txt.bak", ".txt"), None);
+    }
+
+    #[test]
+    fn test_word_start_ordering() {
+        assert_eq!(
+            ordered(&WordStart, &["false", "git push", "ls -l"], "ls"),
+            vec!["ls -l", "false"]
+        );
+    }
+
+    #[test]
+    fn test_fuzzy_ordering() {
+        assert_eq!(
+            ordered(
+                &Fuzzy,
+                &["cargo", "git checkout", "gco main", "echo logcon"],
+                "gco"
+            ),
+            vec!["gco main", "git checkout", "echo logcon"]
+        );
+        assert_eq!(Fuzzy.score("git commit", "gcx"), None);
+        assert_eq!(Fuzzy.score("ocg", "gco"), None);
+    }
+
+    #[test]
+    fn test_glob_ordering() {
+        assert_eq!(
+            ordered(
+                &Glob,
+                &["git pull", "git push -f", "echo digit; push", "push git"],
+                "git*push"
+            ),
+            vec!["git push -f", "echo digit; push"]
+        );
+        assert_eq!(Glob.score("git push", "g?t p*"), Some(1.0));
+        assert_eq!(Glob.score("gt push", "g?t"), None);
+        assert_eq!(Glob.score("ls", "*"), Some(1.0));
+        assert_eq!(Glob.score("make all", "**all"), Some(1.0));
+        assert_eq!(Glob.score("grün", "gr?n"), Some(1.0));
+    }
+
+    #[test]
+    fn test_prefix_ordering() {
+        assert_eq!(
+            ordered(
+                &Prefix,
+                &["make && cargo test", "echo\ncargo build", "cargo run"],
+                "cargo"
+            ),
+            vec!["cargo run", "echo\ncargo build"]
+        );
+        assert_eq!(Line.score("cargo run", "cargo"), None);
+        assert_eq!(Line.score("cd x\ncargo", "cargo"), Some(0.0));
+    }
+
+    #[test]
+    fn test_suffix_ordering() {
+        assert_eq!(
+            ordered(&Suffix, &["vim notes.txt", "cat a.txt", "ls txt/"], "a.txt"),
+            vec!["cat a.txt"]
+        );
+        assert_eq!(
+            ordered(&Suffix, &["cat data.txt", "cat a.txt"], "a.txt"),
+            vec!["cat a.txt", "cat data.txt"]
+        );
+    }
+}
@@ -0,0 +1,443 @@
+use std::{borrow::Cow, cmp::Reverse, ops::Range};
+
+use regex::{Regex, RegexBuilder};
+
+use crate::{
+    config::{CaseMode, MatchMode},
+    entry::Entry,
+    score::{self, Scorer},
+};
+
+/// How big the highlight regex may get when compiled, in bytes. Huge queries (e.g. pasted by
+/// accident) don't get highlighted instead of taking ages to compile and match.
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+/// How a query is matched against entries.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MatchOptions {
+    pub mode: MatchMode,
+    pub case: CaseMode,
+    /// Only match at the start of an entry or of one of its lines, with substring matching
+    pub prefix: bool,
+    /// Only match at the end of an entry or of one of its lines, with substring matching
+    pub suffix: bool,
+    /// Whitespace in the query matches any run of whitespace, including line breaks
+    pub loose_whitespace: bool,
+}
+
+/// An entry matching a query.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Match {
+    pub entry: Entry,
+    /// Higher is better, see `Scorer::score`
+    pub score: f64,
+    /// The byte ranges of the entry's command to highlight, in order
+    pub spans: Vec<Range<usize>>,
+}
+
+/// A query prepared for matching entries and finding what to highlight in them.
+pub struct Matcher {
+    case_sensitive: bool,
+    /// Whether whitespace runs are collapsed before matching, see `MatchOptions`
+    loose_whitespace: bool,
+    /// The terms that all have to match, lowercase unless matching case-sensitively
+    terms: Vec<String>,
+    /// The terms that must not match, like `terms`
+    excluded: Vec<String>,
+    scorer: Box<dyn Scorer>,
+    /// The terms as regex, to highlight matches. `None` if it's too big, see `REGEX_SIZE_LIMIT`
+    highlight: Option<Regex>,
+}
+
+impl Matcher {
+    /// Prepares `query`. The query is split into terms (see `split_terms`), except when only
+    /// matching at the start or end, where it's matched as a whole.
+    pub fn new(query: &str, options: MatchOptions) -> Self {
+        let anchored = (options.prefix || options.suffix) && options.mode == MatchMode::Substring;
+        let (terms, excluded) = if anchored {
+            let terms = if query.is_empty() {
+                Vec::new()
+            } else {
+                vec![query.to_string()]
+            };
+            (terms, Vec::new())
+        } else {
+            let (mut terms, excluded) = split_terms(query);
+            if options.mode == MatchMode::Glob {
+                // Stars on their own match everything, like no term at all
+                terms.retain(|term| !term.chars().all(|ch| ch == '*'));
+            }
+            (terms, excluded)
+        };
+        let (terms, excluded) = if options.loose_whitespace {
+            let collapse = |terms: Vec<String>| -> Vec<_> {
+                terms.iter().map(|term| collapse_whitespace(term)).collect()
+            };
+            (collapse(terms), collapse(excluded))
+        } else {
+            (terms, excluded)
+        };
+        let case_sensitive = options.case.is_sensitive(query);
+        let highlight = highlight_regex(&terms, options, case_sensitive).ok();
+        let normalize = |terms: Vec<String>| -> Vec<_> {
+            terms
+                .into_iter()
+                .map(|term| {
+                    if case_sensitive {
+                        term
+                    } else {
+                        term.to_lowercase()
+                    }
+                })
+                .collect()
+        };
+        Self {
+            case_sensitive,
+            loose_whitespace: options.loose_whitespace,
+            terms: normalize(terms),
+            excluded: normalize(excluded),
+            scorer: score::for_mode(options.mode, options.prefix, options.suffix),
+            highlight,
+        }
+    }
+
+    /// The terms that all have to match. Without any terms, everything matches.
+    pub fn terms(&self) -> &[String] {
+        &self.terms
+    }
+
+    /// Returns `None` if `text` doesn't match, otherwise its score. The worst matching term
+    /// determines the score.
+    pub fn score(&self, text: &str) -> Option<f64> {
+        let text = if self.case_sensitive {
+            Cow::Borrowed(text)
+        } else {
+            Cow::Owned(text.to_lowercase())
+        };
+        let text = if self.loose_whitespace {
+            Cow::Owned(collapse_whitespace(&text))
+        } else {
+            text
+        };
+        if self
+            .excluded
+            .iter()
+            .any(|term| self.scorer.score(&text, term).is_some())
+        {
+            return None;
+        }
+        // Browsing, everything matches
+        if self.terms.is_empty() {
+            return Some(score::PREFERRED);
+        }
+        self.terms.iter().try_fold(f64::INFINITY, |worst, term| {
+            Some(worst.min(self.scorer.score(&text, term)?))
+        })
+    }
+
+    /// The regex to highlight matches with, unless the query is too big for it. Matching
+    /// doesn't need it.
+    pub fn highlight(&self) -> Option<&Regex> {
+        self.highlight.as_ref()
+    }
+
+    /// Returns the byte ranges in `text` to highlight. Excluded terms aren't highlighted, and
+    /// nothing is without a highlight regex.
+    pub fn spans(&self, text: &str) -> Vec<Range<usize>> {
+        match &self.highlight {
+            Some(regex) => match_ranges(text, regex),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Returns the entries matching `query`, in their original order, with the spans to highlight.
+pub fn search(
+    entries: impl IntoIterator<Item = Entry>,
+    query: &str,
+    options: MatchOptions,
+) -> impl Iterator<Item = Match> {
+    let matcher = Matcher::new(query, options);
+    entries.into_iter().filter_map(move |entry| {
+        let score = matcher.score(&entry.command)?;
+        let mut spans = matcher.spans(&entry.command);
+        spans.retain(|span| !span.is_empty());
+        Some(Match {
+            entry,
+            score,
+            spans,
+        })
+    })
+}
+
+/// Returns a regex matching any of `terms`, as `options` match them. Fails if the regex gets
+/// too big.
+fn highlight_regex(
+    terms: &[String],
+    options: MatchOptions,
+    case_sensitive: bool,
+) -> Result<Regex, regex::Error> {
+    let flags = if case_sensitive { "" } else { "(?i)" };
+    // The terms' whitespace is collapsed to single spaces already
+    let space = if options.loose_whitespace {
+        r"\s+"
+    } else {
+        " "
+    };
+    let mut terms = terms.to_vec();
+    // Prefer the longest term if several match at the same position
+    terms.sort_by_key(|term| Reverse(term.len()));
+    let alternatives: Vec<_> = match options.mode {
+        MatchMode::Substring => {
+            let start = if options.prefix { "^" } else { "" };
+            let end = if options.suffix { "$" } else { "" };
+            terms
+                .iter()
+                .map(|term| {
+                    let escaped = regex::escape(term).replace(' ', space);
+                    format!("{start}{escaped}{end}")
+                })
+                .collect()
+        }
+        // Each character is a group of its own, so only the characters get highlighted
+        MatchMode::Fuzzy => terms
+            .iter()
+            .map(|term| {
+                let chars: Vec<_> = term
+                    .chars()
+                    .map(|ch| match ch {
+                        ' ' => format!("({space})"),
+                        ch => format!("({})", regex::escape(ch.encode_utf8(&mut [0; 4]))),
+                    })
+                    .collect();
+                format!("(?s:{})", chars.join(".*?"))
+            })
+            .collect(),
+        // Without leading stars, highlights start where the glob's text does
+        MatchMode::Glob => terms
+            .iter()
+            .map(|term| {
+                term.trim_start_matches('*')
+                    .chars()
+                    .map(|ch| match ch {
+                        '*' => "(?s:.*?)".to_string(),
+                        '?' => "(?s:.)".to_string(),
+                        ' ' => space.to_string(),
+                        ch => regex::escape(ch.encode_utf8(&mut [0; 4])),
+                    })
+                    .collect()
+            })
+            .collect(),
+    };
+    // The terms are escaped, so only the size can be a problem
+    RegexBuilder::new(&format!("(?m){flags}(?:{})", alternatives.join("|")))
+        .size_limit(REGEX_SIZE_LIMIT)
+        .dfa_size_limit(REGEX_SIZE_LIMIT)
+        .build()
+}
+
+/// Replaces each run of whitespace in `text` by a single space.
+fn collapse_whitespace(text: &str) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(ch) = chars.next() {
+        if ch.is_whitespace() {
+            while chars.next_if(|next| next.is_whitespace()).is_some() {}
+            collapsed.push(' ');
+        } else {
+            collapsed.push(ch);
+        }
+    }
+    collapsed
+}
+
+/// Splits `query` into words at whitespace. Whitespace with a backslash in front is part of a
+/// word instead.
+pub fn split_words(query: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut chars = query.chars().peekable();
+    while let Some(ch) = chars.next() {
+        if ch == '\\' {
+            word.push(chars.next_if(|next| next.is_whitespace()).unwrap_or(ch));
+        } else if ch.is_whitespace() {
+            if !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+        } else {
+            word.push(ch);
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+/// Splits `query` into the words that have to match and the words that must not, which start
+/// with `!` (without a word after it, `!` is ignored). A backslash in front of the `!` makes it
+/// part of a word that has to match instead.
+pub fn split_terms(query: &str) -> (Vec<String>, Vec<String>) {
+    let mut included = Vec::new();
+    let mut excluded = Vec::new();
+    for word in split_words(query) {
+        if let Some(rest) = word.strip_prefix("\\!") {
+            included.push(format!("!{rest}"));
+        } else if let Some(term) = word.strip_prefix('!') {
+            if !term.is_empty() {
+                excluded.push(term.to_string());
+            }
+        } else {
+            included.push(word);
+        }
+    }
+    (included, excluded)
+}
+
+/// Returns the byte ranges of all matches of `regex` in `text`. If the regex has groups, the
+/// ranges of the groups are returned instead of the whole match.
+pub fn match_ranges(text: &str, regex: &Regex) -> Vec<Range<usize>> {
+    if regex.captures_len() == 1 {
+        return regex.find_iter(text).map(|m| m.range()).collect();
+    }
+    regex
+        .captures_iter(text)
+        .flat_map(|captures| {
+            (1..captures.len())
+                .filter_map(|i| captures.get(i))
+                .map(|m| m.range())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{collapse_whitespace, search, split_terms, split_words, MatchOptions, Matcher};
+    use crate::{config::MatchMode, entry::Entry};
+
+    #[test]
+    fn test_split_words() {
+        assert_eq!(split_words("docker  prune "), vec!["docker", "prune"]);
+        assert_eq!(split_words(r"echo\ hi \d"), vec!["echo hi", r"\d"]);
+        assert!(split_words(" ").is_empty());
+    }
+
+    #[test]
+    fn test_split_terms() {
+        let terms = |query| {
+            let (included, excluded) = split_terms(query);
+            (included.join(","), excluded.join(","))
+        };
+        assert_eq!(terms("git !push"), ("git".into(), "push".into()));
+        assert_eq!(terms("!a !b"), ("".into(), "a,b".into()));
+        assert_eq!(terms(r"echo \!! !"), ("echo,!!".into(), "".into()));
+        assert_eq!(terms("a!b"), ("a!b".into(), "".into()));
+    }
+
+    fn commands(history: &[&str]) -> Vec<Entry> {
+        history.iter().map(|s| Entry::from(s.to_string())).collect()
+    }
+
+    #[test]
+    fn test_search() {
+        let history = commands(&["git push", "cargo build", "git pull origin", "git log"]);
+        let matches: Vec<_> = search(history, "pu git", MatchOptions::default())
+            .map(|m| (m.entry.command, m.spans))
+            .collect();
+        assert_eq!(
+            matches,
+            vec![
+                ("git push".to_string(), vec![0..3, 4..6]),
+                ("git pull origin".to_string(), vec![0..3, 4..6]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_search_fuzzy() {
+        let options = MatchOptions {
+            mode: MatchMode::Fuzzy,
+            ..MatchOptions::default()
+        };
+        let matches: Vec<_> =
+            search(commands(&["cargo build", "cd /tmp"]), "cb", options).collect();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].entry.command, "cargo build");
+        assert_eq!(matches[0].spans, vec![0..1, 6..7]);
+    }
+
+    #[test]
+    fn test_matcher() {
+        let matcher = Matcher::new("Git !push", MatchOptions::default());
+        assert_eq!(matcher.terms(), ["Git"]);
+        assert!(matcher.score("Git pull").is_some());
+        assert!(matcher.score("git pull").is_none());
+        assert!(matcher.score("Git push").is_none());
+        // Excluded terms aren't highlighted
+        assert_eq!(matcher.spans("Git push"), vec![0..3]);
+        let anchored = MatchOptions {
+            prefix: true,
+            ..MatchOptions::default()
+        };
+        let matcher = Matcher::new("git !push", anchored);
+        assert_eq!(matcher.terms(), ["git !push"]);
+        assert!(matcher.score("git !push origin").is_some());
+    }
+
+    #[test]
+    fn test_collapse_whitespace() {
+        assert_eq!(collapse_whitespace("git\n  commit\t -m"), "git commit -m");
+        assert_eq!(collapse_whitespace(" a "), " a ");
+    }
+
+    #[test]
+    fn test_search_loose_whitespace() {
+        let history = commands(&["git\n  commit -m x", "git  commit", "git add\ncommit"]);
+        let loose = MatchOptions {
+            loose_whitespace: true,
+            ..MatchOptions::default()
+        };
+        let matches: Vec<_> = search(history.clone(), r"git\ commit", loose)
+            .map(|m| (m.entry.command, m.spans))
+            .collect();
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0].0, "git\n  commit -m x");
+        assert_eq!(matches[0].1, vec![0..12]);
+        assert_eq!(matches[1].1, vec![0..11]);
+        assert_eq!(
+            search(history.clone(), r"git\ commit", MatchOptions::default()).count(),
+            0
+        );
+        for mode in [MatchMode::Fuzzy, MatchMode::Glob] {
+            let options = MatchOptions { mode, ..loose };
+            let first = search(history.clone(), r"git\ c", options).next().unwrap();
+            // The line break and indentation are highlighted as well
+            assert!(first
+                .spans
+                .iter()
+                .any(|span| span.start <= 3 && span.end >= 6));
+        }
+        let prefix = MatchOptions {
+            prefix: true,
+            ..loose
+        };
+        assert_eq!(search(history, "git commit", prefix).count(), 2);
+    }
+
+    #[test]
+    fn test_matcher_with_huge_query() {
+        let fuzzy = MatchOptions {
+            mode: MatchMode::Fuzzy,
+            ..MatchOptions::default()
+        };
+        let query = "ab".repeat(10000);
+        let matcher = Matcher::new(&query, fuzzy);
+        assert!(matcher.highlight().is_none());
+        // Still matches, just without highlights
+        let text = format!("echo {query}");
+        assert!(matcher.score(&text).is_some());
+        assert_eq!(matcher.spans(&text), vec![]);
+        assert!(Matcher::new("ab", fuzzy).highlight().is_some());
+    }
+}
@@ -0,0 +1,53 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Where the last query is kept for `--resume`.
+pub fn last_query_path() -> Option<PathBuf> {
+    let state_home = std::env::var_os("XDG_STATE_HOME")
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("state"))
+        })?;
+    Some(state_home.join("kontrolleurs").join("last_query"))
+}
+
+/// Reads the query saved at `path`. A missing or corrupt file, e.g. one that isn't UTF-8 or
+/// spans several lines, yields no query.
+pub fn load_query(path: &Path) -> Option<String> {
+    let text = String::from_utf8(fs::read(path).ok()?).ok()?;
+    let query = text.strip_suffix('\n').unwrap_or(&text);
+    (!query.chars().any(char::is_control)).then(|| query.to_string())
+}
+
+/// Saves `query` at `path`, creating its directory if needed.
+pub fn save_query(path: &Path, query: &str) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, format!("{query}\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_query, save_query};
+
+    #[test]
+    fn test_save_and_load_query() {
+        let dir = std::env::temp_dir().join(format!("kontrolleurs-state-{}", std::process::id()));
+        let path = dir.join("kontrolleurs").join("last_query");
+        assert_eq!(load_query(&path), None);
+        save_query(&path, "git !push").unwrap();
+        assert_eq!(load_query(&path), Some("git !push".to_string()));
+        save_query(&path, "").unwrap();
+        assert_eq!(load_query(&path), Some(String::new()));
+        // Corrupt files are ignored
+        std::fs::write(&path, b"git\xff").unwrap();
+        assert_eq!(load_query(&path), None);
+        std::fs::write(&path, "git\npush\n").unwrap();
+        assert_eq!(load_query(&path), None);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,9 +1,13 @@
 // This file is c/p from termion and then modified to take an arbitrary FD
 // https://docs.rs/termion/3.0.0/src/termion/sys/unix/size.rs.html
 
-use std::os::fd::AsRawFd;
+use std::{
+    io::Write,
+    os::fd::{AsFd, AsRawFd},
+};
 
 use libc::{ioctl, winsize, TIOCGWINSZ};
+use termion::raw::RawTerminal;
 
 /// Get the size (columns, rows) of the terminal.
 pub fn terminal_size(fd: &impl AsRawFd) -> std::io::Result<(u16, u16)> {
@@ -17,3 +21,56 @@ pub fn terminal_size(fd: &impl AsRawFd) -> std::io::Result<(u16, u16)> {
         }
     }
 }
+
+/// Something that knows the size of the terminal it writes to.
+pub trait TerminalSize {
+    /// Get the size (columns, rows) of the terminal.
+    fn terminal_size(&self) -> std::io::Result<(u16, u16)>;
+}
+
+impl<W: Write + AsFd> TerminalSize for RawTerminal<W> {
+    fn terminal_size(&self) -> std::io::Result<(u16, u16)> {
+        terminal_size(&self.as_fd())
+    }
+}
+
+/// An in-memory terminal with a fixed size, for tests.
+#[cfg(test)]
+pub struct TestTerminal {
+    pub output: Vec<u8>,
+    /// (columns, rows)
+    pub size: (u16, u16),
+}
+
+#[cfg(test)]
+impl TestTerminal {
+    pub fn new(columns: u16, rows: u16) -> Self {
+        Self {
+            output: Vec::new(),
+            size: (columns, rows),
+        }
+    }
+
+    /// Returns everything written so far as string and clears the output.
+    pub fn take_output(&mut self) -> String {
+        String::from_utf8(std::mem::take(&mut self.output)).expect("Output should be UTF-8")
+    }
+}
+
+#[cfg(test)]
+impl Write for TestTerminal {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl TerminalSize for TestTerminal {
+    fn terminal_size(&self) -> std::io::Result<(u16, u16)> {
+        Ok(self.size)
+    }
+}
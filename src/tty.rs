@@ -0,0 +1,459 @@
+use std::{
+    fs::File,
+    io::{ErrorKind, Read, Write},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    time::{Duration, Instant},
+};
+
+use termion::{event::Event, input::TermRead};
+
+/// What terminals send in front of pasted text with bracketed paste enabled.
+pub const PASTE_START: &[u8] = b"\x1b[200~";
+/// What terminals send after pasted text with bracketed paste enabled.
+pub const PASTE_END: &[u8] = b"\x1b[201~";
+
+const ENABLE_BRACKETED_PASTE: &str = "\x1b[?2004h";
+const DISABLE_BRACKETED_PASTE: &str = "\x1b[?2004l";
+/// Reports presses and releases of mouse buttons, with coordinates in the SGR format
+const ENABLE_MOUSE: &str = "\x1b[?1000h\x1b[?1006h";
+const DISABLE_MOUSE: &str = "\x1b[?1006l\x1b[?1000l";
+
+/// Bracketed paste, enabled on a terminal until dropped.
+pub struct BracketedPaste(File);
+
+impl BracketedPaste {
+    pub fn enable(mut tty: File) -> std::io::Result<Self> {
+        write!(tty, "{ENABLE_BRACKETED_PASTE}")?;
+        tty.flush()?;
+        Ok(Self(tty))
+    }
+}
+
+impl Drop for BracketedPaste {
+    fn drop(&mut self) {
+        let _ = write!(self.0, "{DISABLE_BRACKETED_PASTE}");
+        let _ = self.0.flush();
+    }
+}
+
+/// Mouse reporting, enabled on a terminal until dropped.
+pub struct MouseReporting(File);
+
+impl MouseReporting {
+    pub fn enable(mut tty: File) -> std::io::Result<Self> {
+        write!(tty, "{ENABLE_MOUSE}")?;
+        tty.flush()?;
+        Ok(Self(tty))
+    }
+}
+
+impl Drop for MouseReporting {
+    fn drop(&mut self) {
+        let _ = write!(self.0, "{DISABLE_MOUSE}");
+        let _ = self.0.flush();
+    }
+}
+
+/// What an `EventReader` waits for.
+#[derive(Debug)]
+pub enum Input {
+    Event(std::io::Result<Event>),
+    /// The terminal was resized, see `EventReader::watch_resize`
+    Resize,
+}
+
+/// Reads events from a TTY in a thread of its own, so waiting for them can be combined with
+/// other work. Events are only read once asked for, so input after the last one is left for
+/// whoever reads the TTY next, e.g. the shell.
+pub struct EventReader {
+    requests: Sender<()>,
+    /// `None` once the TTY is closed
+    sender: Sender<Option<Input>>,
+    inputs: Receiver<Option<Input>>,
+    /// Whether an event was asked for that wasn't returned yet
+    requested: bool,
+}
+
+impl EventReader {
+    pub fn new(tty: File) -> Self {
+        let (requests, requested) = mpsc::channel();
+        let (sender, inputs) = mpsc::channel();
+        let events = sender.clone();
+        std::thread::spawn(move || {
+            let mut tty_events = tty.events();
+            for () in requested {
+                let event = tty_events.next();
+                let closed = event.is_none();
+                if events.send(event.map(Input::Event)).is_err() || closed {
+                    break;
+                }
+            }
+        });
+        Self {
+            requests,
+            sender,
+            inputs,
+            requested: false,
+        }
+    }
+
+    /// Also wakes up waiting for events when the terminal is resized (on SIGWINCH), which is
+    /// returned as `Input::Resize` in between events.
+    pub fn watch_resize(&self) -> std::io::Result<()> {
+        let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGWINCH])?;
+        let sender = self.sender.clone();
+        std::thread::spawn(move || {
+            for _ in signals.forever() {
+                if sender.send(Some(Input::Resize)).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Waits for the next input. `None` once the TTY is closed.
+    pub fn next(&mut self) -> Option<Input> {
+        self.request();
+        let input = self.inputs.recv().ok().flatten();
+        self.received(input.as_ref());
+        input
+    }
+
+    /// Waits up to `timeout` for the next input. An event that doesn't arrive in time is
+    /// returned by a later call.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Result<Input, RecvTimeoutError> {
+        self.request();
+        let input = self
+            .inputs
+            .recv_timeout(timeout)?
+            .ok_or(RecvTimeoutError::Disconnected)?;
+        self.received(Some(&input));
+        Ok(input)
+    }
+
+    fn received(&mut self, input: Option<&Input>) {
+        // A resize doesn't answer the request, the event is still to come
+        if !matches!(input, Some(Input::Resize)) {
+            self.requested = false;
+        }
+    }
+
+    fn request(&mut self) {
+        if !self.requested {
+            // Only fails if the thread stopped, which waiting for the event notices
+            let _ = self.requests.send(());
+            self.requested = true;
+        }
+    }
+}
+
+/// Whether input from `tty` can be read right away.
+pub fn input_pending(tty: &impl AsRawFd) -> bool {
+    wait_for_input(tty, Duration::ZERO)
+}
+
+/// Waits up to `timeout` for input from `tty`, returns whether there is some.
+fn wait_for_input(tty: &impl AsRawFd, timeout: Duration) -> bool {
+    let mut fds = [libc::pollfd {
+        fd: tty.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    }];
+    let timeout = timeout.as_millis().try_into().unwrap_or(libc::c_int::MAX);
+    let ready = unsafe { libc::poll(fds.as_mut_ptr(), 1, timeout) };
+    ready > 0 && fds[0].revents & libc::POLLIN != 0
+}
+
+/// Asks the terminal for the cursor position and returns its row, counting from 1. The terminal
+/// has to be in raw mode, so the answer can be read from `input` right away. Whatever was typed
+/// before the answer is lost.
+pub fn cursor_row(
+    input: &File,
+    output: &mut impl Write,
+    timeout: Duration,
+) -> std::io::Result<u16> {
+    write!(output, "\x1b[6n")?;
+    output.flush()?;
+    let deadline = Instant::now() + timeout;
+    let mut answer = Vec::new();
+    while !answer.ends_with(b"R") {
+        if !wait_for_input(input, deadline.saturating_duration_since(Instant::now())) {
+            return Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                "No answer from terminal",
+            ));
+        }
+        let mut byte = [0];
+        if (&*input).read(&mut byte)? == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        answer.push(byte[0]);
+    }
+    // The answer looks like ESC [ row ; column R
+    String::from_utf8_lossy(&answer)
+        .rsplit_once("\x1b[")
+        .and_then(|(_, position)| position.split_once(';')?.0.parse().ok())
+        .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidData, "Unexpected cursor position"))
+}
+
+/// Installs a panic hook that restores the current mode of the terminal at `path` and clears
+/// whatever was drawn below the cursor, before the panic message is printed. Unwinding would
+/// restore the mode too, but only after the message got garbled by raw mode, and not at all if
+/// the terminal isn't dropped (e.g. with `panic = "abort"`).
+pub fn restore_on_panic(path: &Path) -> std::io::Result<()> {
+    let original = terminal_mode(path)?;
+    let path = PathBuf::from(path);
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        restore(&path, &original);
+        default_hook(info);
+    }));
+    Ok(())
+}
+
+/// Restores the current mode of the terminal at `path` and clears whatever was drawn below the
+/// cursor once one of `signals` arrives, then exits with the status shells report for processes
+/// killed by the signal. Being killed would leave the terminal in raw mode otherwise.
+pub fn restore_on_signals(path: &Path, signals: &[libc::c_int]) -> std::io::Result<()> {
+    let original = terminal_mode(path)?;
+    let path = PathBuf::from(path);
+    // The signal handler only queues the signal, the terminal is restored in a regular thread
+    let mut signals = signal_hook::iterator::Signals::new(signals)?;
+    std::thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            restore(&path, &original);
+            std::process::exit(128 + signal);
+        }
+    });
+    Ok(())
+}
+
+fn terminal_mode(path: &Path) -> std::io::Result<libc::termios> {
+    let tty = File::open(path)?;
+    let mut mode = unsafe { std::mem::zeroed::<libc::termios>() };
+    if unsafe { libc::tcgetattr(tty.as_raw_fd(), &mut mode) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(mode)
+}
+
+fn restore(path: &Path, mode: &libc::termios) {
+    // The terminal the prompt writes to might be gone already, so open it again
+    if let Ok(mut tty) = File::options().write(true).open(path) {
+        unsafe { libc::tcsetattr(tty.as_raw_fd(), libc::TCSANOW, mode) };
+        let _ = write!(
+            tty,
+            "\r{}{DISABLE_BRACKETED_PASTE}{DISABLE_MOUSE}",
+            termion::clear::AfterCursor
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::CStr,
+        fs::File,
+        io::ErrorKind,
+        os::fd::{AsRawFd, FromRawFd},
+        path::Path,
+        process::{Command, Stdio},
+        sync::mpsc::RecvTimeoutError,
+        time::{Duration, Instant},
+    };
+
+    use termion::event::{Event, Key};
+
+    use super::{
+        cursor_row, input_pending, restore_on_panic, restore_on_signals, EventReader, Input,
+    };
+
+    const CHILD_VAR: &str = "KONTROLLEURS_TEST_PANIC_TTY";
+    const SIGNAL_CHILD_VAR: &str = "KONTROLLEURS_TEST_SIGNAL_TTY";
+
+    fn local_flags(fd: i32) -> libc::tcflag_t {
+        let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+        assert_eq!(unsafe { libc::tcgetattr(fd, &mut termios) }, 0);
+        termios.c_lflag
+    }
+
+    fn enter_raw_mode(path: &str) {
+        let tty = File::options().write(true).open(path).unwrap();
+        let mut raw = unsafe { std::mem::zeroed::<libc::termios>() };
+        unsafe {
+            libc::tcgetattr(tty.as_raw_fd(), &mut raw);
+            libc::cfmakeraw(&mut raw);
+            libc::tcsetattr(tty.as_raw_fd(), libc::TCSANOW, &raw);
+        }
+        std::mem::forget(tty);
+    }
+
+    fn open_pty() -> (i32, i32) {
+        let (mut master, mut slave) = (0, 0);
+        let result = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                std::ptr::null(),
+            )
+        };
+        assert_eq!(result, 0);
+        (master, slave)
+    }
+
+    #[test]
+    fn test_cursor_row() {
+        let (master, slave) = open_pty();
+        enter_raw_mode(
+            unsafe { CStr::from_ptr(libc::ttyname(slave)) }
+                .to_str()
+                .unwrap(),
+        );
+        let tty = unsafe { File::from_raw_fd(slave) };
+        let mut output = Vec::new();
+        let timeout = Duration::from_millis(10);
+        let err = cursor_row(&tty, &mut output, timeout).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert_eq!(output, b"\x1b[6n");
+        // Typed before the answer
+        let answer = b"ls\x1b[12;5R";
+        let written = unsafe { libc::write(master, answer.as_ptr().cast(), answer.len()) };
+        assert_eq!(written, 9);
+        let timeout = Duration::from_secs(10);
+        assert_eq!(cursor_row(&tty, &mut output, timeout).unwrap(), 12);
+        unsafe { libc::close(master) };
+    }
+
+    #[test]
+    fn test_input_pending() {
+        let (master, slave) = open_pty();
+        let tty = unsafe { File::from_raw_fd(slave) };
+        assert!(!input_pending(&tty));
+        // The terminal is still in canonical mode, where input is only available by line
+        assert_eq!(
+            unsafe { libc::write(master, b"ls\n".as_ptr().cast(), 3) },
+            3
+        );
+        assert!(input_pending(&tty));
+        unsafe { libc::close(master) };
+    }
+
+    #[test]
+    fn test_event_reader() {
+        let (master, slave) = open_pty();
+        let mut events = EventReader::new(unsafe { File::from_raw_fd(slave) });
+        assert!(matches!(
+            events.next_timeout(Duration::from_millis(10)),
+            Err(RecvTimeoutError::Timeout)
+        ));
+        assert_eq!(unsafe { libc::write(master, b"a\n".as_ptr().cast(), 2) }, 2);
+        // The event asked for before arrives now
+        assert!(matches!(
+            events.next_timeout(Duration::from_secs(10)),
+            Ok(Input::Event(Ok(Event::Key(Key::Char('a')))))
+        ));
+        assert!(matches!(
+            events.next(),
+            Some(Input::Event(Ok(Event::Key(Key::Char('\n')))))
+        ));
+        unsafe { libc::close(master) };
+    }
+
+    #[test]
+    fn test_event_reader_resize() {
+        let (master, slave) = open_pty();
+        let mut events = EventReader::new(unsafe { File::from_raw_fd(slave) });
+        events.watch_resize().unwrap();
+        assert!(matches!(
+            events.next_timeout(Duration::from_millis(10)),
+            Err(RecvTimeoutError::Timeout)
+        ));
+        // Without any input
+        unsafe { libc::kill(libc::getpid(), libc::SIGWINCH) };
+        assert!(matches!(events.next(), Some(Input::Resize)));
+        assert_eq!(unsafe { libc::write(master, b"a\n".as_ptr().cast(), 2) }, 2);
+        assert!(matches!(
+            events.next(),
+            Some(Input::Event(Ok(Event::Key(Key::Char('a')))))
+        ));
+        unsafe { libc::close(master) };
+    }
+
+    #[test]
+    fn test_restore_on_signals() {
+        if let Ok(path) = std::env::var(SIGNAL_CHILD_VAR) {
+            // In the child: switch to raw mode and wait to be terminated
+            restore_on_signals(Path::new(&path), &[libc::SIGTERM, libc::SIGHUP]).unwrap();
+            enter_raw_mode(&path);
+            loop {
+                std::thread::park();
+            }
+        }
+
+        let (master, slave) = open_pty();
+        let path = unsafe { CStr::from_ptr(libc::ttyname(slave)) }
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let before = local_flags(slave);
+        for signal in [libc::SIGTERM, libc::SIGHUP] {
+            let mut child = Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "tty::tests::test_restore_on_signals"])
+                .env(SIGNAL_CHILD_VAR, &path)
+                .stdout(Stdio::null())
+                .spawn()
+                .unwrap();
+            // The handler is registered by the time the child is in raw mode
+            let start = Instant::now();
+            while local_flags(slave) & libc::ICANON != 0 {
+                assert!(start.elapsed() < Duration::from_secs(10));
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            assert_eq!(unsafe { libc::kill(child.id() as i32, signal) }, 0);
+            assert_eq!(child.wait().unwrap().code(), Some(128 + signal));
+            assert_eq!(local_flags(slave), before);
+        }
+
+        unsafe {
+            libc::close(slave);
+            libc::close(master);
+        }
+    }
+
+    #[test]
+    fn test_restore_on_panic() {
+        if let Ok(path) = std::env::var(CHILD_VAR) {
+            // In the child: switch to raw mode and panic without ever leaving it
+            restore_on_panic(Path::new(&path)).unwrap();
+            enter_raw_mode(&path);
+            panic!("Panicking in raw mode");
+        }
+
+        let (master, slave) = open_pty();
+        let path = unsafe { CStr::from_ptr(libc::ttyname(slave)) }
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let before = local_flags(slave);
+        assert_ne!(before & libc::ICANON, 0);
+
+        let output = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "tty::tests::test_restore_on_panic"])
+            .env(CHILD_VAR, &path)
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stdout).contains("Panicking in raw mode"));
+        assert_eq!(local_flags(slave), before);
+
+        unsafe {
+            libc::close(slave);
+            libc::close(master);
+        }
+    }
+}